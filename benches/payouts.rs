@@ -0,0 +1,114 @@
+//! Benchmarks of the payout pipeline on a leaderboard far bigger than any real
+//! one, with 10,000 helpers who closed a million tickets between them. Run them
+//! with `cargo bench`.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use crimson::db::Leaderboard;
+use crimson::flavortown::FlavortownUser;
+use crimson::payout::{do_pool_payouts, do_static_rate_payouts};
+use crimson::report::{PayoutListFormat, format_helper_cookies, sort_by_cookies};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use rust_decimal::Decimal;
+
+const HELPERS: usize = 10_000;
+const TICKETS: i64 = 1_000_000;
+
+/// Leaderboard rows as the databases return them, spread unevenly (like real
+/// helpers) over `HELPERS` helpers, and with a few tickets nobody closed
+fn leaderboard_rows() -> Vec<(Option<String>, i64)> {
+    // Weights that fall away like 1/n, as a few helpers close most tickets
+    let weights: Vec<f64> = (1..=HELPERS).map(|rank| 1.0 / rank as f64).collect();
+    let total_weight: f64 = weights.iter().sum();
+    let mut rows: Vec<(Option<String>, i64)> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, weight)| {
+            let tickets = (weight / total_weight * TICKETS as f64).max(1.0) as i64;
+            (Some(format!("U{:08}", i)), tickets)
+        })
+        .collect();
+    rows.push((None, 123));
+    rows
+}
+
+fn helper_tickets() -> HashMap<String, i64> {
+    let mut leaderboard = Leaderboard::default();
+    leaderboard.extend(leaderboard_rows());
+    leaderboard.helper_tickets
+}
+
+fn helper_users(helper_tickets: &HashMap<String, i64>) -> HashMap<String, FlavortownUser> {
+    helper_tickets
+        .keys()
+        .enumerate()
+        .map(|(i, slack_id)| {
+            let user = FlavortownUser {
+                id: i as i64,
+                slack_id: slack_id.clone(),
+                display_name: format!("Helper {}", i),
+                avatar: String::new(),
+                cookies: Some(0),
+            };
+            (slack_id.clone(), user)
+        })
+        .collect()
+}
+
+fn bench_payouts(c: &mut Criterion) {
+    let rows = leaderboard_rows();
+    let helper_tickets = helper_tickets();
+    let pool_cookies = do_pool_payouts(&helper_tickets, &1_000_000).unwrap();
+    let helper_users = helper_users(&helper_tickets);
+
+    c.bench_function("merge leaderboard rows", |b| {
+        b.iter_batched(
+            || rows.clone(),
+            |rows| {
+                let mut leaderboard = Leaderboard::default();
+                leaderboard.extend(rows);
+                leaderboard
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("rank helpers", |b| {
+        let mut leaderboard = Leaderboard::default();
+        leaderboard.extend(rows.clone());
+        b.iter(|| black_box(&leaderboard).rankings().len())
+    });
+    c.bench_function("pool payouts", |b| {
+        b.iter(|| do_pool_payouts(black_box(&helper_tickets), &1_000_000).unwrap())
+    });
+    c.bench_function("rate payouts", |b| {
+        let rate = Decimal::new(25, 1);
+        b.iter(|| do_static_rate_payouts(black_box(&helper_tickets), &rate).unwrap())
+    });
+    c.bench_function("sort payouts", |b| {
+        b.iter(|| sort_by_cookies(black_box(&pool_cookies), &helper_tickets).len())
+    });
+    for (name, format) in [
+        ("payout list", PayoutListFormat::ManualPayouts),
+        ("table", PayoutListFormat::Table),
+        ("json", PayoutListFormat::Json),
+        ("csv", PayoutListFormat::Csv),
+    ] {
+        c.bench_function(&format!("format {}", name), |b| {
+            b.iter(|| {
+                format_helper_cookies(
+                    black_box(&pool_cookies),
+                    &helper_tickets,
+                    &helper_users,
+                    &[],
+                    &format,
+                    false,
+                )
+                .unwrap()
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_payouts);
+criterion_main!(benches);
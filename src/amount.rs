@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use rust_decimal::Decimal;
+
+/// Parses a cookie pool like `1000`, `1.5k` or `2m`. It has to be a whole
+/// number of cookies, and rates like `2.5/ticket` are refused, as they belong
+/// in --cookie-rate.
+pub fn parse_pool(text: &str) -> Result<i64, anyhow::Error> {
+    let text = text.trim().to_ascii_lowercase();
+    if text.contains('/') {
+        return Err(anyhow::anyhow!(
+            "{:?} is a rate, so it goes in --cookie-rate (a pool is a total number of cookies, like 1000 or 1.5k)",
+            text
+        ));
+    }
+    let (number, multiplier) = if let Some(number) = text.strip_suffix('k') {
+        (number, 1_000)
+    } else if let Some(number) = text.strip_suffix('m') {
+        (number, 1_000_000)
+    } else {
+        (text.as_str(), 1)
+    };
+    let invalid = || {
+        format!(
+            "Invalid cookie pool {:?} (expected a number of cookies, like 1000 or 1.5k)",
+            text
+        )
+    };
+    let (whole, fraction) = split_decimal(number).with_context(invalid)?;
+
+    // Worked out without floats, so that e.g. 1.001k is exactly 1001
+    let scale = 10_i64
+        .checked_pow(fraction.len() as u32)
+        .with_context(invalid)?;
+    let fraction_cookies = if fraction.is_empty() {
+        0
+    } else {
+        fraction
+            .parse::<i64>()
+            .ok()
+            .and_then(|fraction| fraction.checked_mul(multiplier))
+            .with_context(invalid)?
+    };
+    if fraction_cookies % scale != 0 {
+        return Err(anyhow::anyhow!(
+            "A cookie pool has to be a whole number of cookies, not {}{}",
+            number,
+            match multiplier {
+                1 => "",
+                1_000 => " thousand",
+                _ => " million",
+            }
+        ));
+    }
+    whole
+        .parse::<i64>()
+        .ok()
+        .and_then(|whole| whole.checked_mul(multiplier))
+        .and_then(|cookies| cookies.checked_add(fraction_cookies / scale))
+        .with_context(|| format!("{:?} is too many cookies", text))
+}
+
+/// Parses a cookie rate like `2.5/ticket`. The unit is needed, so that a pool
+/// can't be given as a rate by mistake.
+pub fn parse_rate(text: &str) -> Result<Decimal, anyhow::Error> {
+    let text = text.trim().to_ascii_lowercase();
+    let Some((number, unit)) = text.split_once('/') else {
+        return Err(anyhow::anyhow!(
+            "{:?} needs a unit, like \"{}/ticket\" (or, for a pool of cookies shared between helpers, use --cookie-pool)",
+            text,
+            text
+        ));
+    };
+    let number = number.trim();
+    if unit.trim() != "ticket" {
+        return Err(anyhow::anyhow!(
+            "Cookie rates are per ticket, like \"{}/ticket\", not per {:?}",
+            number,
+            unit.trim()
+        ));
+    }
+    split_decimal(number)
+        .and_then(|_| number.parse::<Decimal>().ok())
+        .with_context(|| {
+            format!(
+                "Invalid cookie rate {:?} (expected a number of cookies per ticket, like 2.5/ticket)",
+                text
+            )
+        })
+}
+
+/// Splits a plain decimal number like `12.5` into its whole and fractional
+/// digits, or returns `None` if there's anything else in it (signs,
+/// exponents, `inf` and so on)
+fn split_decimal(number: &str) -> Option<(&str, &str)> {
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    (!whole.is_empty() && digits(whole) && digits(fraction) && !number.ends_with('.'))
+        .then_some((whole, fraction))
+}
@@ -0,0 +1,700 @@
+use std::io::{BufRead, IsTerminal, Write};
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Ok, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::Url;
+use rust_decimal::Decimal;
+use time_tz::Tz;
+
+use crate::amount;
+use crate::config::Config;
+use crate::db::Role;
+use crate::flavortown::{ApiVersion, DEFAULT_MAX_CONCURRENT_LOOKUPS};
+use crate::natural_date::{CalendarDuration, DateRange, RelativePeriod, parse_timezone};
+use crate::report::PayoutListFormat;
+use crate::secrets::{self, Secret};
+use crate::setup::SetupWizard;
+
+#[derive(Parser)]
+pub struct CrimsonArgs {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Print every command and flag (with its type, default and help) as JSON,
+    /// for building other interfaces on top of crimson
+    #[clap(long, exclusive = true)]
+    pub dump_cli_json: bool,
+
+    /// Log extra diagnostics, like how long database queries and API calls took
+    #[clap(short, long, env = "CRIMSON_VERBOSE", global = true)]
+    pub verbose: bool,
+
+    /// Only log warnings and errors
+    #[clap(
+        short,
+        long,
+        env = "CRIMSON_QUIET",
+        global = true,
+        conflicts_with = "verbose"
+    )]
+    pub quiet: bool,
+
+    /// How to format the diagnostics logged to stderr
+    #[clap(long, env = "CRIMSON_LOG_FORMAT", global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// TOML config file (by default, crimson.toml in the current directory,
+    /// ~/.config/crimson/ or the platform's config directory, if there is one)
+    #[clap(long, env = "CRIMSON_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Use the settings from this `[profile.<name>]` in the config file (e.g.
+    /// staging or prod)
+    #[clap(long, env = "CRIMSON_PROFILE", global = true)]
+    pub profile: Option<String>,
+
+    /// Post a report to this webhook if crimson fails, with the command, its
+    /// period and the config file (without secrets)
+    #[clap(long, env = "CRIMSON_ERROR_WEBHOOK_URL", global = true)]
+    pub error_webhook_url: Option<Url>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Work out how many cookies each helper has earned over a period
+    #[clap(
+        visible_aliases = ["pay", "lb"],
+        args_conflicts_with_subcommands = true,
+        subcommand_negates_reqs = true
+    )]
+    Payout(Box<PayoutArgs>),
+    /// Go through the payout declared in the config file's `[run]` table,
+    /// stage by stage: compute, archive, notify and execute
+    Run,
+    /// Check on the Nephthys database
+    #[clap(subcommand)]
+    Db(DbCommand),
+    /// Run a web server that answers Slack slash commands (like
+    /// `/leaderboard last week`) with the current rankings
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+    /// Stay running and post leaderboard digests on the schedules in the
+    /// config file
+    Daemon(DaemonArgs),
+    /// Manage Slack user groups
+    #[clap(subcommand)]
+    Usergroup(UsergroupCommand),
+    /// Manage Slack canvases
+    #[clap(subcommand)]
+    Canvas(CanvasCommand),
+    /// Keep secrets in the operating system's keyring instead of `.env` (per
+    /// --profile)
+    #[clap(subcommand)]
+    Secrets(SecretsCommand),
+    /// Update crimson to the latest release from GitHub
+    #[cfg(feature = "self-update")]
+    SelfUpdate(SelfUpdateArgs),
+}
+
+#[cfg(feature = "self-update")]
+#[derive(Args)]
+pub struct SelfUpdateArgs {
+    /// Only say whether there's a newer version
+    #[clap(long)]
+    pub check: bool,
+
+    /// Install this release (e.g. v0.3.0) instead of the latest one, even if
+    /// it's older
+    #[clap(long)]
+    pub version: Option<String>,
+
+    /// Update without asking first
+    #[clap(long, short)]
+    pub yes: bool,
+
+    /// GitHub repository to get releases from
+    #[clap(long, env = "CRIMSON_UPDATE_REPO", default_value = "MMK21Hub/crimson")]
+    pub repo: String,
+}
+
+#[derive(Subcommand)]
+pub enum SecretsCommand {
+    /// Save a secret to the keyring, reading it from the terminal (or stdin)
+    Set { secret: Secret },
+    /// Remove a secret from the keyring
+    Delete { secret: Secret },
+}
+
+#[derive(Subcommand)]
+pub enum CanvasCommand {
+    /// Rewrite a canvas with the latest leaderboard, so that it's always up to
+    /// date. Anything else in the canvas is replaced.
+    Update(CanvasUpdateArgs),
+}
+
+#[derive(Args)]
+pub struct CanvasUpdateArgs {
+    /// ID of the canvas to update (e.g. F0123ABCD)
+    #[clap(long, env = "CRIMSON_CANVAS_ID")]
+    pub canvas_id: String,
+
+    /// How many helpers to list
+    #[clap(long, env = "CRIMSON_TOP", default_value_t = 10)]
+    pub top: usize,
+
+    /// Which period's leaderboard to show
+    #[clap(long, env = "CRIMSON_PERIOD", value_enum, default_value_t = RelativePeriod::ThisWeek)]
+    pub period: RelativePeriod,
+
+    /// Timezone (e.g. Europe/London) that days, weeks and months start in
+    /// (UTC by default)
+    #[arg(long, env = "CRIMSON_TIMEZONE", value_parser = parse_timezone)]
+    pub timezone: Option<&'static Tz>,
+
+    #[clap(flatten)]
+    pub database: DbArgs,
+}
+
+#[derive(Args)]
+pub struct DaemonArgs {
+    /// Address to serve Prometheus metrics on, at `/metrics` (not served by
+    /// default)
+    #[cfg(feature = "server")]
+    #[clap(long, env = "CRIMSON_METRICS_LISTEN")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    #[clap(flatten)]
+    pub database: DbArgs,
+}
+
+#[derive(Subcommand)]
+pub enum UsergroupCommand {
+    /// Set a user group's members to the top helpers of a period, so that
+    /// mentioning it pings whoever's currently doing the most
+    Sync(UsergroupSyncArgs),
+}
+
+#[derive(Args)]
+pub struct UsergroupSyncArgs {
+    /// ID of the user group to update (e.g. S0123ABCD)
+    #[clap(long, env = "CRIMSON_GROUP")]
+    pub group: String,
+
+    /// How many helpers to put in the group
+    #[clap(long, env = "CRIMSON_TOP", default_value_t = 10)]
+    pub top: usize,
+
+    /// Which period's leaderboard to take the top helpers from
+    #[clap(long, env = "CRIMSON_PERIOD", value_enum, default_value_t = RelativePeriod::ThisWeek)]
+    pub period: RelativePeriod,
+
+    /// Timezone (e.g. Europe/London) that days, weeks and months start in
+    /// (UTC by default)
+    #[arg(long, env = "CRIMSON_TIMEZONE", value_parser = parse_timezone)]
+    pub timezone: Option<&'static Tz>,
+
+    /// Print who would be in the group instead of updating it
+    #[clap(long, env = "CRIMSON_DRY_RUN")]
+    pub dry_run: bool,
+
+    #[clap(flatten)]
+    pub database: DbArgs,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Print the schema version, table sizes, and whether the columns that the
+    /// leaderboard query looks tickets up by are indexed
+    Inspect(DbArgs),
+    /// Create or refresh the `crimson_leaderboard` materialized view, which
+    /// keeps hourly ticket counts per helper for `serve`, `daemon` and `canvas
+    /// update` to read (Postgres only)
+    RefreshView(DbArgs),
+}
+
+#[cfg(feature = "server")]
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Address to listen on. Point the slash command's request URL at
+    /// `/slack/commands` on it.
+    #[clap(long, env = "CRIMSON_LISTEN", default_value = "127.0.0.1:3000")]
+    pub listen: SocketAddr,
+
+    /// Signing secret of the Slack app, used to check that requests really
+    /// come from Slack
+    #[clap(long, env = "SLACK_SIGNING_SECRET", hide_env_values = true)]
+    pub slack_signing_secret: String,
+
+    /// Timezone (e.g. Europe/London) that days, weeks and months start in
+    /// (UTC by default)
+    #[arg(long, env = "CRIMSON_TIMEZONE", value_parser = parse_timezone)]
+    pub timezone: Option<&'static Tz>,
+
+    #[clap(flatten)]
+    pub database: DbArgs,
+}
+
+#[derive(Args)]
+pub struct DbArgs {
+    /// Database to read tickets from: a Postgres or MySQL URL, or
+    /// `sqlite://path` for a local copy of the Nephthys schema. Repeat it (or
+    /// separate URLs with spaces) to combine the leaderboards of several
+    /// databases. The config file's `[database]` is used if this isn't given.
+    #[clap(
+        long,
+        env = "DATABASE_URL",
+        hide_env_values = true,
+        value_delimiter = ' '
+    )]
+    pub db: Vec<String>,
+
+    /// Read the database URL(s) from this file instead, or from stdin if it's
+    /// `-`
+    #[clap(long, env = "CRIMSON_DB_URL_FILE", conflicts_with = "db")]
+    pub db_url_file: Option<PathBuf>,
+
+    /// Reach Postgres and MySQL databases through an SSH tunnel to this host
+    /// (e.g. user@bastion), using the system `ssh`
+    #[clap(long, env = "CRIMSON_SSH_TUNNEL")]
+    pub ssh_tunnel: Option<String>,
+
+    /// How many times to retry connecting to a database that can't be reached
+    #[clap(long, env = "CRIMSON_CONNECT_RETRIES", default_value_t = 3)]
+    pub connect_retries: u32,
+}
+
+#[derive(Subcommand)]
+pub enum PayoutCommand {
+    /// Check that the payouts in a plan written by `crimson payout
+    /// --write-plan` are given out: lists them exactly as they were reviewed,
+    /// without counting tickets again, for them to be given out by hand, then
+    /// checks everyone's balance
+    Execute(ExecuteArgs),
+}
+
+#[derive(Args)]
+pub struct ExecuteArgs {
+    /// The plan file to go through
+    #[clap(long, env = "CRIMSON_PLAN")]
+    pub plan: PathBuf,
+
+    /// How to format the payout list [default: payout]
+    #[clap(long, env = "CRIMSON_FORMAT", value_enum)]
+    pub format: Option<PayoutListFormat>,
+
+    #[clap(flatten)]
+    pub flavortown: FlavortownArgs,
+
+    #[clap(flatten)]
+    pub ledger: LedgerArgs,
+}
+
+#[derive(Args)]
+pub struct PayoutArgs {
+    #[clap(subcommand)]
+    pub command: Option<PayoutCommand>,
+
+    /// Start time (ISO 8601, e.g. 2026-02-01T00:00:00Z), or relative to now,
+    /// like "last monday" or "3 weeks ago"
+    #[arg(short, long, env = "CRIMSON_START", required_unless_present_any = ["period", "duration", "week", "month"])]
+    pub start: Option<String>,
+
+    /// End time (ISO 8601, e.g. 2026-03-01T00:00:00Z), or relative to now,
+    /// like "yesterday" [default: now]
+    #[arg(short, long, env = "CRIMSON_END")]
+    pub end: Option<String>,
+
+    /// Pay out for a whole day, week or month instead of giving --start and
+    /// --end. Weeks start on Monday, and days at midnight in --timezone.
+    #[arg(long, env = "CRIMSON_PERIOD", value_enum, conflicts_with_all = ["start", "end"])]
+    pub period: Option<RelativePeriod>,
+
+    /// How long the window is (e.g. 7d, 2w or 36h), to go with either --start
+    /// or --end (which is now by default)
+    #[arg(long, env = "CRIMSON_DURATION", value_parser = CalendarDuration::parse, conflicts_with = "period")]
+    pub duration: Option<CalendarDuration>,
+
+    /// Pay out for an ISO week (e.g. 2026-W05), from midnight on its Monday
+    /// in --timezone
+    #[arg(long, env = "CRIMSON_WEEK", value_parser = DateRange::parse_iso_week, conflicts_with_all = ["start", "end", "period", "duration"])]
+    pub week: Option<DateRange>,
+
+    /// Pay out for a calendar month (e.g. 2026-02), from midnight on the 1st
+    /// in --timezone
+    #[arg(long, env = "CRIMSON_MONTH", value_parser = DateRange::parse_month, conflicts_with_all = ["start", "end", "period", "duration", "week"])]
+    pub month: Option<DateRange>,
+
+    /// Timezone (e.g. Europe/London) for start and end times that don't have a
+    /// UTC offset, and for plain dates like 2026-02-01 (which are otherwise in
+    /// UTC)
+    #[arg(long, env = "CRIMSON_TIMEZONE", value_parser = parse_timezone)]
+    pub timezone: Option<&'static Tz>,
+
+    /// Timezone to show the start and end of the period in [default:
+    /// --timezone, or UTC]
+    #[arg(long, env = "CRIMSON_DISPLAY_TIMEZONE", value_parser = parse_timezone)]
+    pub display_timezone: Option<&'static Tz>,
+
+    /// Pay out for a window longer than 92 days without asking first
+    #[arg(long, env = "CRIMSON_FORCE")]
+    pub force: bool,
+
+    #[clap(flatten)]
+    pub payout_specifier: PayoutSpecifierArgs,
+
+    /// Use the settings from this `[preset.<name>]` in the config file (e.g.
+    /// weekly) instead of the `[payout]` defaults
+    #[clap(long, env = "CRIMSON_PRESET")]
+    pub preset: Option<String>,
+
+    #[clap(flatten)]
+    pub database: DbArgs,
+
+    /// Only count tickets closed by users with one of these roles [default: helper]
+    #[clap(long, env = "CRIMSON_ROLE", value_delimiter = ',')]
+    pub role: Vec<Role>,
+
+    /// Count tickets closed by anyone, regardless of their role
+    #[clap(long, env = "CRIMSON_INCLUDE_NON_HELPERS", conflicts_with = "role")]
+    pub include_non_helpers: bool,
+
+    /// Only count tickets from the Slack channel with this ID (can be repeated
+    /// or comma-separated)
+    #[clap(long, env = "CRIMSON_CHANNEL", value_delimiter = ',')]
+    pub channel: Vec<String>,
+
+    /// Only count tickets in this category (can be repeated or comma-separated)
+    #[clap(long, env = "CRIMSON_CATEGORY", value_delimiter = ',')]
+    pub category: Vec<String>,
+
+    /// Don't count tickets in this category (can be repeated or
+    /// comma-separated)
+    #[clap(long, env = "CRIMSON_EXCLUDE_CATEGORY", value_delimiter = ',')]
+    pub exclude_category: Vec<String>,
+
+    /// Don't count tickets that were reopened after being closed
+    #[clap(long, env = "CRIMSON_EXCLUDE_REOPENED")]
+    pub exclude_reopened: bool,
+
+    /// Count tickets that were reopened after being closed, even if the config
+    /// file says not to
+    #[clap(
+        long,
+        env = "CRIMSON_INCLUDE_REOPENED",
+        conflicts_with = "exclude_reopened"
+    )]
+    pub include_reopened: bool,
+
+    /// Seconds to let a database query run before cancelling it (0 for no limit)
+    #[clap(long, env = "CRIMSON_QUERY_TIMEOUT", default_value_t = 300)]
+    pub query_timeout: u64,
+
+    #[clap(long, env = "CRIMSON_FORMAT", value_enum)]
+    pub format: Option<PayoutListFormat>,
+
+    /// Also write the payout list to this file, in a format that depends on
+    /// its extension: .json, .csv or .txt (can be repeated or comma-separated)
+    #[clap(long, env = "CRIMSON_ALSO_WRITE", value_delimiter = ',')]
+    pub also_write: Vec<PathBuf>,
+
+    /// Write the fully worked-out payouts (who gets what, and the run's ID
+    /// with --record) to this JSON file, for `crimson payout execute` to go
+    /// through once they've been reviewed
+    #[clap(long, env = "CRIMSON_WRITE_PLAN")]
+    pub write_plan: Option<PathBuf>,
+
+    /// Use the leaderboard SQL in this file instead of the built-in query. It
+    /// gets the start and end as $1 and $2, and must return `slack_id` and
+    /// `tickets_closed` columns.
+    #[clap(long, env = "CRIMSON_QUERY_FILE")]
+    pub query_file: Option<PathBuf>,
+
+    /// Prompt to pick the right Flavortown user when a Slack ID matches several
+    #[clap(long, env = "CRIMSON_INTERACTIVE")]
+    pub interactive: bool,
+
+    /// Leave out helpers who can't be matched to a Flavortown user instead of
+    /// asking whether to abort
+    #[clap(long, env = "CRIMSON_SKIP_UNRESOLVED")]
+    pub skip_unresolved: bool,
+
+    /// After printing the payouts, wait for them to be given and then check
+    /// that every helper's cookie balance went up by the right amount
+    #[clap(long, env = "CRIMSON_VERIFY_BALANCES")]
+    pub verify_balances: bool,
+
+    /// Save the payout run and what each helper was owed to the
+    /// `crimson_payout_runs` and `crimson_payout_items` tables in the (first)
+    /// database, creating them if needed
+    #[clap(long, env = "CRIMSON_RECORD")]
+    pub record: bool,
+
+    /// Go ahead even if the period overlaps a payout run recorded with
+    /// --record that paid helpers the same way
+    #[clap(long, env = "CRIMSON_ALLOW_OVERLAP")]
+    pub allow_overlap: bool,
+
+    /// Post the payout list (in the Slack message format) to Slack, via
+    /// SLACK_WEBHOOK_URL or, with --slack-channel, as the SLACK_BOT_TOKEN bot.
+    /// The config file's `[notifier]` can send it somewhere else instead.
+    #[clap(long, env = "CRIMSON_POST_TO_SLACK")]
+    pub post_to_slack: bool,
+
+    /// Show helpers as Slack mentions (`<@SLACKID>`) in the payout list, so
+    /// that posting it pings them. Where mentions can't be used, like
+    /// Mattermost, they're shown as `@` and their name.
+    #[clap(long, env = "CRIMSON_MENTIONS")]
+    pub mentions: bool,
+
+    /// Post a congratulations message for the helper who closed the most
+    /// tickets, with their avatar and stats, to the same place as
+    /// --post-to-slack
+    #[clap(long, env = "CRIMSON_CELEBRATE")]
+    pub celebrate: bool,
+
+    /// Channel ID for --post-to-slack and --celebrate to post in as the bot
+    #[clap(long, env = "SLACK_CHANNEL")]
+    pub slack_channel: Option<String>,
+
+    /// Post as replies to this message in --slack-channel (given by its `ts`),
+    /// e.g. to keep each month's payout updates in one thread
+    #[clap(long, env = "SLACK_THREAD_TS", requires = "slack_channel")]
+    pub slack_thread_ts: Option<String>,
+
+    /// Also send replies posted with --slack-thread-ts to the channel
+    #[clap(long, env = "CRIMSON_SLACK_BROADCAST", requires = "slack_thread_ts")]
+    pub slack_broadcast: bool,
+
+    /// Send each paid helper a DM from the SLACK_BOT_TOKEN bot, saying how many
+    /// tickets they closed and how many cookies they got
+    #[clap(long, env = "CRIMSON_DM_RECIPIENTS")]
+    pub dm_recipients: bool,
+
+    /// Print the DMs that --dm-recipients would send instead of sending them
+    #[clap(long, env = "CRIMSON_DM_DRY_RUN", requires = "dm_recipients")]
+    pub dm_dry_run: bool,
+
+    /// Post the leaderboard and a payout summary, as an embed, to a Discord
+    /// channel via one of its webhooks
+    #[cfg(feature = "discord")]
+    #[clap(long, env = "DISCORD_WEBHOOK_URL")]
+    pub discord_webhook: Option<Url>,
+
+    /// Annotate each payout with what it could buy from the Flavortown shop
+    #[clap(long, env = "CRIMSON_SHOP_CONTEXT")]
+    pub shop_context: bool,
+
+    #[clap(flatten)]
+    pub flavortown: FlavortownArgs,
+
+    #[clap(flatten)]
+    pub ledger: LedgerArgs,
+}
+
+/// How to talk to the Flavortown API
+#[derive(Args)]
+pub struct FlavortownArgs {
+    /// Read the Flavortown API key from this file, or from stdin if it's `-`
+    #[clap(long, env = "CRIMSON_API_KEY_FILE")]
+    pub api_key_file: Option<PathBuf>,
+
+    /// Flavortown API version to use (detected from FLAVORTOWN_API_BASE by default)
+    #[clap(long, env = "CRIMSON_API_VERSION", value_enum)]
+    pub api_version: Option<ApiVersion>,
+
+    /// Seconds to wait for the Flavortown API before giving up on a request
+    #[clap(long, env = "FLAVORTOWN_TIMEOUT_SECS", default_value_t = 30)]
+    pub http_timeout: u64,
+
+    /// How many helpers to look up on Flavortown at once
+    #[clap(long, env = "CRIMSON_CONCURRENCY", default_value_t = DEFAULT_MAX_CONCURRENT_LOOKUPS)]
+    pub concurrency: usize,
+
+    /// Extra root certificate(s) to trust for the Flavortown API, as a PEM file
+    #[clap(long, env = "FLAVORTOWN_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// Where to keep the local ledger of payout runs
+#[derive(Args)]
+pub struct LedgerArgs {
+    /// Add the run to this JSONL ledger file, instead of the one in the config
+    /// file or in crimson's data directory
+    #[clap(long, env = "CRIMSON_LEDGER")]
+    pub ledger: Option<PathBuf>,
+
+    /// Leave the run out of the ledger
+    #[clap(long, env = "CRIMSON_NO_LEDGER", conflicts_with = "ledger")]
+    pub no_ledger: bool,
+}
+
+#[derive(Debug, clap::Args)]
+#[group(multiple = false)]
+pub struct PayoutSpecifierArgs {
+    /// Pays out helpers at a fixed rate of cookies per ticket, like 2.5/ticket
+    #[clap(short = 'r', long, env = "CRIMSON_COOKIE_RATE", value_parser = amount::parse_rate)]
+    pub cookie_rate: Option<Decimal>,
+    /// Pays out helpers based on a cookie pool, like 1000 or 1.5k, distributed proportionally to the number of tickets closed
+    #[clap(short = 'p', long, env = "CRIMSON_COOKIE_POOL", value_parser = amount::parse_pool)]
+    pub cookie_pool: Option<i64>,
+}
+
+/// Fills in whatever wasn't given on the command line (or in the environment)
+/// from the config file, or asks for it if there's a `wizard`
+pub fn apply_config(
+    command: &mut Command,
+    config: &Config,
+    wizard: Option<&SetupWizard>,
+) -> Result<(), anyhow::Error> {
+    let (database, timezone) = match command {
+        // Everything that executing a plan needs is in the plan
+        Command::Payout(payout_args) if payout_args.command.is_some() => return Ok(()),
+        Command::Payout(payout_args) => {
+            let defaults = config.payout_preset(payout_args.preset.as_deref())?;
+            let table = match &payout_args.preset {
+                Some(preset) => format!("[preset.{}]", preset),
+                None => "[payout]".to_string(),
+            };
+            let specifier = &mut payout_args.payout_specifier;
+            if specifier.cookie_rate.is_none() && specifier.cookie_pool.is_none() {
+                specifier.cookie_rate = defaults.cookie_rate;
+                specifier.cookie_pool = defaults.cookie_pool;
+            }
+            match (specifier.cookie_rate, specifier.cookie_pool) {
+                (Some(_), Some(_)) => {
+                    return Err(anyhow::anyhow!(
+                        "The config file's {} can't have both cookie_rate and cookie_pool",
+                        table
+                    ));
+                }
+                (None, None) => {
+                    return Err(anyhow::anyhow!(
+                        "Either --cookie-rate or --cookie-pool is needed (or a default in the config file's {})",
+                        table
+                    ));
+                }
+                _ => {}
+            }
+            // Filters can't be combined with a custom query, so leave them be
+            if payout_args.query_file.is_none() {
+                if payout_args.exclude_category.is_empty() {
+                    payout_args.exclude_category = defaults.exclude_category;
+                }
+                if !payout_args.exclude_reopened && !payout_args.include_reopened {
+                    payout_args.exclude_reopened = defaults.exclude_reopened.unwrap_or(false);
+                }
+            }
+            if payout_args.format.is_none()
+                && let Some(format) = &defaults.format
+            {
+                payout_args.format =
+                    Some(PayoutListFormat::from_str(format, true).map_err(|_| {
+                        anyhow::anyhow!("Invalid format {:?} in config file", format)
+                    })?);
+            }
+            if payout_args.flavortown.api_key_file.as_deref() == Some(Path::new("-"))
+                && payout_args.database.db_url_file.as_deref() == Some(Path::new("-"))
+            {
+                return Err(anyhow::anyhow!(
+                    "--api-key-file and --db-url-file can't both be read from stdin"
+                ));
+            }
+            (&mut payout_args.database, Some(&mut payout_args.timezone))
+        }
+        Command::Db(DbCommand::Inspect(db_args) | DbCommand::RefreshView(db_args)) => {
+            (db_args, None)
+        }
+        #[cfg(feature = "server")]
+        Command::Serve(serve_args) => (&mut serve_args.database, Some(&mut serve_args.timezone)),
+        Command::Daemon(daemon_args) => (&mut daemon_args.database, None),
+        Command::Usergroup(UsergroupCommand::Sync(sync_args)) => {
+            (&mut sync_args.database, Some(&mut sync_args.timezone))
+        }
+        Command::Canvas(CanvasCommand::Update(update_args)) => {
+            (&mut update_args.database, Some(&mut update_args.timezone))
+        }
+        Command::Secrets(_) => return Ok(()),
+        #[cfg(feature = "self-update")]
+        Command::SelfUpdate(_) => return Ok(()),
+        Command::Run => unreachable!("`crimson run` is turned into a payout beforehand"),
+    };
+    if let Some(timezone) = timezone
+        && timezone.is_none()
+    {
+        *timezone = config.timezone()?;
+    }
+    if let Some(db_url_file) = &database.db_url_file {
+        database.db = secrets::read_file(db_url_file)?
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+    }
+    if database.db.is_empty() {
+        database.db = config.database.urls()?;
+    }
+    if database.db.is_empty() {
+        let Some(wizard) = wizard else {
+            return Err(anyhow::anyhow!(
+                "No database to read tickets from: pass --db, set DATABASE_URL, or add a [database] to the config file"
+            ));
+        };
+        database.db = wizard
+            .ask("database URL", "database", "url")?
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+    }
+    Ok(())
+}
+
+/// Asks the operator a yes/no question, defaulting to no (including when
+/// there's nobody at a terminal to answer)
+pub fn confirm(question: &str) -> Result<bool, anyhow::Error> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Like `confirm`, but for the middle of a payout run, where the question has
+/// to give way to Ctrl-C
+pub async fn confirm_interruptible(question: &str) -> Result<bool, anyhow::Error> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let answer = read_line_interruptible().await?.unwrap_or_default();
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Reads a line from stdin, or `None` at the end of it. The read happens on a
+/// thread of its own, so that waiting for it can be cancelled (by Ctrl-C)
+/// like any other future.
+pub async fn read_line_interruptible() -> Result<Option<String>, anyhow::Error> {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        let result = std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map(|read| (read > 0).then_some(line));
+        let _ = sender.send(result);
+    });
+    Ok(receiver.await??)
+}
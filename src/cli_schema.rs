@@ -0,0 +1,100 @@
+use std::any::TypeId;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Arg, ArgAction, Command};
+use reqwest::Url;
+use rust_decimal::Decimal;
+use serde_json::{Value, json};
+
+/// Describes a command, its flags and its subcommands as JSON, so that other
+/// tools (like a form in the web dashboard) can be generated from it
+pub fn command_schema(command: &Command) -> Value {
+    json!({
+        "name": command.get_name(),
+        "about": command.get_about().map(ToString::to_string),
+        "aliases": command.get_visible_aliases().collect::<Vec<_>>(),
+        "args": command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .map(arg_schema)
+            .collect::<Vec<_>>(),
+        "subcommands": command
+            .get_subcommands()
+            .filter(|subcommand| !subcommand.is_hide_set())
+            .map(command_schema)
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn arg_schema(arg: &Arg) -> Value {
+    let value_type = value_type(arg);
+    // Switches can technically be given true or false, but that's not worth
+    // listing
+    let possible_values: Vec<Value> = match value_type {
+        "boolean" => Vec::new(),
+        _ => arg.get_possible_values(),
+    }
+    .iter()
+    .filter(|value| !value.is_hide_set())
+    .map(|value| {
+        json!({
+            "name": value.get_name(),
+            "help": value.get_help().map(ToString::to_string),
+        })
+    })
+    .collect();
+    json!({
+        "id": arg.get_id().as_str(),
+        "long": arg.get_long(),
+        "short": arg.get_short(),
+        "positional": arg.is_positional(),
+        "type": value_type,
+        "multiple": matches!(arg.get_action(), ArgAction::Append),
+        "possible_values": possible_values,
+        "default": arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy())
+            .collect::<Vec<_>>(),
+        "env": arg.get_env().map(|env| env.to_string_lossy()),
+        "required": arg.is_required_set(),
+        "global": arg.is_global_set(),
+        "help": arg.get_long_help().or(arg.get_help()).map(ToString::to_string),
+    })
+}
+
+/// A rough type for the values a flag takes: `boolean` for switches, `enum`
+/// for a fixed set of values, and otherwise what it's parsed as
+fn value_type(arg: &Arg) -> &'static str {
+    if !arg.get_action().takes_values() {
+        return "boolean";
+    }
+    if !arg.get_possible_values().is_empty() {
+        return "enum";
+    }
+    let type_id = arg.get_value_parser().type_id();
+    if [
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+    ]
+    .iter()
+    .any(|id| type_id == *id)
+    {
+        "integer"
+    } else if type_id == TypeId::of::<f64>() || type_id == TypeId::of::<Decimal>() {
+        "number"
+    } else if type_id == TypeId::of::<PathBuf>() {
+        "path"
+    } else if type_id == TypeId::of::<Url>() {
+        "url"
+    } else if type_id == TypeId::of::<SocketAddr>() {
+        "socket_address"
+    } else {
+        "string"
+    }
+}
@@ -0,0 +1,407 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::{Context, Ok, Result};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use reqwest::Url;
+use time::OffsetDateTime;
+use time::macros::format_description;
+use time_tz::{OffsetDateTimeExt, TimeZone, timezones};
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+#[cfg(feature = "otel")]
+use tracing::warn;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+#[cfg(feature = "server")]
+use crate::cli::ServeArgs;
+use crate::cli::{
+    CanvasCommand, CanvasUpdateArgs, Command, CrimsonArgs, DaemonArgs, DbArgs, DbCommand,
+    LogFormat, PayoutCommand, SecretsCommand, UsergroupCommand, UsergroupSyncArgs, apply_config,
+};
+use crate::config::Config;
+use crate::db::{
+    Database, LeaderboardFilters, MergedTicketSource, TicketSource, check_schemas,
+    connect_databases,
+};
+use crate::error_report::ErrorReporter;
+use crate::failure::{Failure, FailureContext};
+use crate::notify::{Markup, Span};
+#[cfg(feature = "self-update")]
+use crate::self_update;
+use crate::setup::SetupWizard;
+#[cfg(feature = "otel")]
+use crate::telemetry;
+use crate::{cli_schema, daemon, digest, payout, pipeline, plan, secrets, slack};
+#[cfg(feature = "server")]
+use crate::{metrics, server};
+
+/// Runs crimson's command-line interface, with the arguments that the process
+/// was started with
+pub async fn run() -> Result<(), anyhow::Error> {
+    // ring rather than aws-lc-rs, as it cross-compiles to static musl binaries
+    // without needing CMake or a C++ toolchain. It's only an error if a
+    // provider has already been installed, which is fine.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let mut args = CrimsonArgs::parse();
+    let Some(command) = args.command.take() else {
+        if !args.dump_cli_json {
+            CrimsonArgs::command()
+                .error(ErrorKind::MissingSubcommand, "a subcommand is needed")
+                .exit();
+        }
+        let schema = cli_schema::command_schema(&CrimsonArgs::command());
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    };
+    #[cfg(feature = "otel")]
+    let (tracer_provider, tracing_error) = match telemetry::tracer_provider() {
+        Result::Ok(tracer_provider) => (tracer_provider, None),
+        Err(error) => (None, Some(error)),
+    };
+    #[cfg(feature = "otel")]
+    init_logging(&args, tracer_provider.as_ref().map(telemetry::layer));
+    #[cfg(not(feature = "otel"))]
+    init_logging(&args, None);
+    #[cfg(feature = "otel")]
+    if let Some(error) = tracing_error {
+        warn!("Not exporting traces: {:#}", error);
+    }
+    let mut reporter = ErrorReporter::new(args.error_webhook_url.clone());
+    let result = run_command(&args, command, &mut reporter).await;
+    if let Err(error) = &result {
+        reporter.report(error).await;
+    }
+    #[cfg(feature = "otel")]
+    if let Some(tracer_provider) = tracer_provider {
+        telemetry::shutdown(tracer_provider).await;
+    }
+    result
+}
+
+/// Runs the subcommand given, telling `reporter` what it's working on
+async fn run_command(
+    args: &CrimsonArgs,
+    mut command: Command,
+    reporter: &mut ErrorReporter,
+) -> Result<(), anyhow::Error> {
+    // Before the config is loaded, so that a config file this version doesn't
+    // understand doesn't stop it being updated to one that does
+    #[cfg(feature = "self-update")]
+    if let Command::SelfUpdate(update_args) = &command {
+        return self_update::self_update(update_args).await;
+    }
+    let config_path = args.config.clone().or_else(Config::find);
+    let mut config = match &config_path {
+        Some(path) => Config::load(path).failure(Failure::Config)?,
+        None => Config::default(),
+    };
+    if let Some(path) = &config_path {
+        let webhook_url = config
+            .error_reports
+            .webhook_url
+            .as_deref()
+            .map(Url::parse)
+            .transpose()
+            .context("[error_reports] webhook_url is not a valid URL")
+            .failure(Failure::Config)?;
+        reporter.use_config(path, webhook_url.as_ref());
+    }
+    if let Some(profile) = &args.profile {
+        config.use_profile(profile).failure(Failure::Config)?;
+    }
+    let wizard = SetupWizard::new(
+        config_path.unwrap_or_else(|| PathBuf::from("crimson.toml")),
+        args.profile.clone(),
+    );
+    if let Command::Run = command {
+        let run = config
+            .run
+            .as_ref()
+            .context("The config file has no [run] table for `crimson run`")
+            .failure(Failure::Config)?;
+        command = Command::Payout(pipeline::payout_args(run).failure(Failure::Config)?);
+    }
+    apply_config(&mut command, &config, wizard.as_ref()).failure(Failure::Config)?;
+    match &command {
+        Command::Payout(command_args) => match &command_args.command {
+            Some(PayoutCommand::Execute(execute_args)) => {
+                plan::execute(execute_args, &config, wizard.as_ref()).await
+            }
+            None => payout::payout(command_args, &config, wizard.as_ref(), reporter).await,
+        },
+        Command::Db(DbCommand::Inspect(db_args)) => inspect_databases(db_args).await,
+        Command::Db(DbCommand::RefreshView(db_args)) => refresh_leaderboard_views(db_args).await,
+        #[cfg(feature = "server")]
+        Command::Serve(serve_args) => serve(serve_args).await,
+        Command::Daemon(daemon_args) => run_daemon(daemon_args, &config).await,
+        Command::Usergroup(UsergroupCommand::Sync(sync_args)) => sync_usergroup(sync_args).await,
+        Command::Canvas(CanvasCommand::Update(update_args)) => update_canvas(update_args).await,
+        Command::Secrets(SecretsCommand::Set { secret }) => {
+            secrets::set(*secret, args.profile.as_deref())
+        }
+        Command::Secrets(SecretsCommand::Delete { secret }) => {
+            secrets::delete(*secret, args.profile.as_deref())
+        }
+        Command::Run => unreachable!("`crimson run` is turned into a payout beforehand"),
+        #[cfg(feature = "self-update")]
+        Command::SelfUpdate(_) => unreachable!("`crimson self-update` is run beforehand"),
+    }
+}
+
+/// Whether to colour the diagnostics: only on a terminal, unless NO_COLOR or
+/// CLICOLOR_FORCE say otherwise. Older Windows consoles show the colour codes
+/// as text until they're told to understand them, so if that fails, it's no.
+fn use_colour() -> bool {
+    if anstyle_query::no_color() {
+        return false;
+    }
+    if anstyle_query::clicolor_force() {
+        return true;
+    }
+    std::io::stderr().is_terminal() && anstyle_query::windows::enable_ansi_colors().unwrap_or(true)
+}
+
+/// Sends crimson's diagnostics to stderr, so that reports on stdout can be
+/// piped somewhere without them. Spans go to the `tracing` layer, if given.
+fn init_logging(args: &CrimsonArgs, tracing: Option<Box<dyn Layer<Registry> + Send + Sync>>) {
+    let level = if args.verbose {
+        LevelFilter::DEBUG
+    } else if args.quiet {
+        LevelFilter::WARN
+    } else {
+        LevelFilter::INFO
+    };
+    // Leave out the libraries' own logs, which are only useful when debugging them
+    let filter = Targets::new().with_target(env!("CARGO_CRATE_NAME"), level);
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(use_colour())
+        .with_target(false);
+    let layer = match args.log_format {
+        // Fields are there for log collectors, so people only get the message
+        LogFormat::Text => layer
+            .without_time()
+            .fmt_fields(format::debug_fn(|writer, field, value| {
+                if field.name() == "message" {
+                    write!(writer, "{:?}", value)
+                } else {
+                    Result::Ok(())
+                }
+            }))
+            .boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(tracing)
+        .with(layer.with_filter(filter))
+        .init();
+}
+
+async fn inspect_databases(db_args: &DbArgs) -> Result<(), anyhow::Error> {
+    let (databases, _tunnels) = connect_databases(db_args, None).await?;
+    for (i, database) in databases.iter().enumerate() {
+        if databases.len() > 1 {
+            println!("Database #{}:", i + 1);
+        }
+        let info = database.inspect().await?;
+        match &info.latest_migration {
+            Some(migration) => println!("Schema version: {} (latest Prisma migration)", migration),
+            None => println!("Schema version: unknown (no Prisma migrations found)"),
+        }
+        for (table, count) in &info.row_counts {
+            println!("\"{}\" rows: {}", table, count);
+        }
+        for index in &info.indexes {
+            println!(
+                "Index on \"{}\".\"{}\": {}",
+                index.table,
+                index.column,
+                if index.indexed { "yes" } else { "MISSING" }
+            );
+        }
+        match &info.schema_error {
+            Some(error) => println!("Leaderboard query: won't work ({})", error),
+            None => {
+                println!("Leaderboard query: should work");
+                for index in info.indexes.iter().filter(|index| !index.indexed) {
+                    println!(
+                        "Tip: add an index on \"{}\".\"{}\", otherwise the leaderboard query has to scan every ticket",
+                        index.table, index.column
+                    );
+                }
+            }
+        }
+        if i + 1 < databases.len() {
+            println!();
+        }
+    }
+    Ok(())
+}
+
+async fn refresh_leaderboard_views(db_args: &DbArgs) -> Result<(), anyhow::Error> {
+    let (databases, _tunnels) = connect_databases(db_args, None).await?;
+    check_schemas(&databases, &LeaderboardFilters::helpers()).await?;
+    for (i, database) in databases.iter().enumerate() {
+        let Database::Postgres(database) = database else {
+            return Err(anyhow::anyhow!(
+                "Database #{} isn't Postgres, which is the only database with materialized views",
+                i + 1
+            ));
+        };
+        let refresh_started = Instant::now();
+        let created = database.refresh_leaderboard_view().await?;
+        info!(
+            "{} leaderboard view in database #{} ({}ms)",
+            if created { "Created" } else { "Refreshed" },
+            i + 1,
+            refresh_started.elapsed().as_millis()
+        );
+    }
+    Ok(())
+}
+
+/// Has dashboards read from the leaderboard view of any database that has one
+/// (see `crimson db refresh-view`), rather than counting up every ticket
+async fn with_leaderboard_views(databases: Vec<Database>) -> Result<Vec<Database>, anyhow::Error> {
+    let mut with_views = Vec::new();
+    for (i, database) in databases.into_iter().enumerate() {
+        let database = match database {
+            Database::Postgres(postgres) if postgres.has_leaderboard_view().await? => {
+                info!(
+                    "Reading leaderboards from the view in database #{}, which is only as up to date as the last `crimson db refresh-view`",
+                    i + 1
+                );
+                Database::Postgres(postgres.with_leaderboard_view())
+            }
+            database => database,
+        };
+        with_views.push(database);
+    }
+    Ok(with_views)
+}
+
+#[cfg(feature = "server")]
+async fn serve(serve_args: &ServeArgs) -> Result<(), anyhow::Error> {
+    let (databases, _tunnels) =
+        connect_databases(&serve_args.database, Some(server::QUERY_TIMEOUT)).await?;
+    check_schemas(&databases, &LeaderboardFilters::helpers()).await?;
+    let databases = with_leaderboard_views(databases).await?;
+    server::serve(
+        serve_args.listen,
+        server::SlashCommandServer {
+            ticket_source: MergedTicketSource::new(databases),
+            signing_secret: serve_args.slack_signing_secret.clone(),
+            timezone: serve_args.timezone.unwrap_or(timezones::db::UTC),
+        },
+    )
+    .await
+}
+
+async fn run_daemon(daemon_args: &DaemonArgs, config: &Config) -> Result<(), anyhow::Error> {
+    let (databases, _tunnels) = connect_databases(&daemon_args.database, None).await?;
+    check_schemas(&databases, &LeaderboardFilters::helpers()).await?;
+    let databases = with_leaderboard_views(databases).await?;
+    #[cfg(feature = "server")]
+    if let Some(listen) = daemon_args.metrics_listen {
+        metrics::serve(listen).await?;
+    }
+    daemon::run(config, MergedTicketSource::new(databases)).await
+}
+
+async fn sync_usergroup(sync_args: &UsergroupSyncArgs) -> Result<(), anyhow::Error> {
+    let bot_token =
+        if sync_args.dry_run {
+            None
+        } else {
+            Some(std::env::var("SLACK_BOT_TOKEN").context(
+                "SLACK_BOT_TOKEN environment variable must be set to update user groups",
+            )?)
+        };
+    let period = sync_args
+        .period
+        .resolve(sync_args.timezone.unwrap_or(timezones::db::UTC))?;
+    let (databases, _tunnels) = connect_databases(&sync_args.database, None).await?;
+    let filters = LeaderboardFilters::helpers();
+    check_schemas(&databases, &filters).await?;
+    let ticket_source = MergedTicketSource::new(databases);
+    let leaderboard = ticket_source
+        .helper_leaderboard(period.start, period.end, &filters)
+        .await?;
+    let top_helpers: Vec<(&String, i64)> = leaderboard
+        .rankings()
+        .into_iter()
+        .take(sync_args.top)
+        .collect();
+    if top_helpers.is_empty() {
+        // Slack won't let a user group be emptied, and it's probably a mistake
+        return Err(anyhow::anyhow!(
+            "Nobody has closed any tickets in that period, so there's no one to put in the group"
+        ));
+    }
+
+    let pretty_printer = format_description!(
+        "[weekday] [day padding:none] [month repr:short] [year] (@ [hour]:[minute])"
+    );
+    println!(
+        "Top {} helper(s) from {} to {}:",
+        top_helpers.len(),
+        period.start.format(&pretty_printer)?,
+        period.end.format(&pretty_printer)?
+    );
+    for (slack_id, tickets) in &top_helpers {
+        println!("- {} ({} tickets)", slack_id, tickets);
+    }
+    let slack_ids: Vec<&String> = top_helpers.iter().map(|(slack_id, _)| *slack_id).collect();
+    match bot_token {
+        Some(bot_token) => {
+            slack::set_usergroup_members(&bot_token, &sync_args.group, &slack_ids).await?;
+            info!("Updated user group {}", sync_args.group);
+        }
+        None => println!("Would set user group {} to them", sync_args.group),
+    }
+    Ok(())
+}
+
+async fn update_canvas(update_args: &CanvasUpdateArgs) -> Result<(), anyhow::Error> {
+    let bot_token = std::env::var("SLACK_BOT_TOKEN")
+        .context("SLACK_BOT_TOKEN environment variable must be set to update canvases")?;
+    let timezone = update_args.timezone.unwrap_or(timezones::db::UTC);
+    let (databases, _tunnels) = connect_databases(&update_args.database, None).await?;
+    check_schemas(&databases, &LeaderboardFilters::helpers()).await?;
+    let ticket_source = MergedTicketSource::new(with_leaderboard_views(databases).await?);
+
+    let mut message = digest::leaderboard_digest(
+        &ticket_source,
+        update_args.period,
+        timezone,
+        update_args.top,
+    )
+    .await?;
+    let updated_at =
+        OffsetDateTime::now_utc()
+            .to_timezone(timezone)
+            .format(format_description!(
+                "[day padding:none] [month repr:short] [year] at [hour]:[minute]"
+            ))?;
+    message.push_line(Vec::new());
+    message.push_line(vec![Span::Text(format!(
+        "_Last updated {} ({})_",
+        updated_at,
+        timezone.name()
+    ))]);
+    slack::replace_canvas_content(
+        &bot_token,
+        &update_args.canvas_id,
+        &message.render(Markup::SlackCanvas),
+    )
+    .await?;
+    info!("Updated canvas {}", update_args.canvas_id);
+    Ok(())
+}
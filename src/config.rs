@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Ok, Result};
+use directories::ProjectDirs;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use time_tz::Tz;
+
+use crate::natural_date::parse_timezone;
+use crate::notify::NotifierConfig;
+
+/// Settings from crimson's TOML config file. Anything given on the command
+/// line (or in the environment) takes precedence.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Timezone (e.g. Europe/London) that schedules and periods are in, UTC
+    /// by default
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub flavortown: FlavortownConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub payout: PayoutConfig,
+    /// Named sets of `[payout]` settings, for `crimson payout --preset`
+    #[serde(default, rename = "preset")]
+    pub presets: BTreeMap<String, PayoutConfig>,
+    /// Where announcements get posted, unless overridden (Slack by default)
+    #[serde(default)]
+    pub notifier: NotifierConfig,
+    #[serde(default, rename = "digest")]
+    pub digests: Vec<DigestConfig>,
+    /// The payout pipeline for `crimson run`
+    pub run: Option<RunConfig>,
+    #[serde(default, rename = "profile")]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    #[serde(default)]
+    pub error_reports: ErrorReportsConfig,
+    #[serde(default)]
+    pub ledger: LedgerConfig,
+    /// Which of the profiles is in use
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// A `[profile.<name>]` table, for switching between deployments (e.g.
+/// staging and production) with `--profile`. Anything it sets replaces the
+/// top-level setting.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub flavortown: FlavortownConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+}
+
+/// The `[error_reports]` table, for hearing about failed runs
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorReportsConfig {
+    /// Where to post a report when crimson fails, if not given with
+    /// --error-webhook-url
+    pub webhook_url: Option<String>,
+}
+
+/// The `[ledger]` table, for the file that payout runs are added to
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LedgerConfig {
+    /// Where to keep the ledger, if not given with --ledger (crimson's data
+    /// directory by default)
+    pub path: Option<PathBuf>,
+}
+
+/// The `[flavortown]` table, for when FLAVORTOWN_API_BASE isn't set
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FlavortownConfig {
+    pub api_base: Option<String>,
+    /// The API key itself, if it isn't in the environment. Keep the file
+    /// private if this is set!
+    pub api_key: Option<String>,
+    /// Environment variable to read the API key from, instead of
+    /// FLAVORTOWN_API_KEY
+    pub api_key_env: Option<String>,
+}
+
+/// The `[database]` table, for when `--db` isn't given
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Database URL(s), separated by spaces like `--db`
+    pub url: Option<String>,
+    /// Environment variable to read the database URL(s) from, so that
+    /// passwords don't have to be in the config file
+    pub url_env: Option<String>,
+}
+
+/// The `[payout]` table, with defaults for `crimson payout`, or a
+/// `[preset.<name>]` table with settings for `--preset` to use instead
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PayoutConfig {
+    pub cookie_rate: Option<Decimal>,
+    pub cookie_pool: Option<i64>,
+    /// Categories of tickets not to count
+    #[serde(default)]
+    pub exclude_category: Vec<String>,
+    /// Unset to leave it to the `[payout]` table (for a preset) or the flags
+    pub exclude_reopened: Option<bool>,
+    /// Payout list format (`payout`, `message`, `table`, `json` or `csv`)
+    pub format: Option<String>,
+}
+
+/// The `[run]` table, which declares a whole payout for `crimson run`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RunConfig {
+    /// What to do, in order
+    pub stages: Vec<Stage>,
+    /// Which period to pay out for (e.g. `last-week`), or `start`, `end`,
+    /// `duration`, `week` or `month` as for `crimson payout`
+    pub period: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub duration: Option<String>,
+    pub week: Option<String>,
+    pub month: Option<String>,
+    /// `[preset.<name>]` to use instead of the `[payout]` defaults
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub notify: NotifyStageConfig,
+}
+
+/// A step of `crimson run`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Work out the payouts and print them
+    Compute,
+    /// Save them to the database, like `--record`
+    Archive,
+    /// Tell people about them, as set out in `[run.notify]`
+    Notify,
+    /// Wait for them to be given out (by hand, as crimson can't give out
+    /// cookies) and then check everyone's balance, like `--verify-balances`
+    Execute,
+}
+
+/// The `[run.notify]` table, saying who the notify stage tells
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyStageConfig {
+    /// Post the payout list to the `[notifier]`, like `--post-to-slack`
+    #[serde(default)]
+    pub post: bool,
+    /// Like `--celebrate`
+    #[serde(default)]
+    pub celebrate: bool,
+    /// Like `--mentions`
+    #[serde(default)]
+    pub mentions: bool,
+    /// DM each paid helper, like `--dm-recipients`
+    #[serde(default)]
+    pub dm: bool,
+    /// Like `--discord-webhook`
+    pub discord_webhook: Option<String>,
+}
+
+/// A leaderboard to post on a schedule, for `crimson daemon`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DigestConfig {
+    /// When to post, as a cron expression (e.g. `0 9 * * MON`)
+    pub schedule: String,
+    /// Which period's leaderboard to post (e.g. `last-week`)
+    #[serde(default = "default_period")]
+    pub period: String,
+    /// How many helpers to list
+    #[serde(default = "default_top")]
+    pub top: usize,
+    /// Where to post this digest, if not where the top-level notifier posts
+    pub notifier: Option<NotifierConfig>,
+}
+
+fn default_period() -> String {
+    "this-week".to_string()
+}
+
+/// Where crimson keeps its files on this platform (e.g. `%APPDATA%\\crimson`
+/// on Windows), if there's a home directory to put them in
+pub fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("", "", "crimson")
+}
+
+/// The directories that `crimson.toml` (and a `.env`) can live in, besides the
+/// current directory: `~/.config/crimson/` (or under `XDG_CONFIG_HOME`), then
+/// the platform's own config directory, like `%APPDATA%\\crimson\\config` on
+/// Windows or `~/Library/Application Support/crimson` on macOS
+pub fn config_dirs() -> Vec<PathBuf> {
+    let xdg_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("crimson"));
+    let platform_dir = project_dirs().map(|dirs| dirs.config_dir().to_path_buf());
+    let mut dirs: Vec<PathBuf> = xdg_dir.into_iter().chain(platform_dir).collect();
+    dirs.dedup();
+    dirs
+}
+
+/// Loads environment variables from `.env` in the current directory (or the
+/// nearest parent with one), then from `.env` in the config directories.
+/// Variables that are already set win. Files that can't be read are warned
+/// about rather than skipped silently, as a `.env` saved by Notepad or
+/// PowerShell can be in an encoding that isn't UTF-8.
+pub fn load_env_files() {
+    let found = dotenvy::dotenv().map(|_| ());
+    let candidates = config_dirs().into_iter().map(|dir| {
+        let path = dir.join(".env");
+        (path.clone(), dotenvy::from_path(path))
+    });
+    for (path, result) in std::iter::once((PathBuf::from(".env"), found)).chain(candidates) {
+        match result {
+            Err(error) if !error.not_found() => {
+                eprintln!("Warning: couldn't load {}: {}", path.display(), error)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn default_top() -> usize {
+    10
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&config).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Looks for `crimson.toml` in the current directory, then in each of
+    /// `config_dirs`
+    pub fn find() -> Option<PathBuf> {
+        std::iter::once(PathBuf::from("crimson.toml"))
+            .chain(
+                config_dirs()
+                    .into_iter()
+                    .map(|dir| dir.join("crimson.toml")),
+            )
+            .find(|path| path.exists())
+    }
+
+    /// Applies the settings from `[profile.<name>]`
+    pub fn use_profile(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let Some(profile) = self.profiles.remove(name) else {
+            let profiles: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            return Err(anyhow::anyhow!(
+                "There's no [profile.{}] in the config file (it has: {})",
+                name,
+                if profiles.is_empty() {
+                    "none".to_string()
+                } else {
+                    profiles.join(", ")
+                }
+            ));
+        };
+        self.timezone = profile.timezone.or(self.timezone.take());
+        self.flavortown.api_base = profile
+            .flavortown
+            .api_base
+            .or(self.flavortown.api_base.take());
+        self.flavortown.api_key = profile
+            .flavortown
+            .api_key
+            .or(self.flavortown.api_key.take());
+        self.flavortown.api_key_env = profile
+            .flavortown
+            .api_key_env
+            .or(self.flavortown.api_key_env.take());
+        self.profile = Some(name.to_string());
+        // A profile's database replaces the top-level one entirely, as it
+        // can't have both a url and a url_env
+        if profile.database.url.is_some() || profile.database.url_env.is_some() {
+            self.database = profile.database;
+        }
+        Ok(())
+    }
+
+    /// The `[payout]` settings, with those from `[preset.<name>]` on top
+    pub fn payout_preset(&self, name: Option<&str>) -> Result<PayoutConfig, anyhow::Error> {
+        let Some(name) = name else {
+            return Ok(self.payout.clone());
+        };
+        let Some(preset) = self.presets.get(name) else {
+            let presets: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+            return Err(anyhow::anyhow!(
+                "There's no [preset.{}] in the config file (it has: {})",
+                name,
+                if presets.is_empty() {
+                    "none".to_string()
+                } else {
+                    presets.join(", ")
+                }
+            ));
+        };
+        let mut payout = self.payout.clone();
+        // A preset's cookie_rate or cookie_pool replaces either of the
+        // defaults, as only one can be used
+        if preset.cookie_rate.is_some() || preset.cookie_pool.is_some() {
+            payout.cookie_rate = preset.cookie_rate;
+            payout.cookie_pool = preset.cookie_pool;
+        }
+        if !preset.exclude_category.is_empty() {
+            payout.exclude_category = preset.exclude_category.clone();
+        }
+        payout.exclude_reopened = preset.exclude_reopened.or(payout.exclude_reopened);
+        payout.format = preset.format.clone().or(payout.format);
+        Ok(payout)
+    }
+
+    pub fn timezone(&self) -> Result<Option<&'static Tz>, anyhow::Error> {
+        self.timezone
+            .as_deref()
+            .map(parse_timezone)
+            .transpose()
+            .context("Invalid timezone in config file")
+    }
+}
+
+impl DatabaseConfig {
+    /// The database URLs that the config file gives, if any
+    pub fn urls(&self) -> Result<Vec<String>, anyhow::Error> {
+        let urls = match (&self.url, &self.url_env) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "The config file's [database] can't have both url and url_env"
+                ));
+            }
+            (Some(url), None) => url.clone(),
+            (None, Some(url_env)) => std::env::var(url_env).with_context(|| {
+                format!(
+                    "{} environment variable (the config file's [database] url_env) not set",
+                    url_env
+                )
+            })?,
+            (None, None) => return Ok(Vec::new()),
+        };
+        Ok(urls.split_whitespace().map(str::to_string).collect())
+    }
+}
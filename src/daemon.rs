@@ -0,0 +1,115 @@
+use anyhow::{Context, Ok, Result};
+use time::OffsetDateTime;
+use time::macros::format_description;
+use time_tz::{Tz, timezones};
+use tracing::{error, info};
+
+use crate::config::Config;
+use crate::db::MergedTicketSource;
+use crate::digest;
+use crate::metrics::METRICS;
+use crate::natural_date::RelativePeriod;
+use crate::notify::{AnyNotifier, Notifier};
+use crate::schedule::Schedule;
+
+/// A digest from the config, checked and ready to go
+struct Digest {
+    schedule: Schedule,
+    period: RelativePeriod,
+    top: usize,
+    notifier: AnyNotifier,
+}
+
+/// Checks that every digest in the config makes sense
+fn load_digests(config: &Config) -> Result<(&'static Tz, Vec<Digest>), anyhow::Error> {
+    let timezone = config.timezone()?.unwrap_or(timezones::db::UTC);
+    if config.digests.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The config file doesn't have any [[digest]]s to post"
+        ));
+    }
+
+    let digests = config
+        .digests
+        .iter()
+        .enumerate()
+        .map(|(i, digest)| {
+            Ok(Digest {
+                schedule: Schedule::parse(&digest.schedule)?,
+                period: RelativePeriod::from_words(&digest.period).with_context(|| {
+                    format!(
+                        "Digest #{} has unknown period {:?} (expected something like last-week)",
+                        i + 1,
+                        digest.period
+                    )
+                })?,
+                top: digest.top,
+                notifier: digest
+                    .notifier
+                    .as_ref()
+                    .unwrap_or(&config.notifier)
+                    .build()
+                    .with_context(|| format!("Digest #{} can't be posted", i + 1))?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok((timezone, digests))
+}
+
+/// Posts the digests in the config file whenever they're due, until Ctrl-C is
+/// pressed. A digest that fails is reported and then tried again next time.
+pub async fn run(config: &Config, ticket_source: MergedTicketSource) -> Result<(), anyhow::Error> {
+    let (timezone, digests) = load_digests(config)?;
+    let pretty_printer = format_description!(
+        "[weekday] [day padding:none] [month repr:short] [year] (@ [hour]:[minute])"
+    );
+    info!("Loaded {} digest(s)", digests.len());
+
+    let now = OffsetDateTime::now_utc();
+    let mut next_due: Vec<Option<OffsetDateTime>> = digests
+        .iter()
+        .map(|digest| digest.schedule.next_after(now, timezone))
+        .collect();
+    loop {
+        let Some((due_at, due)) = next_due
+            .iter()
+            .enumerate()
+            .filter_map(|(i, due_at)| Some(((*due_at)?, i)))
+            .min()
+        else {
+            return Err(anyhow::anyhow!("None of the digests are ever due again"));
+        };
+        info!(
+            "Next up: digest #{} on {}",
+            due + 1,
+            due_at.format(&pretty_printer)?
+        );
+        let wait = (due_at - OffsetDateTime::now_utc()).max(time::Duration::ZERO);
+        tokio::select! {
+            _ = tokio::time::sleep(wait.unsigned_abs()) => {}
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping");
+                return Ok(());
+            }
+        }
+
+        let digest = &digests[due];
+        let result = async {
+            let message =
+                digest::leaderboard_digest(&ticket_source, digest.period, timezone, digest.top)
+                    .await?;
+            digest
+                .notifier
+                .notify(&message)
+                .await
+                .inspect_err(|_| METRICS.api_error(digest.notifier.platform()))
+        }
+        .await;
+        METRICS.run_finished("digest", result.is_ok());
+        match result {
+            Result::Ok(()) => info!("Posted digest #{}", due + 1),
+            Err(error) => error!("Failed to post digest #{}: {:#}", due + 1, error),
+        }
+        next_due[due] = digest.schedule.next_after(due_at, timezone);
+    }
+}
@@ -0,0 +1,35 @@
+use rust_decimal::Decimal;
+use time::OffsetDateTime;
+
+/// A payout that crimson has worked out, as saved by `--record`
+#[derive(Debug, Clone)]
+pub struct PayoutRun {
+    pub period_start: OffsetDateTime,
+    pub period_end: OffsetDateTime,
+    /// Set if helpers were paid a fixed number of cookies per ticket
+    pub cookie_rate: Option<Decimal>,
+    /// Set if helpers shared out a fixed pool of cookies
+    pub cookie_pool: Option<i64>,
+    pub items: Vec<PayoutItem>,
+}
+
+/// A payout run that was saved by an earlier `--record`
+#[derive(Debug, Clone)]
+pub struct RecordedRun {
+    pub id: i64,
+    pub period_start: OffsetDateTime,
+    pub period_end: OffsetDateTime,
+    pub cookie_rate: Option<Decimal>,
+    pub cookie_pool: Option<i64>,
+}
+
+/// What one helper was owed as part of a `PayoutRun`
+#[derive(Debug, Clone)]
+pub struct PayoutItem {
+    pub slack_id: String,
+    /// Not set if the helper couldn't be matched to a Flavortown user, so
+    /// wasn't paid
+    pub flavortown_user_id: Option<i64>,
+    pub tickets_closed: i64,
+    pub cookies: Decimal,
+}
@@ -0,0 +1,524 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use futures::future;
+use time::OffsetDateTime;
+use tokio_postgres::error::SqlState;
+
+#[cfg(feature = "sqlite")]
+use rusqlite::types::FromSql as SqliteValue;
+
+use crate::cli::DbArgs;
+use crate::failure::{Failure, FailureContext};
+use crate::tunnel::SshTunnel;
+
+mod ledger;
+mod mysql;
+mod postgres;
+mod query;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use ledger::{PayoutItem, PayoutRun, RecordedRun};
+pub use mysql::MysqlTicketSource;
+pub use postgres::PostgresTicketSource;
+pub use query::{LeaderboardFilters, Role};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteTicketSource;
+
+/// How many tickets were closed by each helper over a period
+#[derive(Debug, Clone, Default)]
+pub struct Leaderboard {
+    /// Tickets closed by each helper, keyed by Slack ID
+    pub helper_tickets: HashMap<String, i64>,
+    /// Tickets that can't be credited to anyone, because whoever closed them
+    /// wasn't recorded, has since been deleted, or has no Slack ID
+    pub unattributed_tickets: i64,
+}
+
+impl Leaderboard {
+    /// Helpers and how many tickets they closed, most first. Ties are broken
+    /// by Slack ID so that the order is stable.
+    pub fn rankings(&self) -> Vec<(&String, i64)> {
+        let mut rankings: Vec<(&String, i64)> = self
+            .helper_tickets
+            .iter()
+            .map(|(slack_id, tickets)| (slack_id, *tickets))
+            .collect();
+        rankings.sort_by(|(id_a, tickets_a), (id_b, tickets_b)| {
+            tickets_b.cmp(tickets_a).then_with(|| id_a.cmp(id_b))
+        });
+        rankings
+    }
+}
+
+/// Adds `(slack_id, tickets_closed)` rows to a leaderboard, where a missing
+/// Slack ID means the tickets weren't attributed to anyone
+impl Extend<(Option<String>, i64)> for Leaderboard {
+    fn extend<I: IntoIterator<Item = (Option<String>, i64)>>(&mut self, rows: I) {
+        for (slack_id, tickets_closed) in rows {
+            match slack_id {
+                Some(slack_id) => {
+                    *self.helper_tickets.entry(slack_id).or_insert(0) += tickets_closed
+                }
+                None => self.unattributed_tickets += tickets_closed,
+            }
+        }
+    }
+}
+
+impl FromIterator<(Option<String>, i64)> for Leaderboard {
+    fn from_iter<I: IntoIterator<Item = (Option<String>, i64)>>(rows: I) -> Self {
+        let mut leaderboard = Self::default();
+        leaderboard.extend(rows);
+        leaderboard
+    }
+}
+
+/// Somewhere that we can find out how many tickets each helper has closed
+// Futures from the concrete types (like `Database`) are still `Send`, which is
+// what servers built on crimson need
+#[allow(async_fn_in_trait)]
+pub trait TicketSource {
+    /// Counts the tickets closed by each helper between `start` (inclusive) and
+    /// `end` (exclusive) that match `filters`
+    async fn helper_leaderboard(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error>;
+}
+
+/// Waits until a running query should be given up on, either because Ctrl-C
+/// was pressed or because it's been running for longer than `timeout`, and
+/// returns an error saying which
+async fn query_interrupted(timeout: Option<Duration>) -> anyhow::Error {
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => anyhow::anyhow!("Database query cancelled"),
+        _ = timed_out => anyhow::anyhow!(
+            "Database query took longer than {}s (see --query-timeout)",
+            timeout.unwrap_or_default().as_secs()
+        ),
+    }
+}
+
+/// Checks that every column the leaderboard query needs with `filters` is
+/// among the `(table, column)` pairs found in the database, naming any that
+/// are missing
+fn check_required_columns(
+    found: &HashSet<(String, String)>,
+    filters: &LeaderboardFilters,
+) -> Result<(), anyhow::Error> {
+    let missing: Vec<String> = filters
+        .required_columns()
+        .into_iter()
+        .filter(|(table, column)| !found.contains(&(table.to_string(), column.to_string())))
+        .map(|(table, column)| format!("\"{}\".\"{}\"", table, column))
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Database doesn't look like a Nephthys database: missing {}",
+            missing.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// How long to wait before the first retry of a failed connection, doubling
+/// for each retry after that
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether a connection failed because the database couldn't be reached (or
+/// isn't ready yet), which is worth retrying, rather than because it turned us
+/// away, e.g. for a wrong password
+fn is_transient_connect_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<std::io::Error>()
+            || cause
+                .downcast_ref::<tokio_postgres::Error>()
+                .and_then(|error| error.code())
+                .is_some_and(|code| *code == SqlState::CANNOT_CONNECT_NOW)
+    })
+}
+
+/// Prisma's record of the migrations that have been applied, which tells us
+/// which version of the Nephthys schema a database has
+const MIGRATIONS_TABLE: &str = "_prisma_migrations";
+
+/// Columns that the leaderboard query filters or joins on, so it's slow on a
+/// large database unless they're indexed
+const INDEXED_COLUMNS: &[(&str, &str)] = &[("Ticket", "closedAt"), ("Ticket", "closedById")];
+
+/// Whether a column that the leaderboard query looks rows up by has an index
+pub struct IndexStatus {
+    pub table: &'static str,
+    pub column: &'static str,
+    pub indexed: bool,
+}
+
+/// What we could find out about a database's schema, for `crimson db inspect`
+pub struct SchemaInfo {
+    /// The most recently applied Prisma migration, if the database has any
+    pub latest_migration: Option<String>,
+    /// Number of rows in each of the tables that the leaderboard query reads
+    pub row_counts: Vec<(&'static str, i64)>,
+    pub indexes: Vec<IndexStatus>,
+    /// Why the leaderboard query won't work on this database, if it won't
+    pub schema_error: Option<anyhow::Error>,
+}
+
+/// Stands in for `rusqlite::types::FromSql` when there's no SQLite support
+#[cfg(not(feature = "sqlite"))]
+trait SqliteValue {}
+
+#[cfg(not(feature = "sqlite"))]
+impl<T> SqliteValue for T {}
+
+#[cfg(not(feature = "sqlite"))]
+const WITHOUT_SQLITE: &str =
+    "This build of crimson can't read SQLite databases (it was built without the `sqlite` feature)";
+
+/// Whichever database tickets are being read from, picked based on the URL
+pub enum Database {
+    Postgres(PostgresTicketSource),
+    Mysql(MysqlTicketSource),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteTicketSource),
+}
+
+impl Database {
+    /// Connects to a `postgresql://` or `mysql://` URL, or opens a
+    /// `sqlite://path` file. Queries are cancelled if they take longer than
+    /// `query_timeout`.
+    pub async fn connect(
+        db_url: &str,
+        query_timeout: Option<Duration>,
+    ) -> Result<Self, anyhow::Error> {
+        #[cfg(not(feature = "sqlite"))]
+        if db_url.starts_with("sqlite://") {
+            return Err(anyhow::anyhow!(WITHOUT_SQLITE));
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = db_url.strip_prefix("sqlite://") {
+            return Ok(Self::Sqlite(SqliteTicketSource::open(path, query_timeout)?));
+        }
+        if db_url.starts_with("mysql://") {
+            Ok(Self::Mysql(
+                MysqlTicketSource::connect(db_url, query_timeout).await?,
+            ))
+        } else {
+            Ok(Self::Postgres(
+                PostgresTicketSource::connect(db_url, query_timeout).await?,
+            ))
+        }
+    }
+
+    /// Checks that `connect` would understand a URL, without connecting to it
+    pub fn check_url(db_url: &str) -> Result<(), anyhow::Error> {
+        #[cfg(not(feature = "sqlite"))]
+        if db_url.starts_with("sqlite://") {
+            return Err(anyhow::anyhow!(WITHOUT_SQLITE));
+        }
+        if let Some(path) = db_url.strip_prefix("sqlite://") {
+            // Opening a file that doesn't exist would create an empty database
+            if !std::path::Path::new(path).exists() {
+                return Err(anyhow::anyhow!("SQLite database {} doesn't exist", path));
+            }
+        } else if db_url.starts_with("mysql://") {
+            mysql_async::Opts::from_url(db_url).context("DATABASE_URL is not a valid MySQL URL")?;
+        } else {
+            db_url
+                .parse::<tokio_postgres::Config>()
+                .context("DATABASE_URL is not a valid Postgres URL")?;
+        }
+        Ok(())
+    }
+
+    /// Like `connect`, but if the database can't be reached (e.g. during a
+    /// failover), tries again up to `retries` times with exponential backoff
+    pub async fn connect_with_retries(
+        db_url: &str,
+        query_timeout: Option<Duration>,
+        retries: u32,
+    ) -> Result<Self, anyhow::Error> {
+        let mut attempt = 0;
+        loop {
+            match Self::connect(db_url, query_timeout).await {
+                Err(error) if is_transient_connect_error(&error) && attempt < retries => {
+                    tokio::time::sleep(CONNECT_RETRY_BACKOFF * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                Err(error) if attempt > 0 => {
+                    return Err(
+                        error.context(format!("Gave up connecting after {} attempts", attempt + 1))
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Makes sure the tables and columns that the leaderboard query needs with
+    /// `filters` exist, so that a schema mismatch gets a clear error up front
+    pub async fn check_schema(&self, filters: &LeaderboardFilters) -> Result<(), anyhow::Error> {
+        let found = self.columns(&["Ticket", "User", "CategoryTag"]).await?;
+        check_required_columns(&found, filters)
+    }
+
+    /// Looks at the schema version, table sizes and indexes, to help work out
+    /// why the leaderboard query is failing or slow
+    pub async fn inspect(&self) -> Result<SchemaInfo, anyhow::Error> {
+        let tables = ["Ticket", "User"];
+        let schema_error = self
+            .check_schema(&LeaderboardFilters::helpers())
+            .await
+            .err();
+
+        let latest_migration = if self.columns(&[MIGRATIONS_TABLE]).await?.is_empty() {
+            None
+        } else {
+            self.query_value::<String>(&format!(
+                "SELECT migration_name FROM {} WHERE finished_at IS NOT NULL ORDER BY finished_at DESC LIMIT 1",
+                MIGRATIONS_TABLE
+            ))
+            .await
+            .context("Failed to read Prisma migrations")?
+        };
+
+        let found = self.columns(&tables).await?;
+        let mut row_counts = Vec::new();
+        for table in tables {
+            // Counting a table that doesn't exist would just error
+            if !found.iter().any(|(found_table, _)| found_table == table) {
+                continue;
+            }
+            let count = self
+                .query_value::<i64>(&format!("SELECT COUNT(*) FROM {}", self.quote(table)))
+                .await
+                .with_context(|| format!("Failed to count rows in {}", table))?;
+            row_counts.push((table, count.unwrap_or_default()));
+        }
+
+        let indexed = match self {
+            Self::Postgres(source) => source.indexed_columns(&tables).await?,
+            Self::Mysql(source) => source.indexed_columns(&tables).await?,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(source) => source.indexed_columns(&tables).await?,
+        };
+        let indexes = INDEXED_COLUMNS
+            .iter()
+            .map(|&(table, column)| IndexStatus {
+                table,
+                column,
+                indexed: indexed.contains(&(table.to_string(), column.to_string())),
+            })
+            .collect();
+
+        Ok(SchemaInfo {
+            latest_migration,
+            row_counts,
+            indexes,
+            schema_error,
+        })
+    }
+
+    /// Saves a payout run and what each helper was owed into the
+    /// `crimson_payout_runs` and `crimson_payout_items` tables (creating them if
+    /// needed), returning the ID of the run
+    pub async fn record_payout_run(&self, run: &PayoutRun) -> Result<i64, anyhow::Error> {
+        match self {
+            Self::Postgres(source) => source.record_payout_run(run).await,
+            Self::Mysql(source) => source.record_payout_run(run).await,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(source) => source.record_payout_run(run).await,
+        }
+        .context("Failed to record payout run")
+    }
+
+    /// Finds the recorded payout runs whose periods overlap this one, which
+    /// paid helpers in the same way (at a rate, or from a pool)
+    pub async fn overlapping_payout_runs(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        by_rate: bool,
+    ) -> Result<Vec<RecordedRun>, anyhow::Error> {
+        // Nothing's been recorded if the table hasn't been created yet
+        if self.columns(&["crimson_payout_runs"]).await?.is_empty() {
+            return Ok(Vec::new());
+        }
+        let runs = match self {
+            Self::Postgres(source) => source.payout_runs().await,
+            Self::Mysql(source) => source.payout_runs().await,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(source) => source.payout_runs().await,
+        }
+        .context("Failed to read recorded payout runs")?;
+        Ok(runs
+            .into_iter()
+            .filter(|run| {
+                run.period_start < end
+                    && run.period_end > start
+                    && run.cookie_rate.is_some() == by_rate
+            })
+            .collect())
+    }
+
+    async fn columns(&self, tables: &[&str]) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        match self {
+            Self::Postgres(source) => source.columns(tables).await,
+            Self::Mysql(source) => source.columns(tables).await,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(source) => source.columns(tables).await,
+        }
+    }
+
+    async fn query_value<T>(&self, sql: &str) -> Result<Option<T>, anyhow::Error>
+    where
+        T: for<'a> tokio_postgres::types::FromSql<'a>
+            + mysql_async::prelude::FromValue
+            + SqliteValue
+            + Send
+            + 'static,
+    {
+        match self {
+            Self::Postgres(source) => source.query_value(sql).await,
+            Self::Mysql(source) => source.query_value(sql).await,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(source) => source.query_value(sql).await,
+        }
+    }
+
+    /// Quotes a table name, as Nephthys' are case-sensitive
+    fn quote(&self, name: &str) -> String {
+        match self {
+            Self::Postgres(_) => format!("\"{}\"", name),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(_) => format!("\"{}\"", name),
+            Self::Mysql(_) => format!("`{}`", name),
+        }
+    }
+}
+
+impl TicketSource for Database {
+    async fn helper_leaderboard(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error> {
+        match self {
+            Self::Postgres(source) => source.helper_leaderboard(start, end, filters).await,
+            Self::Mysql(source) => source.helper_leaderboard(start, end, filters).await,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(source) => source.helper_leaderboard(start, end, filters).await,
+        }
+    }
+}
+
+/// Several databases treated as one, e.g. separate Nephthys instances for
+/// different programs. Helpers are matched up across them by Slack ID.
+pub struct MergedTicketSource {
+    databases: Vec<Database>,
+}
+
+impl MergedTicketSource {
+    pub fn new(databases: Vec<Database>) -> Self {
+        Self { databases }
+    }
+
+    /// The first database given, which is where anything crimson saves goes
+    pub fn primary(&self) -> &Database {
+        &self.databases[0]
+    }
+}
+
+impl TicketSource for MergedTicketSource {
+    async fn helper_leaderboard(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error> {
+        let leaderboards = future::try_join_all(self.databases.iter().enumerate().map(
+            |(i, database)| async move {
+                database
+                    .helper_leaderboard(start, end, filters)
+                    .await
+                    .with_context(|| format!("Leaderboard query failed on database #{}", i + 1))
+            },
+        ))
+        .await?;
+
+        let mut merged = Leaderboard::default();
+        for leaderboard in leaderboards {
+            merged.extend(
+                leaderboard
+                    .helper_tickets
+                    .into_iter()
+                    .map(|(slack_id, tickets)| (Some(slack_id), tickets)),
+            );
+            merged.unattributed_tickets += leaderboard.unattributed_tickets;
+        }
+        Ok(merged)
+    }
+}
+
+/// Connects to every database given, through an SSH tunnel if asked to. The
+/// tunnels are returned too, and need to be kept around until the databases
+/// are finished with.
+pub(crate) async fn connect_databases(
+    db_args: &DbArgs,
+    query_timeout: Option<Duration>,
+) -> Result<(Vec<Database>, Vec<SshTunnel>), anyhow::Error> {
+    let mut tunnels = Vec::new();
+    let mut db_urls = Vec::new();
+    for db_url in &db_args.db {
+        match &db_args.ssh_tunnel {
+            Some(destination) if !db_url.starts_with("sqlite://") => {
+                let (tunnel, tunnelled_url) = SshTunnel::open(destination, db_url)
+                    .await
+                    .failure(Failure::Database)?;
+                tunnels.push(tunnel);
+                db_urls.push(tunnelled_url);
+            }
+            _ => db_urls.push(db_url.clone()),
+        }
+    }
+    let databases = future::try_join_all(db_urls.iter().map(|db_url| {
+        Database::connect_with_retries(db_url, query_timeout, db_args.connect_retries)
+    }))
+    .await
+    .failure(Failure::Database)?;
+    Ok((databases, tunnels))
+}
+
+/// Makes sure that the leaderboard query will work with `filters` on every
+/// database
+pub(crate) async fn check_schemas(
+    databases: &[Database],
+    filters: &LeaderboardFilters,
+) -> Result<(), anyhow::Error> {
+    future::try_join_all(
+        databases
+            .iter()
+            .enumerate()
+            .map(|(i, database)| async move {
+                database
+                    .check_schema(filters)
+                    .await
+                    .with_context(|| format!("Database #{} can't be used", i + 1))
+            }),
+    )
+    .await
+    .failure(Failure::Database)?;
+    Ok(())
+}
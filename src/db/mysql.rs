@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use futures::TryStreamExt;
+use mysql_async::prelude::Queryable;
+use mysql_async::{Opts, Pool, TxOpts, Value};
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+use super::ledger::{PayoutRun, RecordedRun};
+use super::query::{Dialect, LeaderboardFilters, Param, leaderboard_query};
+use super::{Leaderboard, TicketSource, query_interrupted};
+
+/// Reads tickets out of a Nephthys database hosted on MySQL or MariaDB
+pub struct MysqlTicketSource {
+    pool: Pool,
+    query_timeout: Option<Duration>,
+}
+
+impl MysqlTicketSource {
+    pub async fn connect(
+        db_url: &str,
+        query_timeout: Option<Duration>,
+    ) -> Result<Self, anyhow::Error> {
+        let opts = Opts::from_url(db_url).context("DATABASE_URL is not a valid MySQL URL")?;
+        let pool = Pool::new(opts);
+        // The pool connects lazily, so check that the database is reachable up front
+        let conn = pool
+            .get_conn()
+            .await
+            .context("Failed to connect to Nephthys database")?;
+        drop(conn);
+        Ok(Self {
+            pool,
+            query_timeout,
+        })
+    }
+}
+
+impl MysqlTicketSource {
+    /// Lists the `(table, column)` pairs that exist in the given tables
+    pub async fn columns(
+        &self,
+        tables: &[&str],
+    ) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let mut found = HashSet::new();
+        for table in tables {
+            let columns: Vec<(String, String)> = conn
+                .exec(
+                    r#"
+                    SELECT table_name, column_name
+                    FROM information_schema.columns
+                    WHERE table_schema = DATABASE() AND table_name = ?
+                "#,
+                    (table,),
+                )
+                .await
+                .context("Failed to inspect database schema")?;
+            found.extend(columns);
+        }
+        Ok(found)
+    }
+
+    /// Lists the `(table, column)` pairs that are the first column of an index,
+    /// so can be used to look up rows by that column
+    pub async fn indexed_columns(
+        &self,
+        tables: &[&str],
+    ) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let mut found = HashSet::new();
+        for table in tables {
+            let columns: Vec<(String, String)> = conn
+                .exec(
+                    r#"
+                    SELECT table_name, column_name
+                    FROM information_schema.statistics
+                    WHERE table_schema = DATABASE() AND table_name = ? AND seq_in_index = 1
+                "#,
+                    (table,),
+                )
+                .await
+                .context("Failed to inspect database indexes")?;
+            found.extend(columns);
+        }
+        Ok(found)
+    }
+
+    /// Runs a query that returns a single optional value, like a count
+    pub async fn query_value<T>(&self, sql: &str) -> Result<Option<T>, anyhow::Error>
+    where
+        T: mysql_async::prelude::FromValue + Send + 'static,
+    {
+        let mut conn = self.pool.get_conn().await?;
+        Ok(conn.query_first(sql).await?)
+    }
+
+    pub async fn payout_runs(&self) -> Result<Vec<RecordedRun>, anyhow::Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let runs = conn
+            .query_map(
+                "SELECT id, period_start, period_end, CAST(cookie_rate AS DECIMAL(30, 10)), cookie_pool FROM crimson_payout_runs",
+                |(id, period_start, period_end, cookie_rate, cookie_pool): (
+                    i64,
+                    PrimitiveDateTime,
+                    PrimitiveDateTime,
+                    _,
+                    _,
+                )| RecordedRun {
+                    id,
+                    // Saved in UTC, like the Nephthys timestamps
+                    period_start: period_start.assume_utc(),
+                    period_end: period_end.assume_utc(),
+                    cookie_rate,
+                    cookie_pool,
+                },
+            )
+            .await?;
+        Ok(runs)
+    }
+
+    pub async fn record_payout_run(&self, run: &PayoutRun) -> Result<i64, anyhow::Error> {
+        let mut conn = self.pool.get_conn().await?;
+        conn.query_drop(
+            r#"
+            CREATE TABLE IF NOT EXISTS crimson_payout_runs (
+                id BIGINT AUTO_INCREMENT PRIMARY KEY,
+                created_at DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+                period_start DATETIME(3) NOT NULL,
+                period_end DATETIME(3) NOT NULL,
+                cookie_rate DECIMAL(30, 10),
+                cookie_pool BIGINT
+            )
+        "#,
+        )
+        .await?;
+        conn.query_drop(
+            r#"
+            CREATE TABLE IF NOT EXISTS crimson_payout_items (
+                run_id BIGINT NOT NULL,
+                slack_id VARCHAR(64) NOT NULL,
+                flavortown_user_id BIGINT,
+                tickets_closed BIGINT NOT NULL,
+                cookies DECIMAL(30, 10) NOT NULL,
+                PRIMARY KEY (run_id, slack_id),
+                FOREIGN KEY (run_id) REFERENCES crimson_payout_runs (id)
+            )
+        "#,
+        )
+        .await?;
+        let mut transaction = conn.start_transaction(TxOpts::default()).await?;
+        transaction
+            .exec_drop(
+                r#"
+                INSERT INTO crimson_payout_runs (period_start, period_end, cookie_rate, cookie_pool)
+                VALUES (?, ?, ?, ?)
+            "#,
+                (
+                    to_utc_datetime(run.period_start),
+                    to_utc_datetime(run.period_end),
+                    run.cookie_rate,
+                    run.cookie_pool,
+                ),
+            )
+            .await?;
+        let run_id = transaction
+            .last_insert_id()
+            .context("MySQL didn't return the ID of the new payout run")?
+            as i64;
+        transaction
+            .exec_batch(
+                r#"
+                INSERT INTO crimson_payout_items (run_id, slack_id, flavortown_user_id, tickets_closed, cookies)
+                VALUES (?, ?, ?, ?, ?)
+            "#,
+                run.items.iter().map(|item| {
+                    (
+                        run_id,
+                        &item.slack_id,
+                        item.flavortown_user_id,
+                        item.tickets_closed,
+                        item.cookies,
+                    )
+                }),
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(run_id)
+    }
+}
+
+fn to_utc_datetime(datetime: OffsetDateTime) -> PrimitiveDateTime {
+    let datetime = datetime.to_offset(UtcOffset::UTC);
+    PrimitiveDateTime::new(datetime.date(), datetime.time())
+}
+
+impl TicketSource for MysqlTicketSource {
+    async fn helper_leaderboard(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error> {
+        let query = leaderboard_query(Dialect::Mysql, start, end, filters);
+        let params: Vec<Value> = query
+            .params
+            .into_iter()
+            .map(|param| match param {
+                // `DATETIME` columns don't carry a timezone, and Nephthys stores them in UTC
+                Param::Timestamp(timestamp) => to_utc_datetime(timestamp).into(),
+                Param::Text(text) => text.into(),
+            })
+            .collect();
+        let mut conn = self.pool.get_conn().await?;
+        let connection_id = conn.id();
+        // Stream the rows straight into the leaderboard rather than buffering
+        // them all first
+        let query = async {
+            let rows = conn
+                .exec_stream::<(Option<String>, i64), _, _>(query.sql, params)
+                .await?;
+            rows.try_collect::<Leaderboard>().await
+        };
+        tokio::select! {
+            rows = query => Ok(rows?),
+            reason = query_interrupted(self.query_timeout) => {
+                // Dropping the future doesn't stop the query server-side, so
+                // kill it from another connection (best effort)
+                if let Result::Ok(mut killer) = self.pool.get_conn().await {
+                    killer
+                        .query_drop(format!("KILL QUERY {}", connection_id))
+                        .await
+                        .ok();
+                }
+                Err(reason)
+            }
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use deadpool_postgres::{Client, Manager, ManagerConfig, Pool, RecyclingMethod};
+use futures::TryStreamExt;
+use time::OffsetDateTime;
+use tokio_postgres::NoTls;
+use tokio_postgres::types::ToSql;
+
+use super::ledger::{PayoutRun, RecordedRun};
+use super::query::{Dialect, LeaderboardFilters, Param, Query, leaderboard_query};
+use super::{Leaderboard, TicketSource, query_interrupted};
+
+/// Maximum number of connections to hold open to the database at once
+const MAX_POOL_SIZE: usize = 4;
+
+/// How many tickets each helper closed in each hour (in UTC), counted the same
+/// way as the leaderboard query with `LeaderboardFilters::helpers()`, so that
+/// dashboards can sum up a period without going through every ticket
+const CREATE_LEADERBOARD_VIEW: &str = r#"
+    CREATE MATERIALIZED VIEW crimson_leaderboard AS
+    SELECT u."slackId" AS slack_id,
+        date_trunc('hour', t."closedAt" AT TIME ZONE 'UTC') AS hour,
+        COUNT(DISTINCT t."id") AS tickets_closed
+    FROM "Ticket" t
+    LEFT JOIN "User" u ON u."id" = t."closedById"
+    WHERE t."closedAt" IS NOT NULL AND (u."id" IS NULL OR u."helper" = true)
+    GROUP BY u."slackId", date_trunc('hour', t."closedAt" AT TIME ZONE 'UTC');
+    CREATE UNIQUE INDEX crimson_leaderboard_slack_id_hour
+        ON crimson_leaderboard (slack_id, hour);
+"#;
+
+/// Sums up the `crimson_leaderboard` view between `$1` and `$2`, which gives
+/// the same counts as the leaderboard query as long as they're on the hour
+const LEADERBOARD_VIEW_QUERY: &str = r#"
+    SELECT slack_id, SUM(tickets_closed)::BIGINT AS tickets_closed
+    FROM crimson_leaderboard
+    WHERE hour >= $1::timestamptz AND hour < $2::timestamptz
+    GROUP BY slack_id
+"#;
+
+/// Reads tickets straight out of the Nephthys Postgres database, sharing a
+/// small pool of connections between queries
+pub struct PostgresTicketSource {
+    pool: Pool,
+    /// Replaces the built-in leaderboard query if set
+    custom_leaderboard_query: Option<String>,
+    /// Whether to count tickets from the `crimson_leaderboard` view where it
+    /// can be used
+    reads_leaderboard_view: bool,
+    statement_timeout: Option<Duration>,
+}
+
+impl PostgresTicketSource {
+    pub async fn connect(
+        db_url: &str,
+        statement_timeout: Option<Duration>,
+    ) -> Result<Self, anyhow::Error> {
+        let config = db_url
+            .parse()
+            .context("DATABASE_URL is not a valid Postgres URL")?;
+        let manager = Manager::from_config(
+            config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager).max_size(MAX_POOL_SIZE).build()?;
+        // The pool connects lazily, so check that the database is reachable up front
+        let client = pool
+            .get()
+            .await
+            .context("Failed to connect to Nephthys database")?;
+        drop(client);
+        Ok(Self {
+            pool,
+            custom_leaderboard_query: None,
+            reads_leaderboard_view: false,
+            statement_timeout,
+        })
+    }
+
+    /// Lists the `(table, column)` pairs that exist in the given tables
+    pub async fn columns(
+        &self,
+        tables: &[&str],
+    ) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                r#"
+                SELECT table_name::text, column_name::text
+                FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = ANY($1)
+            "#,
+                &[&tables],
+            )
+            .await
+            .context("Failed to inspect database schema")?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Lists the `(table, column)` pairs that are the first column of an index,
+    /// so can be used to look up rows by that column
+    pub async fn indexed_columns(
+        &self,
+        tables: &[&str],
+    ) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                r#"
+                SELECT t.relname::text, a.attname::text
+                FROM pg_index i
+                JOIN pg_class t ON t.oid = i.indrelid
+                JOIN pg_namespace n ON n.oid = t.relnamespace
+                JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = i.indkey[0]
+                WHERE n.nspname = current_schema() AND t.relname = ANY($1)
+            "#,
+                &[&tables],
+            )
+            .await
+            .context("Failed to inspect database indexes")?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Runs a query that returns a single optional value, like a count
+    pub async fn query_value<T>(&self, sql: &str) -> Result<Option<T>, anyhow::Error>
+    where
+        T: for<'a> tokio_postgres::types::FromSql<'a>,
+    {
+        let client = self.pool.get().await?;
+        let row = client.query_opt(sql, &[]).await?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    /// Creates the `crimson_leaderboard` materialized view if it doesn't exist,
+    /// or refreshes it if it does. Returns whether the view had to be created.
+    pub async fn refresh_leaderboard_view(&self) -> Result<bool, anyhow::Error> {
+        let client = self.pool.get().await?;
+        if self.has_leaderboard_view().await? {
+            // Views from before the hours were bucketed in UTC can't just be
+            // refreshed, as the `hour` column itself has the wrong type
+            let hour_type: Option<String> = client
+                .query_opt(
+                    "SELECT format_type(atttypid, atttypmod) FROM pg_attribute WHERE attrelid = 'crimson_leaderboard'::regclass AND attname = 'hour'",
+                    &[],
+                )
+                .await?
+                .map(|row| row.try_get(0))
+                .transpose()?;
+            if hour_type.as_deref() != Some("timestamp with time zone") {
+                client
+                    .batch_execute(&format!(
+                        "BEGIN; DROP MATERIALIZED VIEW crimson_leaderboard; {CREATE_LEADERBOARD_VIEW} COMMIT;"
+                    ))
+                    .await
+                    .context("Failed to rebuild leaderboard view")?;
+                return Ok(true);
+            }
+            // Concurrently, so that dashboards can keep reading the old data
+            // while it refreshes (which needs the unique index)
+            client
+                .batch_execute("REFRESH MATERIALIZED VIEW CONCURRENTLY crimson_leaderboard")
+                .await
+                .context("Failed to refresh leaderboard view")?;
+            return Ok(false);
+        }
+        client
+            .batch_execute(CREATE_LEADERBOARD_VIEW)
+            .await
+            .context("Failed to create leaderboard view")?;
+        Ok(true)
+    }
+
+    /// Whether `crimson db refresh-view` has created the leaderboard view
+    pub async fn has_leaderboard_view(&self) -> Result<bool, anyhow::Error> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT 1 FROM pg_matviews WHERE schemaname = current_schema() AND matviewname = 'crimson_leaderboard'",
+                &[],
+            )
+            .await?;
+        Ok(row.is_some())
+    }
+
+    pub async fn payout_runs(&self) -> Result<Vec<RecordedRun>, anyhow::Error> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT id, period_start, period_end, cookie_rate::NUMERIC, cookie_pool::BIGINT FROM crimson_payout_runs",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| RecordedRun {
+                id: row.get(0),
+                period_start: row.get(1),
+                period_end: row.get(2),
+                cookie_rate: row.get(3),
+                cookie_pool: row.get(4),
+            })
+            .collect())
+    }
+
+    pub async fn record_payout_run(&self, run: &PayoutRun) -> Result<i64, anyhow::Error> {
+        let mut client = self.pool.get().await?;
+        // Tables from older versions of crimson have a 32-bit pool, and floats
+        // for cookies, so those are cast both ways rather than needing their
+        // columns changed
+        client
+            .batch_execute(
+                r#"
+                CREATE TABLE IF NOT EXISTS crimson_payout_runs (
+                    id BIGSERIAL PRIMARY KEY,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    period_start TIMESTAMPTZ NOT NULL,
+                    period_end TIMESTAMPTZ NOT NULL,
+                    cookie_rate NUMERIC,
+                    cookie_pool BIGINT
+                );
+                CREATE TABLE IF NOT EXISTS crimson_payout_items (
+                    run_id BIGINT NOT NULL REFERENCES crimson_payout_runs (id),
+                    slack_id TEXT NOT NULL,
+                    flavortown_user_id BIGINT,
+                    tickets_closed BIGINT NOT NULL,
+                    cookies NUMERIC NOT NULL,
+                    PRIMARY KEY (run_id, slack_id)
+                );
+            "#,
+            )
+            .await?;
+        let transaction = client.transaction().await?;
+        let run_id: i64 = transaction
+            .query_one(
+                r#"
+                INSERT INTO crimson_payout_runs (period_start, period_end, cookie_rate, cookie_pool)
+                VALUES ($1, $2, $3::NUMERIC, $4::BIGINT)
+                RETURNING id
+            "#,
+                &[
+                    &run.period_start,
+                    &run.period_end,
+                    &run.cookie_rate,
+                    &run.cookie_pool,
+                ],
+            )
+            .await?
+            .get(0);
+        let insert_item = transaction
+            .prepare(
+                r#"
+                INSERT INTO crimson_payout_items (run_id, slack_id, flavortown_user_id, tickets_closed, cookies)
+                VALUES ($1, $2, $3, $4, $5::NUMERIC)
+            "#,
+            )
+            .await?;
+        for item in &run.items {
+            transaction
+                .execute(
+                    &insert_item,
+                    &[
+                        &run_id,
+                        &item.slack_id,
+                        &item.flavortown_user_id,
+                        &item.tickets_closed,
+                        &item.cookies,
+                    ],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(run_id)
+    }
+
+    /// Swaps out the built-in leaderboard query for a custom one. It gets the
+    /// period's start and end as `$1` and `$2`, and must return `slack_id` and
+    /// `tickets_closed` columns.
+    pub fn with_leaderboard_query(mut self, query: String) -> Self {
+        self.custom_leaderboard_query = Some(query);
+        self
+    }
+
+    /// Counts tickets from the `crimson_leaderboard` view rather than the
+    /// `Ticket` table whenever that gives the same answer: for the helper
+    /// leaderboard, over a period that starts and ends on the hour. The view is
+    /// only as up to date as the last `crimson db refresh-view`.
+    pub fn with_leaderboard_view(mut self) -> Self {
+        self.reads_leaderboard_view = true;
+        self
+    }
+
+    fn can_read_leaderboard_view(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> bool {
+        let on_the_hour =
+            |time: OffsetDateTime| time.unix_timestamp_nanos() % 3_600_000_000_000 == 0;
+        self.reads_leaderboard_view
+            && *filters == LeaderboardFilters::helpers()
+            && on_the_hour(start)
+            && on_the_hour(end)
+    }
+}
+
+impl TicketSource for PostgresTicketSource {
+    async fn helper_leaderboard(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error> {
+        let client = self.pool.get().await?;
+        if let Some(statement_timeout) = self.statement_timeout {
+            client
+                .batch_execute(&format!(
+                    "SET statement_timeout = {}",
+                    statement_timeout.as_millis()
+                ))
+                .await?;
+        }
+        // Postgres enforces the timeout itself, so we only need to handle Ctrl-C
+        let cancel_token = client.cancel_token();
+        let result = tokio::select! {
+            result = self.run_leaderboard_query(&client, start, end, filters) => result,
+            reason = query_interrupted(None) => {
+                // Best effort, as we're giving up on the query either way
+                cancel_token.cancel_query(NoTls).await.ok();
+                Err(reason)
+            }
+        };
+        // The connection goes back to the pool afterwards, so the timeout mustn't outlive this query
+        if self.statement_timeout.is_some()
+            && client
+                .batch_execute("RESET statement_timeout")
+                .await
+                .is_err()
+        {
+            // Don't let anyone else use a connection we couldn't clean up
+            drop(Client::take(client));
+        }
+        result
+    }
+}
+
+impl PostgresTicketSource {
+    async fn run_leaderboard_query(
+        &self,
+        client: &Client,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error> {
+        let query = match &self.custom_leaderboard_query {
+            Some(sql) => Query {
+                sql: sql.clone(),
+                params: vec![Param::Timestamp(start), Param::Timestamp(end)],
+            },
+            None if self.can_read_leaderboard_view(start, end, filters) => Query {
+                sql: LEADERBOARD_VIEW_QUERY.to_string(),
+                params: vec![Param::Timestamp(start), Param::Timestamp(end)],
+            },
+            None => leaderboard_query(Dialect::Postgres, start, end, filters),
+        };
+        // Cached per connection, so running the same query for several periods
+        // only plans it once
+        let statement = client
+            .prepare_cached(&query.sql)
+            .await
+            .context("Failed to prepare leaderboard query")?;
+        for column in ["slack_id", "tickets_closed"] {
+            if !statement.columns().iter().any(|c| c.name() == column) {
+                return Err(anyhow::anyhow!(
+                    "Leaderboard query does not return a `{}` column",
+                    column
+                ));
+            }
+        }
+        let params: Vec<&(dyn ToSql + Sync)> = query
+            .params
+            .iter()
+            .map(|param| match param {
+                Param::Timestamp(timestamp) => timestamp as &(dyn ToSql + Sync),
+                Param::Text(text) => text,
+            })
+            .collect();
+        // Custom queries don't have to use every parameter
+        let param_count = statement.params().len();
+        if param_count > params.len() {
+            return Err(anyhow::anyhow!(
+                "Leaderboard query takes {} parameters, but only $1 (start) and $2 (end) are available",
+                param_count
+            ));
+        }
+        // Stream the rows straight into the leaderboard rather than buffering
+        // them all first
+        let rows = client
+            .query_raw(&statement, params[..param_count].iter().copied())
+            .await?;
+
+        // try_get rather than get, as a custom query could return the wrong types
+        let leaderboard: Leaderboard = rows
+            .map_err(anyhow::Error::from)
+            .and_then(|row| async move {
+                let slack_id: Option<&str> = row
+                    .try_get("slack_id")
+                    .context("`slack_id` column should be text")?;
+                let tickets_closed: i64 = row
+                    .try_get("tickets_closed")
+                    .context("`tickets_closed` column should be a bigint")?;
+                Ok((slack_id.map(str::to_string), tickets_closed))
+            })
+            .try_collect()
+            .await?;
+
+        Ok(leaderboard)
+    }
+}
@@ -0,0 +1,249 @@
+use time::OffsetDateTime;
+
+/// A boolean column on the Nephthys `User` table that makes someone eligible
+/// for payouts
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Helper,
+    Admin,
+}
+
+impl Role {
+    fn column(self) -> &'static str {
+        match self {
+            Self::Helper => "helper",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// Narrows down which tickets count towards the leaderboard
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeaderboardFilters {
+    /// Only count tickets closed by users with one of these roles (or by anyone
+    /// if empty)
+    pub roles: Vec<Role>,
+    /// Only count tickets from one of these Slack channels, by ID (or from any
+    /// channel if empty)
+    pub channels: Vec<String>,
+    /// Only count tickets in one of these categories (or any category if empty)
+    pub categories: Vec<String>,
+    /// Don't count tickets in any of these categories
+    pub excluded_categories: Vec<String>,
+    /// Don't count tickets that were reopened after they were closed, as the
+    /// closure was premature
+    pub exclude_reopened: bool,
+}
+
+impl LeaderboardFilters {
+    /// Only counts tickets closed by helpers, as the leaderboards posted to
+    /// Slack do
+    pub fn helpers() -> Self {
+        Self {
+            roles: vec![Role::Helper],
+            ..Default::default()
+        }
+    }
+
+    /// Columns the leaderboard query relies on with these filters, as
+    /// (table, column)
+    pub fn required_columns(&self) -> Vec<(&'static str, &'static str)> {
+        let mut columns = vec![
+            ("Ticket", "id"),
+            ("Ticket", "closedById"),
+            ("Ticket", "closedAt"),
+            ("User", "id"),
+            ("User", "slackId"),
+        ];
+        columns.extend(self.roles.iter().map(|role| ("User", role.column())));
+        if !self.channels.is_empty() {
+            columns.push(("Ticket", "channelId"));
+        }
+        if !self.categories.is_empty() || !self.excluded_categories.is_empty() {
+            columns.push(("Ticket", "categoryTagId"));
+            columns.push(("CategoryTag", "id"));
+            columns.push(("CategoryTag", "name"));
+        }
+        if self.exclude_reopened {
+            columns.push(("Ticket", "reopenedAt"));
+        }
+        columns.dedup();
+        columns
+    }
+}
+
+/// The flavour of SQL to generate, as each database quotes identifiers, writes
+/// placeholders and stores timestamps differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Postgres,
+    Mysql,
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    Sqlite,
+}
+
+/// A value to bind to a placeholder. Each database converts these into its own
+/// parameter types.
+#[derive(Debug, Clone)]
+pub enum Param {
+    Timestamp(OffsetDateTime),
+    Text(String),
+}
+
+/// SQL along with the parameters for its placeholders, in order
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub sql: String,
+    pub params: Vec<Param>,
+}
+
+/// Builds up the parameters for a query while handing out placeholders for them
+struct QueryBuilder {
+    dialect: Dialect,
+    params: Vec<Param>,
+}
+
+impl QueryBuilder {
+    fn new(dialect: Dialect) -> Self {
+        Self {
+            dialect,
+            params: Vec::new(),
+        }
+    }
+
+    /// Quotes a table or column name
+    fn ident(&self, name: &str) -> String {
+        match self.dialect {
+            Dialect::Postgres | Dialect::Sqlite => format!("\"{}\"", name),
+            Dialect::Mysql => format!("`{}`", name),
+        }
+    }
+
+    /// Adds a parameter, returning the placeholder to refer to it with
+    fn bind(&mut self, param: Param) -> String {
+        let is_timestamp = matches!(param, Param::Timestamp(_));
+        self.params.push(param);
+        match self.dialect {
+            Dialect::Postgres if is_timestamp => format!("${}::timestamptz", self.params.len()),
+            Dialect::Postgres => format!("${}", self.params.len()),
+            Dialect::Mysql => "?".to_string(),
+            Dialect::Sqlite => format!("?{}", self.params.len()),
+        }
+    }
+
+    /// Wraps a timestamp column so that it can be compared against a bound
+    /// `Param::Timestamp`. SQLite stores timestamps as text, so we compare them
+    /// as Unix timestamps instead.
+    fn timestamp(&self, column: String) -> String {
+        match self.dialect {
+            Dialect::Sqlite => format!("unixepoch({})", column),
+            Dialect::Postgres | Dialect::Mysql => column,
+        }
+    }
+
+    /// A comma-separated list of placeholders, one for each value
+    fn bind_list(&mut self, values: &[String]) -> String {
+        values
+            .iter()
+            .map(|value| self.bind(Param::Text(value.clone())))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn finish(self, sql: String) -> Query {
+        Query {
+            sql,
+            params: self.params,
+        }
+    }
+}
+
+/// Builds the query that counts tickets closed by each helper between `start`
+/// (inclusive) and `end` (exclusive), returning `slack_id` and `tickets_closed`
+pub fn leaderboard_query(
+    dialect: Dialect,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+    filters: &LeaderboardFilters,
+) -> Query {
+    let mut q = QueryBuilder::new(dialect);
+    let closed_at = q.timestamp(format!("t.{}", q.ident("closedAt")));
+    // A LEFT JOIN so that tickets whose closer is missing still come back (with
+    // a NULL Slack ID), to be reported as unattributed
+    let mut joins = vec![format!(
+        "LEFT JOIN {} u ON u.{} = t.{}",
+        q.ident("User"),
+        q.ident("id"),
+        q.ident("closedById")
+    )];
+    let mut conditions = vec![
+        format!("{} >= {}", closed_at, q.bind(Param::Timestamp(start))),
+        format!("{} < {}", closed_at, q.bind(Param::Timestamp(end))),
+    ];
+
+    if !filters.roles.is_empty() {
+        let roles = filters
+            .roles
+            .iter()
+            .map(|role| format!("u.{} = true", q.ident(role.column())))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        // Nobody knows what role a missing user had, so keep their tickets
+        conditions.push(format!("(u.{} IS NULL OR {})", q.ident("id"), roles));
+    }
+
+    if !filters.channels.is_empty() {
+        let channel = format!("t.{}", q.ident("channelId"));
+        let list = q.bind_list(&filters.channels);
+        conditions.push(format!("{} IN ({})", channel, list));
+    }
+
+    if !filters.categories.is_empty() || !filters.excluded_categories.is_empty() {
+        joins.push(format!(
+            "LEFT JOIN {} c ON c.{} = t.{}",
+            q.ident("CategoryTag"),
+            q.ident("id"),
+            q.ident("categoryTagId")
+        ));
+        let category = format!("c.{}", q.ident("name"));
+        if !filters.categories.is_empty() {
+            let list = q.bind_list(&filters.categories);
+            conditions.push(format!("{} IN ({})", category, list));
+        }
+        if !filters.excluded_categories.is_empty() {
+            let list = q.bind_list(&filters.excluded_categories);
+            conditions.push(format!(
+                "({} IS NULL OR {} NOT IN ({}))",
+                category, category, list
+            ));
+        }
+    }
+
+    if filters.exclude_reopened {
+        // A ticket that was reopened and then closed again will have been
+        // closed after it was reopened, which is fine
+        let reopened_at = q.timestamp(format!("t.{}", q.ident("reopenedAt")));
+        conditions.push(format!(
+            "({} IS NULL OR {} < {})",
+            reopened_at, reopened_at, closed_at
+        ));
+    }
+
+    let slack_id = format!("u.{}", q.ident("slackId"));
+    // Count each ticket once, even if the joins produce more than one row for
+    // it. Only the latest closer is stored on the ticket, so that's who gets
+    // the credit.
+    let sql = format!(
+        "SELECT {slack_id} AS slack_id, COUNT(DISTINCT t.{id}) AS tickets_closed\n\
+         FROM {ticket} t\n\
+         {joins}\n\
+         WHERE {conditions}\n\
+         GROUP BY {slack_id}\n\
+         ORDER BY tickets_closed DESC",
+        id = q.ident("id"),
+        ticket = q.ident("Ticket"),
+        joins = joins.join("\n"),
+        conditions = conditions.join("\n    AND "),
+    );
+    q.finish(sql)
+}
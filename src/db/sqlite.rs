@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension, params_from_iter};
+use rust_decimal::Decimal;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use super::ledger::{PayoutRun, RecordedRun};
+use super::query::{Dialect, LeaderboardFilters, Param, leaderboard_query};
+use super::{Leaderboard, TicketSource, query_interrupted};
+
+/// Reads tickets from a local SQLite copy of the Nephthys schema, for
+/// development and demos without access to the real database
+pub struct SqliteTicketSource {
+    connection: Arc<Mutex<Connection>>,
+    query_timeout: Option<Duration>,
+}
+
+impl SqliteTicketSource {
+    pub fn open(path: &str, query_timeout: Option<Duration>) -> Result<Self, anyhow::Error> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open SQLite database {}", path))?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            query_timeout,
+        })
+    }
+}
+
+impl SqliteTicketSource {
+    /// Lists the `(table, column)` pairs that exist in the given tables
+    pub async fn columns(
+        &self,
+        tables: &[&str],
+    ) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        let connection = self.connection.clone();
+        let tables: Vec<String> = tables.iter().map(|table| table.to_string()).collect();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().expect("SQLite connection lock poisoned");
+            let mut statement = connection.prepare("SELECT name FROM pragma_table_info(?1)")?;
+            let mut found = HashSet::new();
+            for table in tables {
+                let columns = statement.query_map((&table,), |row| row.get::<_, String>(0))?;
+                for column in columns {
+                    found.insert((table.clone(), column?));
+                }
+            }
+            Ok(found)
+        })
+        .await?
+    }
+
+    /// Lists the `(table, column)` pairs that are the first column of an index,
+    /// so can be used to look up rows by that column
+    pub async fn indexed_columns(
+        &self,
+        tables: &[&str],
+    ) -> Result<HashSet<(String, String)>, anyhow::Error> {
+        let connection = self.connection.clone();
+        let tables: Vec<String> = tables.iter().map(|table| table.to_string()).collect();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().expect("SQLite connection lock poisoned");
+            let mut statement = connection.prepare(
+                r#"
+                SELECT ii.name
+                FROM pragma_index_list(?1) il
+                JOIN pragma_index_info(il.name) ii
+                WHERE ii.seqno = 0
+            "#,
+            )?;
+            let mut found = HashSet::new();
+            for table in tables {
+                let columns = statement.query_map((&table,), |row| row.get::<_, String>(0))?;
+                for column in columns {
+                    found.insert((table.clone(), column?));
+                }
+            }
+            Ok(found)
+        })
+        .await?
+    }
+
+    /// Runs a query that returns a single optional value, like a count
+    pub async fn query_value<T>(&self, sql: &str) -> Result<Option<T>, anyhow::Error>
+    where
+        T: rusqlite::types::FromSql + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        let sql = sql.to_string();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().expect("SQLite connection lock poisoned");
+            let value = connection
+                .query_row(&sql, (), |row| row.get(0))
+                .optional()?;
+            Ok(value)
+        })
+        .await?
+    }
+
+    pub async fn payout_runs(&self) -> Result<Vec<RecordedRun>, anyhow::Error> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().expect("SQLite connection lock poisoned");
+            let mut statement = connection.prepare(
+                "SELECT id, period_start, period_end, cookie_rate, cookie_pool FROM crimson_payout_runs",
+            )?;
+            let rows = statement.query_map((), |row| {
+                rusqlite::Result::Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<f64>>(3)?,
+                    row.get::<_, Option<i64>>(4)?,
+                ))
+            })?;
+            let mut runs = Vec::new();
+            for row in rows {
+                let (id, period_start, period_end, cookie_rate, cookie_pool) = row?;
+                runs.push(RecordedRun {
+                    id,
+                    period_start: OffsetDateTime::parse(&period_start, &Rfc3339)?,
+                    period_end: OffsetDateTime::parse(&period_end, &Rfc3339)?,
+                    cookie_rate: cookie_rate.map(Decimal::try_from).transpose()?,
+                    cookie_pool,
+                });
+            }
+            Ok(runs)
+        })
+        .await?
+    }
+
+    pub async fn record_payout_run(&self, run: &PayoutRun) -> Result<i64, anyhow::Error> {
+        let connection = self.connection.clone();
+        let run = run.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().expect("SQLite connection lock poisoned");
+            connection.execute_batch(
+                r#"
+                CREATE TABLE IF NOT EXISTS crimson_payout_runs (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+                    period_start TEXT NOT NULL,
+                    period_end TEXT NOT NULL,
+                    cookie_rate REAL,
+                    cookie_pool INTEGER
+                );
+                CREATE TABLE IF NOT EXISTS crimson_payout_items (
+                    run_id INTEGER NOT NULL REFERENCES crimson_payout_runs (id),
+                    slack_id TEXT NOT NULL,
+                    flavortown_user_id INTEGER,
+                    tickets_closed INTEGER NOT NULL,
+                    cookies REAL NOT NULL,
+                    PRIMARY KEY (run_id, slack_id)
+                );
+            "#,
+            )?;
+            let transaction = connection.transaction()?;
+            // Stored as text, like the Nephthys timestamps. SQLite has no exact
+            // decimal type, so cookies are stored as floats.
+            transaction.execute(
+                r#"
+                INSERT INTO crimson_payout_runs (period_start, period_end, cookie_rate, cookie_pool)
+                VALUES (?1, ?2, ?3, ?4)
+            "#,
+                (
+                    run.period_start.format(&Rfc3339)?,
+                    run.period_end.format(&Rfc3339)?,
+                    run.cookie_rate.map(f64::try_from).transpose()?,
+                    run.cookie_pool,
+                ),
+            )?;
+            let run_id = transaction.last_insert_rowid();
+            {
+                let mut insert_item = transaction.prepare(
+                    r#"
+                    INSERT INTO crimson_payout_items (run_id, slack_id, flavortown_user_id, tickets_closed, cookies)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+                )?;
+                for item in &run.items {
+                    insert_item.execute((
+                        run_id,
+                        &item.slack_id,
+                        item.flavortown_user_id,
+                        item.tickets_closed,
+                        f64::try_from(item.cookies)?,
+                    ))?;
+                }
+            }
+            transaction.commit()?;
+            Ok(run_id)
+        })
+        .await?
+    }
+}
+
+impl TicketSource for SqliteTicketSource {
+    async fn helper_leaderboard(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        filters: &LeaderboardFilters,
+    ) -> Result<Leaderboard, anyhow::Error> {
+        let query = leaderboard_query(Dialect::Sqlite, start, end, filters);
+        let params: Vec<Value> = query
+            .params
+            .into_iter()
+            .map(|param| match param {
+                // Compared against unixepoch() of the stored timestamp
+                Param::Timestamp(timestamp) => Value::Integer(timestamp.unix_timestamp()),
+                Param::Text(text) => Value::Text(text),
+            })
+            .collect();
+        let connection = self.connection.clone();
+        let interrupt_handle = connection
+            .lock()
+            .expect("SQLite connection lock poisoned")
+            .get_interrupt_handle();
+        // rusqlite is blocking, so keep it off the async worker threads
+        let query = tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().expect("SQLite connection lock poisoned");
+            let mut statement = connection.prepare_cached(&query.sql)?;
+            let rows = statement.query_map(params_from_iter(params), |row| {
+                std::result::Result::Ok((row.get("slack_id")?, row.get("tickets_closed")?))
+            })?;
+            let leaderboard: Leaderboard = rows.collect::<Result<_, _>>()?;
+            Ok(leaderboard)
+        });
+        tokio::select! {
+            result = query => result?,
+            reason = query_interrupted(self.query_timeout) => {
+                interrupt_handle.interrupt();
+                Err(reason)
+            }
+        }
+    }
+}
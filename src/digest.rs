@@ -0,0 +1,67 @@
+use std::time::Instant;
+
+use anyhow::{Ok, Result};
+use time::macros::format_description;
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use crate::db::{LeaderboardFilters, TicketSource};
+use crate::metrics::METRICS;
+use crate::natural_date::RelativePeriod;
+use crate::notify::{Message, Span};
+
+/// Counts the tickets closed by helpers over a period (as of now, in
+/// `timezone`) and lists the top `size` of them in a message
+pub async fn leaderboard_digest(
+    ticket_source: &impl TicketSource,
+    relative_period: RelativePeriod,
+    timezone: &Tz,
+    size: usize,
+) -> Result<Message, anyhow::Error> {
+    let period = relative_period.resolve(timezone)?;
+    let filters = LeaderboardFilters::helpers();
+    let query_started = Instant::now();
+    let leaderboard = ticket_source
+        .helper_leaderboard(period.start, period.end, &filters)
+        .await?;
+    METRICS.query_finished(query_started.elapsed());
+
+    let date_format = format_description!("[day padding:none] [month repr:short] [year]");
+    let first_day = period.start.to_timezone(timezone).date();
+    let last_day = (period.end - time::Duration::DAY)
+        .to_timezone(timezone)
+        .date();
+    let dates = if last_day == first_day {
+        first_day.format(&date_format)?
+    } else {
+        format!(
+            "{} to {}",
+            first_day.format(&date_format)?,
+            last_day.format(&date_format)?
+        )
+    };
+    let mut message = Message::default();
+    message.push_line(vec![
+        Span::Bold(format!(
+            "Helper leaderboard for {}",
+            relative_period.words()
+        )),
+        Span::Text(format!(" ({})", dates)),
+    ]);
+    let rankings = leaderboard.rankings();
+    if rankings.is_empty() {
+        message.push_line(vec![Span::Text(
+            "Nobody has closed any tickets then.".to_string(),
+        )]);
+    }
+    for (rank, (slack_id, tickets)) in rankings.iter().take(size).enumerate() {
+        message.push_line(vec![
+            Span::Text(format!("{}. ", rank + 1)),
+            Span::Mention {
+                slack_id: slack_id.to_string(),
+                name: None,
+            },
+            Span::Text(format!(": {} tickets", tickets)),
+        ]);
+    }
+    Ok(message)
+}
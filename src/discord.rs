@@ -0,0 +1,77 @@
+use anyhow::{Context, Ok, Result};
+use reqwest::Url;
+use serde::Serialize;
+
+/// Discord won't accept an embed description longer than this
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+/// Hack Club red
+const EMBED_COLOR: u32 = 0xec3750;
+
+#[derive(Serialize, Debug)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+/// A rich message card, which is how webhook posts get formatted nicely
+#[derive(Serialize, Debug)]
+pub struct Embed {
+    pub title: String,
+    pub description: String,
+    pub fields: Vec<EmbedField>,
+}
+
+#[derive(Serialize, Debug)]
+struct WireEmbed<'a> {
+    title: &'a str,
+    description: String,
+    color: u32,
+    fields: &'a [EmbedField],
+}
+
+#[derive(Serialize, Debug)]
+struct WebhookMessage<'a> {
+    embeds: [WireEmbed<'a>; 1],
+}
+
+/// Posts an embed to a Discord channel through one of its webhooks
+pub async fn post_embed(webhook_url: &Url, embed: &Embed) -> Result<(), anyhow::Error> {
+    let message = WebhookMessage {
+        embeds: [WireEmbed {
+            title: &embed.title,
+            description: truncate_lines(&embed.description, MAX_DESCRIPTION_LENGTH),
+            color: EMBED_COLOR,
+            fields: &embed.fields,
+        }],
+    };
+    reqwest::Client::new()
+        .post(webhook_url.clone())
+        .json(&message)
+        .send()
+        .await
+        .context("Failed to post to Discord webhook")?
+        .error_for_status()
+        .context("Discord webhook rejected the message")?;
+    Ok(())
+}
+
+/// Cuts text down to at most `max_length` characters, dropping whole lines
+/// from the end and saying how many were left out
+fn truncate_lines(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    let lines: Vec<&str> = text.lines().collect();
+    let mut kept = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let omitted = format!("…and {} more", lines.len() - i);
+        if kept.chars().count() + line.chars().count() + omitted.chars().count() + 2 > max_length {
+            return kept + &omitted;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    kept
+}
@@ -0,0 +1,171 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use reqwest::Url;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::failure::Failure;
+use crate::natural_date::Period;
+
+/// Config keys whose values are left out of reports entirely
+const SECRET_KEYS: [&str; 7] = [
+    "api_key",
+    "url",
+    "webhook_url",
+    "discord_webhook",
+    "token",
+    "refresh_token",
+    "password",
+];
+
+/// Reports failed runs to a webhook, with enough context (the command, its
+/// period and the config, without any secrets) to work out what went wrong
+/// without access to wherever crimson was running
+pub struct ErrorReporter {
+    webhook_url: Option<Url>,
+    command: Vec<String>,
+    period: Option<Period>,
+    config: Option<toml::Table>,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorReport<'a> {
+    /// A one-line summary, for webhooks that show a `text` field (like Slack's)
+    text: String,
+    error: String,
+    failure: Failure,
+    command: &'a [String],
+    #[serde(with = "time::serde::rfc3339::option")]
+    period_start: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    period_end: Option<OffsetDateTime>,
+    config: Option<&'a toml::Table>,
+    version: &'static str,
+    #[serde(with = "time::serde::rfc3339")]
+    failed_at: OffsetDateTime,
+}
+
+impl ErrorReporter {
+    /// Reports to `webhook_url`, if there is one, about the command that
+    /// crimson was started with
+    pub fn new(webhook_url: Option<Url>) -> Self {
+        Self {
+            webhook_url,
+            command: std::env::args().map(|arg| redact_arg(&arg)).collect(),
+            period: None,
+            config: None,
+        }
+    }
+
+    /// Includes the config file in reports, and reports to the webhook that it
+    /// gives if there isn't one already
+    pub fn use_config(&mut self, path: &Path, webhook_url: Option<&Url>) {
+        if self.webhook_url.is_none() {
+            self.webhook_url = webhook_url.cloned();
+        }
+        match std::fs::read_to_string(path)
+            .ok()
+            .and_then(|config| toml::from_str::<toml::Table>(&config).ok())
+        {
+            Some(mut config) => {
+                redact_table(&mut config);
+                self.config = Some(config);
+            }
+            None => warn!("Couldn't read the config file to include in error reports"),
+        }
+    }
+
+    /// Includes the period being paid out for in reports
+    pub fn use_period(&mut self, period: Period) {
+        self.period = Some(period);
+    }
+
+    /// Sends a report about `error`, unless there's nowhere to send it or the
+    /// run was stopped on purpose. Failing to report is only a warning, so
+    /// that it doesn't hide the original error.
+    pub async fn report(&self, error: &anyhow::Error) {
+        let Some(webhook_url) = &self.webhook_url else {
+            return;
+        };
+        let failure = Failure::of(error);
+        if failure == Failure::Interrupted {
+            return;
+        }
+        let report = ErrorReport {
+            text: format!(
+                "crimson failed ({}): {}",
+                self.command.get(1..).unwrap_or_default().join(" "),
+                error
+            ),
+            error: format!("{:#}", error),
+            failure,
+            command: &self.command,
+            period_start: self.period.map(|period| period.start),
+            period_end: self.period.map(|period| period.end),
+            config: self.config.as_ref(),
+            version: env!("CARGO_PKG_VERSION"),
+            failed_at: OffsetDateTime::now_utc(),
+        };
+        match post_report(webhook_url, &report).await {
+            Result::Ok(()) => info!("Reported the error to the error webhook"),
+            Err(error) => warn!("Couldn't report the error: {:#}", error),
+        }
+    }
+}
+
+async fn post_report(webhook_url: &Url, report: &ErrorReport<'_>) -> Result<(), anyhow::Error> {
+    reqwest::Client::new()
+        .post(webhook_url.clone())
+        .timeout(Duration::from_secs(10))
+        .json(report)
+        .send()
+        .await
+        .context("Failed to post to the error webhook")?
+        .error_for_status()
+        .context("The error webhook rejected the report")?;
+    Ok(())
+}
+
+/// Cuts URLs in arguments (like --db and --discord-webhook) down to where
+/// they point, as their credentials, paths and queries can hold secrets
+fn redact_arg(arg: &str) -> String {
+    match arg.split_once('=') {
+        Some((flag, value)) if flag.starts_with("--") => format!("{}={}", flag, redact_url(value)),
+        _ => redact_url(arg),
+    }
+}
+
+fn redact_url(text: &str) -> String {
+    match Url::parse(text) {
+        Result::Ok(url) if url.has_host() => {
+            format!(
+                "{}://{}/[redacted]",
+                url.scheme(),
+                url.host_str().unwrap_or_default()
+            )
+        }
+        _ => text.to_string(),
+    }
+}
+
+fn redact_table(table: &mut toml::Table) {
+    for (key, value) in table.iter_mut() {
+        if SECRET_KEYS.contains(&key.as_str()) {
+            *value = toml::Value::String("[redacted]".to_string());
+        } else {
+            redact_value(value);
+        }
+    }
+}
+
+fn redact_value(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(text) => *text = redact_url(text),
+        toml::Value::Table(table) => redact_table(table),
+        toml::Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
@@ -0,0 +1,117 @@
+//! The kinds of failure that crimson tells apart by its exit code, so that
+//! whatever runs it on a schedule can tell what went wrong.
+
+use std::fmt;
+use std::process::ExitCode;
+
+use serde::{Deserialize, Serialize};
+
+use crate::flavortown::FlavortownError;
+
+/// What kind of thing went wrong. Each has its own exit code.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    /// Anything that isn't one of the others (exit code 1)
+    Other,
+    /// Flags or arguments that don't make sense, caught before anything was
+    /// done (exit code 2, like clap's own usage errors)
+    Validation,
+    /// The config file or environment is missing something or is invalid
+    /// (exit code 3)
+    Config,
+    /// A database couldn't be connected to, queried or written to (exit code 4)
+    Database,
+    /// The Flavortown, Slack or Discord API failed (exit code 5)
+    Api,
+    /// The run got through, but not for everyone, like helpers who couldn't be
+    /// looked up or DMs that didn't send (exit code 6)
+    Partial,
+    /// The operator (or the lack of one) said no to carrying on (exit code 7)
+    Aborted,
+    /// Stopped with Ctrl-C (exit code 130, as shells use)
+    Interrupted,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::Other => 1,
+            Self::Validation => 2,
+            Self::Config => 3,
+            Self::Database => 4,
+            Self::Api => 5,
+            Self::Partial => 6,
+            Self::Aborted => 7,
+            Self::Interrupted => 130,
+        })
+    }
+
+    /// Works out what kind of failure an error is, from the kind it was given
+    /// with `FailureContext::failure` or else from the errors that caused it
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(classified) = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Classified>())
+        {
+            return classified.failure;
+        }
+        for cause in error.chain() {
+            if cause.is::<FlavortownError>() || cause.is::<reqwest::Error>() {
+                return Self::Api;
+            }
+            if cause.is::<tokio_postgres::Error>()
+                || cause.is::<deadpool_postgres::PoolError>()
+                || cause.is::<mysql_async::Error>()
+            {
+                return Self::Database;
+            }
+            #[cfg(feature = "sqlite")]
+            if cause.is::<rusqlite::Error>() {
+                return Self::Database;
+            }
+        }
+        Self::Other
+    }
+
+    /// Wraps an error to say what kind of failure it is, without changing how
+    /// it's shown
+    pub fn wrap(self, error: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Classified {
+            failure: self,
+            error,
+        })
+    }
+}
+
+/// Gives errors a `Failure` kind, like `anyhow::Context` gives them context
+pub trait FailureContext<T> {
+    fn failure(self, failure: Failure) -> Result<T, anyhow::Error>;
+}
+
+impl<T, E: Into<anyhow::Error>> FailureContext<T> for Result<T, E> {
+    fn failure(self, failure: Failure) -> Result<T, anyhow::Error> {
+        self.map_err(|error| failure.wrap(error.into()))
+    }
+}
+
+/// An error with its kind of failure attached. It shows up just as the error
+/// it wraps would, causes and all.
+#[derive(Debug)]
+struct Classified {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the top message, as the causes are listed from `source`
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
@@ -0,0 +1,691 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures::{StreamExt, stream};
+use reqwest::{Certificate, StatusCode, Url};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::cli::read_line_interruptible;
+use crate::progress;
+
+/// Maximum number of Flavortown user lookups to have in flight at once, unless
+/// the client is told otherwise
+pub const DEFAULT_MAX_CONCURRENT_LOOKUPS: usize = 8;
+
+/// How many times to retry a request that failed in a way that might be temporary
+const MAX_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry, doubling on each subsequent one
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How many pages of search results to follow before assuming the API is
+/// stuck handing out more
+const MAX_SEARCH_PAGES: usize = 50;
+
+/// Which version of the Flavortown API to talk to
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    /// Works out the API version from the API base URL, if it ends in a
+    /// version segment like `/api/v1`
+    pub fn detect(api_base: &Url) -> Option<Self> {
+        match api_base.path().trim_end_matches('/').rsplit('/').next() {
+            Some("v1") => Some(Self::V1),
+            Some("v2") => Some(Self::V2),
+            _ => None,
+        }
+    }
+}
+
+/// A Flavortown user, in the same shape whichever API version it came from
+#[derive(Debug, Clone)]
+pub struct FlavortownUser {
+    pub id: i64,
+    pub slack_id: String,
+    pub display_name: String,
+    pub avatar: String,
+    pub cookies: Option<i64>,
+}
+
+/// Something that can be bought from the Flavortown shop
+#[derive(Debug, Clone)]
+pub struct ShopItem {
+    pub name: String,
+    /// Price in cookies
+    pub cost: Decimal,
+}
+
+/// One page of user search results, plus whatever needs to be sent to get the
+/// next page (if there is one)
+struct UsersPage {
+    users: Vec<FlavortownUser>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+struct V1User {
+    id: i64,
+    slack_id: String,
+    display_name: String,
+    avatar: String,
+    project_ids: Vec<i64>,
+    cookies: Option<i64>,
+}
+#[derive(Deserialize, Debug)]
+struct V1Pagination {
+    current_page: i64,
+    total_pages: i64,
+}
+#[derive(Deserialize, Debug)]
+struct V1UsersResponse {
+    users: Vec<V1User>,
+    pagination: Option<V1Pagination>,
+}
+
+impl From<V1User> for FlavortownUser {
+    fn from(user: V1User) -> Self {
+        Self {
+            id: user.id,
+            slack_id: user.slack_id,
+            display_name: user.display_name,
+            avatar: user.avatar,
+            cookies: user.cookies,
+        }
+    }
+}
+
+impl From<V1UsersResponse> for UsersPage {
+    fn from(response: V1UsersResponse) -> Self {
+        let next = response
+            .pagination
+            .filter(|pagination| pagination.current_page < pagination.total_pages)
+            .map(|pagination| (pagination.current_page + 1).to_string());
+        Self {
+            users: response.users.into_iter().map(Into::into).collect(),
+            next,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct V1TicketCost {
+    base_cost: Decimal,
+}
+#[derive(Deserialize, Debug)]
+struct V1ShopItem {
+    name: String,
+    ticket_cost: V1TicketCost,
+}
+
+impl From<V1ShopItem> for ShopItem {
+    fn from(item: V1ShopItem) -> Self {
+        Self {
+            name: item.name,
+            cost: item.ticket_cost.base_cost,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct V2UserAttributes {
+    slack_id: String,
+    display_name: String,
+    avatar_url: String,
+    cookie_balance: Option<i64>,
+}
+#[derive(Deserialize, Debug)]
+struct V2User {
+    id: i64,
+    attributes: V2UserAttributes,
+}
+#[derive(Deserialize, Debug)]
+struct V2Meta {
+    next_cursor: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+struct V2UsersResponse {
+    data: Vec<V2User>,
+    meta: V2Meta,
+}
+
+impl From<V2User> for FlavortownUser {
+    fn from(user: V2User) -> Self {
+        Self {
+            id: user.id,
+            slack_id: user.attributes.slack_id,
+            display_name: user.attributes.display_name,
+            avatar: user.attributes.avatar_url,
+            cookies: user.attributes.cookie_balance,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct V2ShopItemAttributes {
+    name: String,
+    price: Decimal,
+}
+#[derive(Deserialize, Debug)]
+struct V2ShopItem {
+    attributes: V2ShopItemAttributes,
+}
+#[derive(Deserialize, Debug)]
+struct V2ShopResponse {
+    data: Vec<V2ShopItem>,
+}
+
+impl From<V2ShopItem> for ShopItem {
+    fn from(item: V2ShopItem) -> Self {
+        Self {
+            name: item.attributes.name,
+            cost: item.attributes.price,
+        }
+    }
+}
+
+impl From<V2UsersResponse> for UsersPage {
+    fn from(response: V2UsersResponse) -> Self {
+        Self {
+            users: response.data.into_iter().map(Into::into).collect(),
+            next: response.meta.next_cursor,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FlavortownError {
+    #[error("Flavortown API rejected our API key ({status}) - {body}")]
+    AuthFailed { status: StatusCode, body: String },
+    #[error("Flavortown API could not find {url}")]
+    NotFound { url: Url },
+    #[error("Flavortown API is rate limiting us{}", retry_after.map(|d| format!(" (retry after {}s)", d.as_secs())).unwrap_or_default())]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("Flavortown API had an internal error: {status} - {body}")]
+    ServerError { status: StatusCode, body: String },
+    #[error("Flavortown API returned error: {status} - {body}")]
+    UnexpectedStatus { status: StatusCode, body: String },
+    #[error("Failed to refresh Flavortown API token: {status} - {body}")]
+    TokenRefreshFailed { status: StatusCode, body: String },
+    #[error("Flavortown API did not respond within {}s (see --http-timeout)", .0.as_secs())]
+    TimedOut(Duration),
+    #[error("Failed to send request to Flavortown API")]
+    Request(#[source] reqwest::Error),
+    #[error("Invalid response from Flavortown API")]
+    InvalidResponse(#[source] reqwest::Error),
+}
+
+impl FlavortownError {
+    /// Whether the request might succeed if we try it again
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::ServerError { .. } | Self::TimedOut(_)
+        )
+    }
+    async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let url = response.url().clone();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs);
+        let body = response.text().await.unwrap_or_default();
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::AuthFailed { status, body },
+            StatusCode::NOT_FOUND => Self::NotFound { url },
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { retry_after },
+            status if status.is_server_error() => Self::ServerError { status, body },
+            status => Self::UnexpectedStatus { status, body },
+        }
+    }
+}
+
+/// Somewhere that helpers can be paid out their cookies
+// `FlavortownClient`'s futures are `Send` all the same
+#[allow(async_fn_in_trait)]
+pub trait RewardBackend {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Searches for the users belonging to each Slack ID, returning every user
+    /// that matched, keyed by Slack ID. Each Slack ID is looked up separately,
+    /// so one failed lookup doesn't stop the rest.
+    async fn find_users_by_slack_ids(
+        &self,
+        slack_ids: impl Iterator<Item = &String>,
+    ) -> HashMap<String, Result<Vec<FlavortownUser>, Self::Error>>;
+
+    /// Lists everything that cookies can be spent on
+    async fn shop_items(&self) -> Result<Vec<ShopItem>, Self::Error>;
+
+    /// Makes sure the next lookups fetch fresh data, e.g. to see updated balances
+    fn forget_cached_users(&self) {}
+}
+
+/// Counts of how much we've talked to the API over the run
+#[derive(Debug, Clone, Default)]
+pub struct ApiMetrics {
+    /// HTTP requests sent, including retries
+    pub calls: usize,
+    /// User searches answered without making a request
+    pub cache_hits: usize,
+    pub retries: usize,
+    latencies: Vec<Duration>,
+}
+
+impl ApiMetrics {
+    /// The latency that `percentile`% of requests finished within
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        let rank = (percentile / 100.0 * latencies.len() as f64).ceil() as usize;
+        latencies.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+/// Settings for the HTTP client used to talk to the API
+pub struct HttpOptions {
+    /// Applies to both establishing a connection and each request as a whole
+    pub timeout: Duration,
+    /// PEM file of extra root certificates to trust, e.g. for a staging
+    /// instance with a self-signed certificate
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// Client for the Flavortown API, sharing one connection pool across requests
+pub struct FlavortownClient {
+    http: reqwest::Client,
+    timeout: Duration,
+    users_url: Url,
+    store_url: Url,
+    api_key: RwLock<String>,
+    token_refresh: Option<Mutex<TokenRefresh>>,
+    api_version: ApiVersion,
+    max_concurrent_lookups: usize,
+    user_cache: std::sync::Mutex<HashMap<String, Vec<FlavortownUser>>>,
+    metrics: std::sync::Mutex<ApiMetrics>,
+}
+
+/// How to get a new access token once the current one expires, following the
+/// OAuth 2.0 refresh token grant
+pub struct TokenRefresh {
+    pub token_url: Url,
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    /// Set if the token endpoint rotates refresh tokens
+    refresh_token: Option<String>,
+}
+
+impl FlavortownClient {
+    /// Creates a client for the API at `api_base` (e.g. `https://flavortown.hackclub.com/api/v1/`)
+    pub fn new(
+        api_base: Url,
+        api_key: String,
+        api_version: ApiVersion,
+        http_options: HttpOptions,
+    ) -> Result<Self, anyhow::Error> {
+        let users_url = api_base
+            .join("users")
+            .context("FLAVORTOWN_API_BASE cannot be used as a base URL")?;
+        let store_url = api_base.join("store")?;
+        // Proxies set with HTTPS_PROXY/HTTP_PROXY/NO_PROXY are picked up by reqwest itself
+        let mut http = reqwest::Client::builder()
+            .connect_timeout(http_options.timeout)
+            .timeout(http_options.timeout);
+        if let Some(ca_cert) = &http_options.ca_cert {
+            let pem = std::fs::read(ca_cert)
+                .with_context(|| format!("Failed to read CA certificate {}", ca_cert.display()))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", ca_cert.display()))?;
+            http = http.tls_certs_merge(certs);
+        }
+        let http = http.build().context("Failed to set up HTTP client")?;
+        Ok(Self {
+            http,
+            timeout: http_options.timeout,
+            users_url,
+            store_url,
+            api_key: RwLock::new(api_key),
+            token_refresh: None,
+            api_version,
+            max_concurrent_lookups: DEFAULT_MAX_CONCURRENT_LOOKUPS,
+            user_cache: Default::default(),
+            metrics: Default::default(),
+        })
+    }
+
+    /// Lets the client swap its API key for a fresh one whenever the API
+    /// rejects it, for deployments that issue short-lived tokens
+    pub fn with_token_refresh(mut self, token_refresh: TokenRefresh) -> Self {
+        self.token_refresh = Some(Mutex::new(token_refresh));
+        self
+    }
+
+    /// Sets how many users `find_users_by_slack_ids` looks up at once (at
+    /// least one)
+    pub fn with_max_concurrent_lookups(mut self, max_concurrent_lookups: usize) -> Self {
+        self.max_concurrent_lookups = max_concurrent_lookups.max(1);
+        self
+    }
+
+    /// Fetches every user matching `query`, following the API's pagination
+    /// until the last page has been read
+    pub async fn search_users(&self, query: &str) -> Result<Vec<FlavortownUser>, FlavortownError> {
+        if let Some(users) = self
+            .user_cache
+            .lock()
+            .expect("cache lock poisoned")
+            .get(query)
+        {
+            self.metrics
+                .lock()
+                .expect("metrics lock poisoned")
+                .cache_hits += 1;
+            return Ok(users.clone());
+        }
+
+        let mut users = Vec::new();
+        let mut next = None;
+        let mut seen = HashSet::new();
+        for pages in 1.. {
+            let page = self.search_users_page(query, next.as_deref()).await?;
+            users.extend(page.users);
+            next = match page.next {
+                None => break,
+                Some(next) if !seen.insert(next.clone()) => {
+                    warn!(
+                        "Flavortown handed out page {next:?} of the search for {query:?} twice, so stopping there"
+                    );
+                    break;
+                }
+                Some(_) if pages == MAX_SEARCH_PAGES => {
+                    warn!(
+                        "Only reading the first {MAX_SEARCH_PAGES} pages of the search for {query:?}"
+                    );
+                    break;
+                }
+                next => next,
+            };
+        }
+
+        self.user_cache
+            .lock()
+            .expect("cache lock poisoned")
+            .insert(query.to_string(), users.clone());
+        Ok(users)
+    }
+
+    pub fn metrics(&self) -> ApiMetrics {
+        self.metrics.lock().expect("metrics lock poisoned").clone()
+    }
+
+    /// Fetches one page of search results. `next` is the page number (v1) or
+    /// cursor (v2) returned alongside the previous page.
+    async fn search_users_page(
+        &self,
+        query: &str,
+        next: Option<&str>,
+    ) -> Result<UsersPage, FlavortownError> {
+        let mut url = self.users_url.clone();
+        url.query_pairs_mut().append_pair("query", query);
+        if let Some(next) = next {
+            let param = match self.api_version {
+                ApiVersion::V1 => "page",
+                ApiVersion::V2 => "cursor",
+            };
+            url.query_pairs_mut().append_pair(param, next);
+        }
+        match self.api_version {
+            ApiVersion::V1 => Ok(self.get_json::<V1UsersResponse>(url).await?.into()),
+            ApiVersion::V2 => Ok(self.get_json::<V2UsersResponse>(url).await?.into()),
+        }
+    }
+
+    /// Fetches and deserializes `url`, retrying with exponential backoff if the
+    /// API is rate limiting us or having a bad time
+    async fn get_json<T: DeserializeOwned>(&self, url: Url) -> Result<T, FlavortownError> {
+        let mut retries = 0;
+        loop {
+            match self.try_get_json(url.clone()).await {
+                Err(error) if error.is_retryable() && retries < MAX_RETRIES => {
+                    let backoff = match error {
+                        FlavortownError::RateLimited {
+                            retry_after: Some(retry_after),
+                        } => retry_after,
+                        _ => RETRY_BACKOFF * 2u32.pow(retries),
+                    };
+                    retries += 1;
+                    self.metrics.lock().expect("metrics lock poisoned").retries += 1;
+                    tokio::time::sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_get_json<T: DeserializeOwned>(&self, url: Url) -> Result<T, FlavortownError> {
+        let api_key = self.api_key();
+        let mut response = self.get(url.clone(), &api_key).await?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.token_refresh.is_some() {
+            self.refresh_api_key(&api_key).await?;
+            response = self.get(url, &self.api_key()).await?;
+        }
+        if !response.status().is_success() {
+            return Err(FlavortownError::from_response(response).await);
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| self.request_error(e, FlavortownError::InvalidResponse))
+    }
+
+    async fn get(&self, url: Url, api_key: &str) -> Result<reqwest::Response, FlavortownError> {
+        let started = Instant::now();
+        let response = self.http.get(url).bearer_auth(api_key).send().await;
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        metrics.calls += 1;
+        metrics.latencies.push(started.elapsed());
+        drop(metrics);
+        response.map_err(|e| self.request_error(e, FlavortownError::Request))
+    }
+
+    fn api_key(&self) -> String {
+        self.api_key.read().expect("API key lock poisoned").clone()
+    }
+
+    /// Fetches a new API key to replace `stale_api_key`, unless another
+    /// request has already done so in the meantime
+    async fn refresh_api_key(&self, stale_api_key: &str) -> Result<(), FlavortownError> {
+        let Some(token_refresh) = &self.token_refresh else {
+            return Ok(());
+        };
+        let mut token_refresh = token_refresh.lock().await;
+        if self.api_key() != stale_api_key {
+            return Ok(());
+        }
+        let response = self
+            .http
+            .post(token_refresh.token_url.clone())
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", &token_refresh.refresh_token),
+            ])
+            .send()
+            .await
+            .map_err(|e| self.request_error(e, FlavortownError::Request))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(FlavortownError::TokenRefreshFailed { status, body });
+        }
+        let tokens: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| self.request_error(e, FlavortownError::InvalidResponse))?;
+        *self.api_key.write().expect("API key lock poisoned") = tokens.access_token;
+        if let Some(refresh_token) = tokens.refresh_token {
+            token_refresh.refresh_token = refresh_token;
+        }
+        Ok(())
+    }
+
+    /// Turns a reqwest error into our error type, calling out timeouts
+    /// specifically so that they don't look like any other network failure
+    fn request_error(
+        &self,
+        error: reqwest::Error,
+        otherwise: impl FnOnce(reqwest::Error) -> FlavortownError,
+    ) -> FlavortownError {
+        if error.is_timeout() {
+            FlavortownError::TimedOut(self.timeout)
+        } else {
+            otherwise(error)
+        }
+    }
+}
+
+impl RewardBackend for FlavortownClient {
+    type Error = FlavortownError;
+
+    async fn find_users_by_slack_ids(
+        &self,
+        slack_ids: impl Iterator<Item = &String>,
+    ) -> HashMap<String, Result<Vec<FlavortownUser>, FlavortownError>> {
+        let slack_ids: Vec<_> = slack_ids.collect();
+        let progress = progress::bar("Resolving users", slack_ids.len());
+        let users = stream::iter(slack_ids)
+            .map(|slack_id| async move { (slack_id.clone(), self.search_users(slack_id).await) })
+            .buffer_unordered(self.max_concurrent_lookups)
+            .inspect(|_| progress.inc(1))
+            .collect()
+            .await;
+        progress.finish_and_clear();
+        users
+    }
+
+    async fn shop_items(&self) -> Result<Vec<ShopItem>, FlavortownError> {
+        let url = self.store_url.clone();
+        match self.api_version {
+            ApiVersion::V1 => {
+                let items: Vec<V1ShopItem> = self.get_json(url).await?;
+                Ok(items.into_iter().map(Into::into).collect())
+            }
+            ApiVersion::V2 => {
+                let response: V2ShopResponse = self.get_json(url).await?;
+                Ok(response.data.into_iter().map(Into::into).collect())
+            }
+        }
+    }
+
+    fn forget_cached_users(&self) {
+        self.user_cache.lock().expect("cache lock poisoned").clear();
+    }
+}
+
+pub(crate) fn log_api_metrics(metrics: &ApiMetrics) {
+    let format_latency = |latency: Option<Duration>| match latency {
+        Some(latency) => format!("{}ms", latency.as_millis()),
+        None => "n/a".to_string(),
+    };
+    debug!(
+        "Flavortown API: {} calls ({} retries, {} cache hits), latency p50 {} / p95 {}",
+        metrics.calls,
+        metrics.retries,
+        metrics.cache_hits,
+        format_latency(metrics.latency_percentile(50.0)),
+        format_latency(metrics.latency_percentile(95.0)),
+    );
+}
+
+/// What came of picking a helper's Flavortown user
+pub(crate) enum Pick {
+    User(FlavortownUser),
+    /// Nobody could be picked for certain, for the reason given
+    Unpicked(String),
+    /// The operator chose to skip them
+    Skipped,
+}
+
+/// Why none of `candidates` were picked for `slack_id` (without asking)
+fn unpicked_reason(slack_id: &str, candidates: &[FlavortownUser]) -> String {
+    match candidates {
+        [] => "no Flavortown account".to_string(),
+        [user] if user.slack_id != slack_id => format!(
+            "no exact match (closest: {}, with Slack ID {})",
+            user.display_name, user.slack_id
+        ),
+        _ => "matches several Flavortown users".to_string(),
+    }
+}
+
+/// Picks the Flavortown user belonging to `slack_id` out of the list of users
+/// that matched the search for it. Only an exact Slack ID match is trusted;
+/// anything else is either resolved by asking the operator (if `interactive`)
+/// or reported as `Pick::Unpicked`.
+pub(crate) async fn pick_flavortown_user(
+    slack_id: &str,
+    mut candidates: Vec<FlavortownUser>,
+    interactive: bool,
+) -> Result<Pick, anyhow::Error> {
+    let exact_matches: Vec<&FlavortownUser> = candidates
+        .iter()
+        .filter(|user| user.slack_id == slack_id)
+        .collect();
+    if let [user] = exact_matches[..] {
+        return Ok(Pick::User(user.clone()));
+    }
+    if !interactive || candidates.is_empty() {
+        return Ok(Pick::Unpicked(unpicked_reason(slack_id, &candidates)));
+    }
+
+    if candidates.len() > 1 {
+        println!("Several Flavortown users could be Slack ID {}:", slack_id);
+    } else {
+        println!(
+            "No Flavortown user has exactly Slack ID {}, but this one is close:",
+            slack_id
+        );
+    }
+    for (i, user) in candidates.iter().enumerate() {
+        println!(
+            "  [{}] {} (ID {}, Slack ID {})",
+            i + 1,
+            user.display_name,
+            user.id,
+            user.slack_id
+        );
+    }
+    println!("  [0] Skip this helper");
+    loop {
+        print!("Choose a user: ");
+        std::io::stdout().flush()?;
+        let Some(choice) = read_line_interruptible().await? else {
+            return Ok(Pick::Skipped);
+        };
+        match choice.trim().parse::<usize>() {
+            Result::Ok(0) => return Ok(Pick::Skipped),
+            Result::Ok(n) if n <= candidates.len() => {
+                return Ok(Pick::User(candidates.swap_remove(n - 1)));
+            }
+            _ => println!("Please enter a number between 0 and {}", candidates.len()),
+        }
+    }
+}
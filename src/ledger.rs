@@ -0,0 +1,168 @@
+//! The local payout ledger: a JSONL file that every payout run and plan
+//! execution is appended to, whether it worked or not, so that there's a
+//! history of what was worked out and given out on this machine.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Ok, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::cli::LedgerArgs;
+use crate::config::{Config, project_dirs};
+use crate::failure::Failure;
+use crate::natural_date::Period;
+
+/// Changes whenever entries written by an older crimson can't be read any more
+const LEDGER_VERSION: u32 = 1;
+
+/// One run, as a line of the ledger
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LedgerEntry {
+    pub version: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub recorded_at: OffsetDateTime,
+    pub crimson_version: String,
+    pub kind: RunKind,
+    /// Set unless the run checked everyone's balance after the payouts were
+    /// given out by hand (with --verify-balances, or by executing a plan)
+    pub dry_run: bool,
+    /// The payout run it was recorded as in the database, with `--record`
+    pub run_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub period_start: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub period_end: OffsetDateTime,
+    /// Set if helpers are paid a fixed number of cookies per ticket
+    pub cookie_rate: Option<Decimal>,
+    /// Set if helpers share out a fixed pool of cookies
+    pub cookie_pool: Option<i64>,
+    /// What each helper was owed, as far as the run got
+    pub helpers: Vec<LedgerPayout>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    /// What the run did, like "Recorded as payout run #3"
+    pub steps: Vec<String>,
+}
+
+impl LedgerEntry {
+    /// An entry for a run that's just finished, with `result`. The rest of it
+    /// can be filled in with `..LedgerEntry::new(...)`.
+    pub fn new(
+        kind: RunKind,
+        dry_run: bool,
+        period: Period,
+        result: &Result<(), anyhow::Error>,
+    ) -> Self {
+        Self {
+            version: LEDGER_VERSION,
+            recorded_at: OffsetDateTime::now_utc(),
+            crimson_version: env!("CARGO_PKG_VERSION").to_string(),
+            kind,
+            dry_run,
+            run_id: None,
+            period_start: period.start,
+            period_end: period.end,
+            cookie_rate: None,
+            cookie_pool: None,
+            helpers: Vec::new(),
+            outcome: Outcome::of(result),
+            steps: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunKind {
+    /// `crimson payout` (or `crimson run`)
+    Payout,
+    /// `crimson payout execute`, which gives nothing out itself: it lists a
+    /// plan's payouts to be given out by hand, then checks that they were
+    Verification,
+}
+
+/// What one helper was owed as part of a `LedgerEntry`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LedgerPayout {
+    pub slack_id: String,
+    /// Unset if they weren't (or hadn't yet been) found on Flavortown
+    pub flavortown_user_id: Option<i64>,
+    pub tickets_closed: i64,
+    /// Written as a string, so that it's exact
+    pub cookies: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    Failed { failure: Failure, error: String },
+}
+
+impl Outcome {
+    pub fn of(result: &Result<(), anyhow::Error>) -> Self {
+        match result {
+            Result::Ok(()) => Self::Succeeded,
+            Err(error) => Self::Failed {
+                failure: Failure::of(error),
+                error: format!("{:#}", error),
+            },
+        }
+    }
+}
+
+/// The ledger file that runs are appended to
+pub struct LedgerFile {
+    path: PathBuf,
+}
+
+impl LedgerFile {
+    /// The ledger given with --ledger or in the config file, or else
+    /// `ledger.jsonl` in crimson's data directory (`ledger-<profile>.jsonl`
+    /// with a profile, so that e.g. staging runs are kept apart). There's none
+    /// with --no-ledger, or if there's no home directory to keep it in.
+    pub fn new(args: &LedgerArgs, config: &Config) -> Option<Self> {
+        if args.no_ledger {
+            return None;
+        }
+        let path = args.ledger.clone().or_else(|| config.ledger.path.clone());
+        let path = path.or_else(|| {
+            let name = match &config.profile {
+                Some(profile) => format!("ledger-{}.jsonl", profile),
+                None => "ledger.jsonl".to_string(),
+            };
+            project_dirs().map(|dirs| dirs.data_dir().join(name))
+        })?;
+        Some(Self { path })
+    }
+
+    /// Adds `entry` to the end of the ledger. Failing to is only a warning:
+    /// the run has already done whatever it did, and running it again just to
+    /// get it into the ledger could pay people twice.
+    pub fn append(&self, entry: &LedgerEntry) {
+        match self.try_append(entry) {
+            Result::Ok(()) => debug!("Added the run to the ledger at {}", self.path.display()),
+            Err(error) => warn!("This run isn't in the ledger: {:#}", error),
+        }
+    }
+
+    fn try_append(&self, entry: &LedgerEntry) -> Result<(), anyhow::Error> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        // Written in one go, so that runs at the same time can't interleave
+        let line = serde_json::to_string(entry)? + "\n";
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write to the ledger at {}", self.path.display()))?;
+        Ok(())
+    }
+}
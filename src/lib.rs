@@ -0,0 +1,45 @@
+//! Works out cookie payouts for the Flavortown support team, from the tickets
+//! that helpers closed in Nephthys.
+//!
+//! The `crimson` binary is a thin wrapper around [`run`]. Other services can
+//! use the pieces directly instead: a [`db::TicketSource`] to count tickets, a
+//! [`flavortown::RewardBackend`] to find the helpers on Flavortown, the
+//! [`payout`] calculations, and the [`report`] builders.
+
+pub mod db;
+pub mod failure;
+pub mod flavortown;
+pub mod natural_date;
+pub mod notify;
+pub mod payout;
+pub mod report;
+
+mod amount;
+mod cli;
+mod cli_schema;
+mod commands;
+mod config;
+mod daemon;
+mod digest;
+#[cfg(feature = "discord")]
+mod discord;
+mod error_report;
+mod ledger;
+mod metrics;
+mod pipeline;
+mod plan;
+mod progress;
+mod schedule;
+mod secrets;
+#[cfg(feature = "self-update")]
+mod self_update;
+#[cfg(feature = "server")]
+mod server;
+mod setup;
+mod slack;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tunnel;
+
+pub use commands::run;
+pub use config::load_env_files;
@@ -0,0 +1,122 @@
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+use anyhow::Context;
+#[cfg(feature = "server")]
+use axum::Router;
+#[cfg(feature = "server")]
+use axum::http::StatusCode;
+#[cfg(feature = "server")]
+use axum::http::header::CONTENT_TYPE;
+#[cfg(feature = "server")]
+use axum::response::{IntoResponse, Response};
+#[cfg(feature = "server")]
+use axum::routing::get;
+use prometheus::{Histogram, HistogramOpts, IntCounterVec, Opts, Registry};
+#[cfg(feature = "server")]
+use tracing::{error, info};
+
+/// What `serve` and `daemon` have been up to, for Prometheus to scrape from
+/// `/metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    registry: Registry,
+    /// Slash commands answered and digests posted, by whether they worked
+    runs: IntCounterVec,
+    /// Failed posts, by the platform they were to
+    api_errors: IntCounterVec,
+    /// How long leaderboard queries took
+    query_seconds: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let runs = IntCounterVec::new(
+            Opts::new("crimson_runs_total", "Slash commands and digests run"),
+            &["kind", "outcome"],
+        )
+        .expect("runs metric is valid");
+        let api_errors = IntCounterVec::new(
+            Opts::new("crimson_api_errors_total", "Failed posts to chat platforms"),
+            &["platform"],
+        )
+        .expect("API errors metric is valid");
+        let query_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "crimson_query_duration_seconds",
+                "How long leaderboard queries took",
+            )
+            .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+        )
+        .expect("query latency metric is valid");
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(runs.clone()))
+            .expect("runs metric is only registered once");
+        registry
+            .register(Box::new(api_errors.clone()))
+            .expect("API errors metric is only registered once");
+        registry
+            .register(Box::new(query_seconds.clone()))
+            .expect("query latency metric is only registered once");
+        Self {
+            registry,
+            runs,
+            api_errors,
+            query_seconds,
+        }
+    }
+
+    /// Counts a slash command (`kind` "slash_command") or digest ("digest")
+    pub fn run_finished(&self, kind: &str, succeeded: bool) {
+        let outcome = if succeeded { "succeeded" } else { "failed" };
+        self.runs.with_label_values(&[kind, outcome]).inc();
+    }
+
+    pub fn api_error(&self, platform: &str) {
+        self.api_errors
+            .with_label_values(&[platform.to_lowercase().as_str()])
+            .inc();
+    }
+
+    pub fn query_finished(&self, duration: Duration) {
+        self.query_seconds.observe(duration.as_secs_f64());
+    }
+
+    /// Every metric, in Prometheus's text format
+    #[cfg(feature = "server")]
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        prometheus::TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+/// Serves the metrics at `/metrics` on `listen`, in the background
+#[cfg(feature = "server")]
+pub async fn serve(listen: SocketAddr) -> Result<(), anyhow::Error> {
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", listen))?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    let app = Router::new().route("/metrics", get(metrics_endpoint));
+    tokio::spawn(async move {
+        if let Err(error) = axum::serve(listener, app).await {
+            error!("Metrics server failed: {}", error);
+        }
+    });
+    Ok(())
+}
+
+/// Serves the metrics, for a `/metrics` route
+#[cfg(feature = "server")]
+pub async fn metrics_endpoint() -> Response {
+    match METRICS.render() {
+        Ok(text) => ([(CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response(),
+        Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response(),
+    }
+}
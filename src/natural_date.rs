@@ -0,0 +1,414 @@
+use anyhow::{Context, Ok, Result};
+use clap::ValueEnum;
+use time::format_description::well_known::Iso8601;
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Weekday};
+use time_tz::{OffsetDateTimeExt, OffsetResult, PrimitiveDateTimeExt, TimeZone, Tz, timezones};
+
+/// Parses a time written relative to `now`: `now`, `today`, `yesterday`,
+/// `tomorrow`, `last monday`, `this friday`, `next tuesday`, or `3 weeks ago`
+/// (in minutes, hours, days, weeks, months or years). Days start at midnight
+/// in `timezone`, and times are to the second. Returns `None` if `text` isn't
+/// anything like that.
+pub fn parse(
+    text: &str,
+    now: OffsetDateTime,
+    timezone: &Tz,
+) -> Result<Option<OffsetDateTime>, anyhow::Error> {
+    let now = now.replace_nanosecond(0).expect("0 is a valid nanosecond");
+    let text = text.trim().to_ascii_lowercase();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let local = now.to_timezone(timezone);
+    let today = local.date();
+    let local_now = PrimitiveDateTime::new(today, local.time());
+
+    let local_time = match words[..] {
+        ["now"] => return Ok(Some(now)),
+        ["today"] => Some(today.midnight()),
+        ["yesterday"] => today.previous_day().map(Date::midnight),
+        ["tomorrow"] => today.next_day().map(Date::midnight),
+        [which @ ("last" | "this" | "next"), day] => {
+            let Some(weekday) = parse_weekday(day) else {
+                return Ok(None);
+            };
+            let date = match which {
+                "last" => today.prev_occurrence(weekday),
+                "next" => today.next_occurrence(weekday),
+                // The day in the current week, which starts on Monday
+                _ => {
+                    today - Duration::days(today.weekday().number_days_from_monday().into())
+                        + Duration::days(weekday.number_days_from_monday().into())
+                }
+            };
+            Some(date.midnight())
+        }
+        [count, unit, "ago"] => {
+            let count: u32 = match count {
+                "a" | "an" => 1,
+                count => match count.parse() {
+                    Result::Ok(count) => count,
+                    Err(_) => return Ok(None),
+                },
+            };
+            let count = i64::from(count);
+            match unit.strip_suffix('s').unwrap_or(unit) {
+                // These are the same everywhere, so don't need the timezone
+                "minute" => return Ok(now.checked_sub(Duration::minutes(count))),
+                "hour" => return Ok(now.checked_sub(Duration::hours(count))),
+                "day" => local_now.checked_sub(Duration::days(count)),
+                "week" => local_now.checked_sub(Duration::weeks(count)),
+                "month" => months_before(today, count).map(|date| date.with_time(local.time())),
+                "year" => months_before(today, count * 12).map(|date| date.with_time(local.time())),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    let local_time = local_time.with_context(|| format!("{:?} is too far away", text))?;
+    let datetime = assume_local_time(local_time, timezone).with_context(|| {
+        format!(
+            "{:?} is {}, which doesn't exist in {} as the clocks go forward then",
+            text,
+            local_time,
+            timezone.name()
+        )
+    })?;
+    Ok(Some(datetime))
+}
+
+fn parse_weekday(name: &str) -> Option<Weekday> {
+    [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ]
+    .into_iter()
+    .find(|weekday| {
+        let full_name = weekday.to_string().to_ascii_lowercase();
+        name == full_name || name == &full_name[..3]
+    })
+}
+
+/// The same day of the month, `months` months earlier (or the last day of
+/// the month, if it's shorter)
+fn months_before(date: Date, months: i64) -> Option<Date> {
+    let index = i64::from(date.year()) * 12 + i64::from(u8::from(date.month()) - 1) - months;
+    let year = i32::try_from(index.div_euclid(12)).ok()?;
+    let month = Month::try_from(u8::try_from(index.rem_euclid(12) + 1).ok()?).ok()?;
+    let day = date.day().min(time::util::days_in_month(month, year));
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// The days from `start` up to (but not including) `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+    pub start: Date,
+    pub end: Date,
+}
+
+impl DateRange {
+    /// Parses an ISO week like `2026-W05`, which starts on a Monday
+    pub fn parse_iso_week(text: &str) -> Result<Self, anyhow::Error> {
+        let invalid = || format!("Invalid week {:?} (expected something like 2026-W05)", text);
+        let (year, week) = text.trim().split_once(['W', 'w']).with_context(invalid)?;
+        let year: i32 = year
+            .strip_suffix('-')
+            .unwrap_or(year)
+            .parse()
+            .with_context(invalid)?;
+        let week: u8 = week.parse().with_context(invalid)?;
+        let start = Date::from_iso_week_date(year, week, Weekday::Monday)
+            .with_context(|| format!("{} doesn't have a week {}", year, week))?;
+        Ok(Self {
+            start,
+            end: start + Duration::WEEK,
+        })
+    }
+
+    /// Parses a month like `2026-02`
+    pub fn parse_month(text: &str) -> Result<Self, anyhow::Error> {
+        let invalid = || format!("Invalid month {:?} (expected something like 2026-02)", text);
+        let (year, month) = text.trim().split_once('-').with_context(invalid)?;
+        let year: i32 = year.parse().with_context(invalid)?;
+        let month: u8 = month.parse().with_context(invalid)?;
+        let month = Month::try_from(month).with_context(invalid)?;
+        let start = Date::from_calendar_date(year, month, 1).with_context(invalid)?;
+        Ok(Self {
+            start,
+            end: start + Duration::days(time::util::days_in_month(month, year).into()),
+        })
+    }
+}
+
+/// A length of time like `7d`, `2w` or `1d12h`, for `--duration`. Weeks and
+/// days are counted on the calendar, so a day that the clocks change on is
+/// still one day, whereas hours and minutes are exact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CalendarDuration {
+    days: i64,
+    exact: Duration,
+}
+
+impl CalendarDuration {
+    pub fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        let mut duration = Self {
+            days: 0,
+            exact: Duration::ZERO,
+        };
+        let mut number = String::new();
+        for c in text.trim().chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let count: u32 = number.parse().with_context(|| {
+                format!("Invalid duration {:?} (expected something like 7d)", text)
+            })?;
+            let count = i64::from(count);
+            match c.to_ascii_lowercase() {
+                'w' => duration.days += count * 7,
+                'd' => duration.days += count,
+                'h' => duration.exact += Duration::hours(count),
+                'm' => duration.exact += Duration::minutes(count),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid duration {:?} (the units are w, d, h and m)",
+                        text
+                    ));
+                }
+            }
+            number.clear();
+        }
+        if !number.is_empty() || (duration.days == 0 && duration.exact.is_zero()) {
+            return Err(anyhow::anyhow!(
+                "Invalid duration {:?} (expected something like 7d)",
+                text
+            ));
+        }
+        Ok(duration)
+    }
+
+    /// When a window of this length starting at `start` ends
+    pub fn after(
+        self,
+        start: OffsetDateTime,
+        timezone: &Tz,
+    ) -> Result<OffsetDateTime, anyhow::Error> {
+        let end = shift_days(start, self.days, timezone)?;
+        end.checked_add(self.exact)
+            .context("The end of the window is too far away")
+    }
+
+    /// When a window of this length ending at `end` starts
+    pub fn before(
+        self,
+        end: OffsetDateTime,
+        timezone: &Tz,
+    ) -> Result<OffsetDateTime, anyhow::Error> {
+        let start = end
+            .checked_sub(self.exact)
+            .context("The start of the window is too far away")?;
+        shift_days(start, -self.days, timezone)
+    }
+}
+
+/// Moves a time by a number of days on the calendar in `timezone`, keeping the
+/// same local time
+fn shift_days(
+    datetime: OffsetDateTime,
+    days: i64,
+    timezone: &Tz,
+) -> Result<OffsetDateTime, anyhow::Error> {
+    let local = datetime.to_timezone(timezone);
+    let shifted = PrimitiveDateTime::new(local.date(), local.time())
+        .checked_add(Duration::days(days))
+        .context("The window is too long")?;
+    assume_local_time(shifted, timezone).with_context(|| {
+        format!(
+            "{} doesn't exist in {}, as the clocks go forward then",
+            shifted,
+            timezone.name()
+        )
+    })
+}
+
+/// The span of time that a payout covers, from `start` (inclusive) to `end`
+/// (exclusive)
+#[derive(Debug, Clone, Copy)]
+pub struct Period {
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+}
+
+impl Period {
+    /// From midnight at the start of the range to midnight at the end, in
+    /// `timezone`
+    pub fn of_dates(dates: DateRange, timezone: &Tz) -> Result<Self> {
+        let local_midnight = |date: Date| {
+            assume_local_time(date.midnight(), timezone).with_context(|| {
+                format!("Midnight on {} doesn't exist in {}", date, timezone.name())
+            })
+        };
+        Ok(Self {
+            start: local_midnight(dates.start)?,
+            end: local_midnight(dates.end)?,
+        })
+    }
+}
+
+/// A period relative to today, like "last week". Weeks start on Monday.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativePeriod {
+    Today,
+    Yesterday,
+    ThisWeek,
+    LastWeek,
+    ThisMonth,
+    LastMonth,
+}
+
+impl RelativePeriod {
+    /// Parses a period written out in words, like "last week"
+    pub fn from_words(text: &str) -> Option<Self> {
+        <Self as ValueEnum>::from_str(&text.trim().replace(' ', "-"), true).ok()
+    }
+
+    /// The period written out in words, like "last week"
+    pub fn words(self) -> String {
+        self.to_possible_value()
+            .expect("no periods are skipped")
+            .get_name()
+            .replace('-', " ")
+    }
+
+    /// The days in the period
+    pub fn dates(self, today: Date) -> DateRange {
+        let week_start =
+            today - time::Duration::days(today.weekday().number_days_from_monday().into());
+        let month_start = today.replace_day(1).expect("every month has a day 1");
+        let next_month_start = month_start
+            + time::Duration::days(
+                time::util::days_in_month(month_start.month(), month_start.year()).into(),
+            );
+        let last_month_start = (month_start - time::Duration::DAY)
+            .replace_day(1)
+            .expect("every month has a day 1");
+        let (start, end) = match self {
+            Self::Today => (today, today + time::Duration::DAY),
+            Self::Yesterday => (today - time::Duration::DAY, today),
+            Self::ThisWeek => (week_start, week_start + time::Duration::WEEK),
+            Self::LastWeek => (week_start - time::Duration::WEEK, week_start),
+            Self::ThisMonth => (month_start, next_month_start),
+            Self::LastMonth => (last_month_start, month_start),
+        };
+        DateRange { start, end }
+    }
+
+    /// Works out when the period starts and ends, as of now in `timezone`
+    pub fn resolve(self, timezone: &Tz) -> Result<Period> {
+        let today = OffsetDateTime::now_utc().to_timezone(timezone).date();
+        Period::of_dates(self.dates(today), timezone)
+    }
+}
+
+/// What `parse_datetime` understands, for when it doesn't
+pub const DATETIME_FORMATS: &str = "Times can be ISO 8601 with a UTC offset (2026-02-01T10:00:00Z), without one if --timezone is set (2026-02-01T10:00:00), plain dates for midnight (2026-02-01), or relative to now (now, yesterday, last monday, 3 weeks ago)";
+
+/// Guesses what a time that couldn't be parsed was meant to be, by fixing the
+/// usual mistakes: a space instead of the `T`, slashes instead of dashes, or a
+/// missing UTC offset
+pub fn suggest_datetime(s: &str, timezone: Option<&Tz>) -> Option<String> {
+    let fixed = s.trim().replace('/', "-").to_uppercase();
+    let fixed = match fixed.split_once(' ') {
+        Some((date, time)) => format!("{}T{}", date, time.replace(' ', "")),
+        None => fixed,
+    };
+    if OffsetDateTime::parse(&fixed, &Iso8601::DEFAULT).is_ok() {
+        return Some(fixed);
+    }
+    // Before dates, which parse the date part of a datetime
+    if PrimitiveDateTime::parse(&fixed, &Iso8601::DEFAULT).is_ok() {
+        return Some(match timezone {
+            Some(_) => fixed,
+            None => format!("{}Z", fixed),
+        });
+    }
+    Date::parse(&fixed, &Iso8601::DEFAULT)
+        .is_ok()
+        .then_some(fixed)
+}
+
+/// Parses an ISO 8601 datetime. If it doesn't have a UTC offset, it's taken to
+/// be local time in `timezone`. A plain date means midnight, in `timezone` or
+/// otherwise UTC. Times relative to now, like "last monday" or "3 weeks ago",
+/// are understood too.
+pub fn parse_datetime(s: &str, timezone: Option<&Tz>) -> Result<OffsetDateTime> {
+    if let Result::Ok(datetime) = OffsetDateTime::parse(s, &Iso8601::DEFAULT) {
+        return Ok(datetime);
+    }
+    if let Some(datetime) = parse(
+        s,
+        OffsetDateTime::now_utc(),
+        timezone.unwrap_or(timezones::db::UTC),
+    )? {
+        return Ok(datetime);
+    }
+    // Dates have to be tried second, as they parse the date part of a datetime
+    let (local, timezone) = match PrimitiveDateTime::parse(s, &Iso8601::DEFAULT) {
+        Result::Ok(local) => {
+            let Some(timezone) = timezone else {
+                return Err(anyhow::anyhow!(
+                    "{:?} needs a UTC offset, like {:?}, unless --timezone is set",
+                    s,
+                    format!("{}Z", s.trim())
+                ));
+            };
+            (local, timezone)
+        }
+        Err(_) => match Date::parse(s, &Iso8601::DEFAULT) {
+            Result::Ok(date) => (date.midnight(), timezone.unwrap_or(timezones::db::UTC)),
+            Err(_) => {
+                let suggestion = match suggest_datetime(s, timezone) {
+                    Some(suggestion) => format!(" (did you mean {:?}?)", suggestion),
+                    None => String::new(),
+                };
+                return Err(anyhow::anyhow!(
+                    "{:?} isn't a time that crimson understands{}. {}",
+                    s,
+                    suggestion,
+                    DATETIME_FORMATS
+                ));
+            }
+        },
+    };
+    assume_local_time(local, timezone).with_context(|| {
+        format!(
+            "{} doesn't exist in {}, as the clocks go forward then",
+            s,
+            timezone.name()
+        )
+    })
+}
+
+/// Works out when a local time in `timezone` happens, or `None` if it's
+/// skipped over when the clocks go forward
+pub fn assume_local_time(local: PrimitiveDateTime, timezone: &Tz) -> Option<OffsetDateTime> {
+    match local.assume_timezone(timezone) {
+        OffsetResult::Some(datetime) => Some(datetime),
+        // When the clocks go back, go with the first time it happens
+        OffsetResult::Ambiguous(earlier, _) => Some(earlier),
+        OffsetResult::None => None,
+    }
+}
+
+pub fn parse_timezone(s: &str) -> Result<&'static Tz> {
+    timezones::get_by_name(s).with_context(|| {
+        format!(
+            "Unknown timezone {:?} (expected something like Europe/London)",
+            s
+        )
+    })
+}
@@ -0,0 +1,348 @@
+use anyhow::{Context, Ok, Result};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::slack::{self, SlackDestination, SlackThread};
+
+/// A piece of a line of a message, which each chat platform formats its own way
+#[derive(Debug, Clone)]
+pub enum Span {
+    Text(String),
+    Bold(String),
+    /// Someone to ping, by Slack ID. On platforms other than Slack, this is
+    /// `@` and their name instead (or their Slack ID, if we don't know it).
+    Mention {
+        slack_id: String,
+        name: Option<String>,
+    },
+}
+
+/// A picture to show alongside a message
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub url: String,
+    pub alt_text: String,
+}
+
+/// An announcement, kept separate from any platform's formatting until it's
+/// sent
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub lines: Vec<Vec<Span>>,
+    pub image: Option<Image>,
+}
+
+/// How a chat platform marks up text
+#[derive(Debug, Clone, Copy)]
+pub enum Markup {
+    /// Slack's mrkdwn
+    Slack,
+    /// Standard Markdown, as used by Mattermost and most webhook consumers
+    Markdown,
+    /// The Markdown used for Slack canvases, which has its own way of
+    /// mentioning people
+    SlackCanvas,
+    /// The subset of HTML that Telegram understands
+    TelegramHtml,
+}
+
+impl Message {
+    pub fn push_line(&mut self, spans: Vec<Span>) {
+        self.lines.push(spans);
+    }
+
+    /// Renders the message as text in the given markup
+    pub fn render(&self, markup: Markup) -> String {
+        let mut text = String::new();
+        for line in &self.lines {
+            for span in line {
+                match (span, markup) {
+                    (Span::Text(s), Markup::TelegramHtml) => text.push_str(&escape_html(s)),
+                    (Span::Text(s), _) => text.push_str(s),
+                    (Span::Bold(s), Markup::TelegramHtml) => {
+                        text.push_str(&format!("<b>{}</b>", escape_html(s)))
+                    }
+                    (Span::Bold(s), Markup::Slack) => text.push_str(&format!("*{}*", s)),
+                    (Span::Bold(s), Markup::Markdown | Markup::SlackCanvas) => {
+                        text.push_str(&format!("**{}**", s))
+                    }
+                    (Span::Mention { slack_id, .. }, Markup::Slack) => {
+                        text.push_str(&format!("<@{}>", slack_id))
+                    }
+                    (Span::Mention { slack_id, name }, Markup::Markdown) => {
+                        text.push_str(&format!("@{}", name.as_ref().unwrap_or(slack_id)))
+                    }
+                    (Span::Mention { slack_id, .. }, Markup::SlackCanvas) => {
+                        text.push_str(&format!("![](@{})", slack_id))
+                    }
+                    (Span::Mention { slack_id, name }, Markup::TelegramHtml) => text.push_str(
+                        &escape_html(&format!("@{}", name.as_ref().unwrap_or(slack_id))),
+                    ),
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// The message as Slack Block Kit blocks: its text as a section, with the
+    /// image (if any) alongside
+    pub fn slack_blocks(&self) -> serde_json::Value {
+        let mut section = json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": self.render(Markup::Slack) },
+        });
+        if let Some(image) = &self.image {
+            section["accessory"] = json!({
+                "type": "image",
+                "image_url": image.url,
+                "alt_text": image.alt_text,
+            });
+        }
+        json!([section])
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Somewhere that announcements can be posted
+// Notifiers are only used as concrete types, whose futures are `Send`
+#[allow(async_fn_in_trait)]
+pub trait Notifier {
+    async fn notify(&self, message: &Message) -> Result<(), anyhow::Error>;
+}
+
+/// Posts to Slack, as a bot or via an incoming webhook
+pub struct SlackNotifier {
+    pub destination: SlackDestination,
+}
+
+impl Notifier for SlackNotifier {
+    async fn notify(&self, message: &Message) -> Result<(), anyhow::Error> {
+        let text = message.render(Markup::Slack);
+        if message.image.is_none() {
+            return slack::post_message(&self.destination, &text).await;
+        }
+        slack::post_blocks(&self.destination, &text, message.slack_blocks()).await
+    }
+}
+
+/// Posts to a Mattermost channel via an incoming webhook
+pub struct MattermostNotifier {
+    pub webhook_url: Url,
+}
+
+impl Notifier for MattermostNotifier {
+    async fn notify(&self, message: &Message) -> Result<(), anyhow::Error> {
+        let text = message.render(Markup::Markdown);
+        let body = match &message.image {
+            // Attachments are the only way to get a picture next to the text
+            Some(image) => json!({
+                "attachments": [{ "fallback": text, "text": text, "thumb_url": image.url }],
+            }),
+            None => json!({ "text": text }),
+        };
+        post_json(&self.webhook_url, &body)
+            .await
+            .context("Failed to post to Mattermost webhook")
+    }
+}
+
+/// POSTs `{"text": ..., "image_url": ...}` (with the text in Markdown) to any
+/// URL, for chat platforms and bots that we don't support directly
+pub struct WebhookNotifier {
+    pub url: Url,
+}
+
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, message: &Message) -> Result<(), anyhow::Error> {
+        let body = json!({
+            "text": message.render(Markup::Markdown),
+            "image_url": message.image.as_ref().map(|image| &image.url),
+        });
+        post_json(&self.url, &body)
+            .await
+            .context("Failed to post to webhook")
+    }
+}
+
+const TELEGRAM_API_BASE: &str = "https://api.telegram.org/";
+
+/// Telegram won't accept a longer caption on a photo, so longer messages are
+/// sent without the picture
+const MAX_TELEGRAM_CAPTION_LENGTH: usize = 1024;
+
+/// Posts to a Telegram chat (or channel) as a bot
+pub struct TelegramNotifier {
+    pub bot_token: String,
+    /// Numeric ID of the chat, or `@username` for a public channel
+    pub chat_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelegramResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+impl Notifier for TelegramNotifier {
+    async fn notify(&self, message: &Message) -> Result<(), anyhow::Error> {
+        let text = message.render(Markup::TelegramHtml);
+        let (method, body) = match &message.image {
+            Some(image) if text.chars().count() <= MAX_TELEGRAM_CAPTION_LENGTH => (
+                "sendPhoto",
+                json!({
+                    "chat_id": self.chat_id,
+                    "photo": image.url,
+                    "caption": text,
+                    "parse_mode": "HTML",
+                }),
+            ),
+            _ => (
+                "sendMessage",
+                json!({ "chat_id": self.chat_id, "text": text, "parse_mode": "HTML" }),
+            ),
+        };
+        // Not `Url::join`, as the colon in the token would make it look like a
+        // URL scheme
+        let url = Url::parse(&format!(
+            "{}bot{}/{}",
+            TELEGRAM_API_BASE, self.bot_token, method
+        ))
+        .context("Invalid Telegram bot token")?;
+        let response: TelegramResponse = reqwest::Client::new()
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            // The URL has the bot token in it, so keep it out of the error
+            .map_err(|error| error.without_url())
+            .context("Failed to post to Telegram")?
+            .json()
+            .await
+            .context("Invalid response from Telegram Bot API")?;
+        if !response.ok {
+            return Err(anyhow::anyhow!(
+                "Telegram Bot API returned error when posting to {}: {}",
+                self.chat_id,
+                response.description.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
+
+async fn post_json(url: &Url, body: &serde_json::Value) -> Result<(), anyhow::Error> {
+    reqwest::Client::new()
+        .post(url.clone())
+        .json(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Whichever notifier the config picked
+pub enum AnyNotifier {
+    Slack(SlackNotifier),
+    Mattermost(MattermostNotifier),
+    Webhook(WebhookNotifier),
+    Telegram(TelegramNotifier),
+}
+
+impl Notifier for AnyNotifier {
+    async fn notify(&self, message: &Message) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Slack(notifier) => notifier.notify(message).await,
+            Self::Mattermost(notifier) => notifier.notify(message).await,
+            Self::Webhook(notifier) => notifier.notify(message).await,
+            Self::Telegram(notifier) => notifier.notify(message).await,
+        }
+    }
+}
+
+impl AnyNotifier {
+    /// Where this posts to, for telling the user
+    pub fn platform(&self) -> &'static str {
+        match self {
+            Self::Slack(_) => "Slack",
+            Self::Mattermost(_) => "Mattermost",
+            Self::Webhook(_) => "the webhook",
+            Self::Telegram(_) => "Telegram",
+        }
+    }
+}
+
+/// A `[notifier]` table in the config file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum NotifierConfig {
+    /// Post to a channel as the SLACK_BOT_TOKEN bot, or via SLACK_WEBHOOK_URL
+    /// if no channel is given. Given the `ts` of a message in the channel
+    /// (e.g. the month's payout announcement), posts replies to it instead,
+    /// which are also sent to the channel with `broadcast`.
+    Slack {
+        channel: Option<String>,
+        thread_ts: Option<String>,
+        #[serde(default)]
+        broadcast: bool,
+    },
+    Mattermost {
+        webhook_url: String,
+    },
+    Webhook {
+        url: String,
+    },
+    /// Post to a chat as the TELEGRAM_BOT_TOKEN bot
+    Telegram {
+        chat_id: String,
+    },
+}
+
+impl Default for NotifierConfig {
+    fn default() -> Self {
+        Self::Slack {
+            channel: None,
+            thread_ts: None,
+            broadcast: false,
+        }
+    }
+}
+
+impl NotifierConfig {
+    pub fn build(&self) -> Result<AnyNotifier, anyhow::Error> {
+        Ok(match self {
+            Self::Slack {
+                channel,
+                thread_ts,
+                broadcast,
+            } => AnyNotifier::Slack(SlackNotifier {
+                destination: SlackDestination::from_env(
+                    channel.as_deref(),
+                    thread_ts.as_ref().map(|ts| SlackThread {
+                        ts: ts.clone(),
+                        broadcast: *broadcast,
+                    }),
+                )?,
+            }),
+            Self::Mattermost { webhook_url } => AnyNotifier::Mattermost(MattermostNotifier {
+                webhook_url: Url::parse(webhook_url)
+                    .context("Mattermost webhook_url is not a valid URL")?,
+            }),
+            Self::Webhook { url } => AnyNotifier::Webhook(WebhookNotifier {
+                url: Url::parse(url).context("Webhook url is not a valid URL")?,
+            }),
+            Self::Telegram { chat_id } => AnyNotifier::Telegram(TelegramNotifier {
+                bot_token: std::env::var("TELEGRAM_BOT_TOKEN").context(
+                    "TELEGRAM_BOT_TOKEN environment variable must be set to post to Telegram",
+                )?,
+                chat_id: chat_id.clone(),
+            }),
+        })
+    }
+}
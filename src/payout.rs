@@ -0,0 +1,1353 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Ok, Result};
+use reqwest::Url;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time_tz::{OffsetDateTimeExt, TimeZone, Tz, timezones};
+use tracing::{Instrument, debug, info, trace_span, warn};
+
+use crate::cli::{
+    FlavortownArgs, PayoutArgs, confirm, confirm_interruptible, read_line_interruptible,
+};
+use crate::config::Config;
+use crate::db::{
+    Database, LeaderboardFilters, MergedTicketSource, PayoutItem, PayoutRun, Role, TicketSource,
+    check_schemas, connect_databases,
+};
+#[cfg(feature = "discord")]
+use crate::discord;
+use crate::error_report::ErrorReporter;
+use crate::failure::{Failure, FailureContext};
+use crate::flavortown::{
+    ApiVersion, FlavortownClient, FlavortownUser, HttpOptions, Pick, RewardBackend, TokenRefresh,
+    log_api_metrics, pick_flavortown_user,
+};
+use crate::ledger::{LedgerEntry, LedgerFile, LedgerPayout, RunKind};
+use crate::natural_date::{Period, parse_datetime};
+use crate::notify::{AnyNotifier, Image, Message, Notifier, NotifierConfig, Span};
+use crate::plan::{PayoutPlan, PlannedPayout};
+#[cfg(feature = "discord")]
+use crate::report::discord_payout_embed;
+use crate::report::{
+    PayoutListFormat, format_helper_cookies, payout_list_message, print_helper_cookies,
+    sort_by_cookies, whole_cookies,
+};
+use crate::secrets::{self, Secret};
+use crate::setup::SetupWizard;
+use crate::slack;
+
+/// What crimson can do in Slack, based on the environment and flags
+pub(crate) struct SlackOptions {
+    /// Used to look up the names of helpers who can't be resolved
+    pub bot_token: Option<String>,
+    /// Where to post announcements about the payout (the payout list and/or a
+    /// celebration of the top helper), if anywhere. This is Slack unless the
+    /// config file says otherwise.
+    pub announce_to: Option<AnyNotifier>,
+}
+
+/// Keeps track of what a payout run has done so far, so that if it's
+/// interrupted it can say what did and didn't happen
+#[derive(Default)]
+pub(crate) struct RunJournal(Mutex<JournalEntries>);
+
+#[derive(Default)]
+struct JournalEntries {
+    /// What each helper is owed, once that's been worked out, as (Slack ID,
+    /// tickets closed, cookies)
+    owed: Vec<(String, i64, Decimal)>,
+    /// The Flavortown user ID of each helper, once they've been looked up
+    resolved: HashMap<String, i64>,
+    /// Set once the full payout list has been printed
+    listed: bool,
+    /// The payout run it was recorded as, with `--record`
+    run_id: Option<i64>,
+    /// Steps that have finished, like "Recorded as payout run #3"
+    done: Vec<String>,
+    /// The step that was under way, like "posting the payout list to Slack"
+    doing: Option<String>,
+    /// Helpers who have been sent a DM
+    dmed: Vec<String>,
+}
+
+impl RunJournal {
+    fn entries(&self) -> MutexGuard<'_, JournalEntries> {
+        self.0.lock().expect("journal lock poisoned")
+    }
+
+    fn owed(
+        &self,
+        helper_cookies: &HashMap<String, Decimal>,
+        helper_tickets: &HashMap<String, i64>,
+    ) {
+        self.entries().owed = sort_by_cookies(helper_cookies, helper_tickets)
+            .into_iter()
+            .map(|(slack_id, cookies)| (slack_id.clone(), helper_tickets[slack_id], cookies))
+            .collect();
+    }
+
+    fn resolved(&self, helper_users: &HashMap<String, FlavortownUser>) {
+        self.entries().resolved = helper_users
+            .iter()
+            .map(|(slack_id, user)| (slack_id.clone(), user.id))
+            .collect();
+    }
+
+    fn listed(&self) {
+        self.entries().listed = true;
+    }
+
+    fn recorded(&self, run_id: i64) {
+        self.entries().run_id = Some(run_id);
+        self.finish(format!("Recorded as payout run #{}", run_id));
+    }
+
+    fn start(&self, step: impl Into<String>) {
+        self.entries().doing = Some(step.into());
+    }
+
+    fn finish(&self, step: impl Into<String>) {
+        let mut entries = self.entries();
+        entries.doing = None;
+        entries.done.push(step.into());
+    }
+
+    fn dmed(&self, slack_id: &str) {
+        self.entries().dmed.push(slack_id.to_string());
+    }
+
+    /// Fills in `entry` with what the run got through, for the ledger
+    fn ledger_entry(&self, entry: LedgerEntry) -> LedgerEntry {
+        let entries = self.entries();
+        LedgerEntry {
+            run_id: entries.run_id,
+            helpers: entries
+                .owed
+                .iter()
+                .map(|(slack_id, tickets_closed, cookies)| LedgerPayout {
+                    slack_id: slack_id.clone(),
+                    flavortown_user_id: entries.resolved.get(slack_id).copied(),
+                    tickets_closed: *tickets_closed,
+                    cookies: *cookies,
+                })
+                .collect(),
+            steps: entries.done.clone(),
+            ..entry
+        }
+    }
+
+    /// Prints what's known about an interrupted run to stdout, clearly marked
+    /// as incomplete, and returns the error to exit with
+    pub fn report_interrupted(&self) -> anyhow::Error {
+        let entries = self.entries();
+        // Gets off the line with the ^C (and any progress bar) on it
+        println!();
+        println!("INCOMPLETE: the payout run was interrupted before it finished");
+        if entries.listed {
+            println!("The payout list above is complete.");
+        } else if entries.owed.is_empty() {
+            println!("It was stopped before the payouts were worked out.");
+        } else {
+            println!("What helpers are owed so far (not checked against Flavortown):");
+            for (slack_id, tickets, cookies) in &entries.owed {
+                println!("- {}: {} cookies ({} tkts)", slack_id, cookies, tickets);
+            }
+        }
+        if entries.done.is_empty() && entries.doing.is_none() && entries.dmed.is_empty() {
+            println!("Nothing was recorded, posted or sent.");
+        }
+        for step in &entries.done {
+            println!("Done: {}", step);
+        }
+        if let Some(step) = &entries.doing {
+            println!("Stopped while {}, so check whether that happened", step);
+        }
+        if !entries.dmed.is_empty() {
+            println!(
+                "DMed {} helper(s): {}",
+                entries.dmed.len(),
+                entries.dmed.join(", ")
+            );
+        }
+        let _ = std::io::stdout().flush();
+        Failure::Interrupted.wrap(anyhow::anyhow!("Interrupted"))
+    }
+}
+
+pub(crate) async fn payout(
+    command_args: &PayoutArgs,
+    config: &Config,
+    wizard: Option<&SetupWizard>,
+    reporter: &mut ErrorReporter,
+) -> Result<(), anyhow::Error> {
+    // Check everything that can be checked before connecting to anything, so
+    // that all of the problems can be reported at once
+    let mut problems = Vec::new();
+    let flavortown = flavortown_client(&command_args.flavortown, config, wizard, &mut problems);
+    // Optional, used to put names to helpers who have no Flavortown account
+    let slack_bot_token = std::env::var("SLACK_BOT_TOKEN").ok();
+    let announce_to = check(
+        &mut problems,
+        if command_args.post_to_slack || command_args.celebrate {
+            let notifier = match &command_args.slack_channel {
+                Some(channel) => NotifierConfig::Slack {
+                    channel: Some(channel.clone()),
+                    thread_ts: command_args.slack_thread_ts.clone(),
+                    broadcast: command_args.slack_broadcast,
+                },
+                None => config.notifier.clone(),
+            };
+            notifier.build().map(Some).failure(Failure::Config)
+        } else {
+            Ok(None)
+        },
+    );
+    if command_args.dm_recipients && !command_args.dm_dry_run && slack_bot_token.is_none() {
+        problems.push(anyhow::anyhow!(
+            "SLACK_BOT_TOKEN environment variable must be set to use --dm-recipients"
+        ));
+    }
+    let period = check(&mut problems, payout_window(command_args));
+    if let Some(period) = period {
+        reporter.use_period(period);
+    }
+    if let Some(period) = period
+        && period.start >= period.end
+    {
+        problems.push(anyhow::anyhow!(
+            "The start of the period ({}) must be before the end ({})",
+            period.start.format(&Rfc3339)?,
+            period.end.format(&Rfc3339)?
+        ));
+    }
+    if let Some(period) = period
+        && period.start > OffsetDateTime::now_utc()
+    {
+        problems.push(anyhow::anyhow!(
+            "The period starts in the future ({}), so there's nothing to pay out for yet",
+            period.start.format(&Rfc3339)?
+        ));
+    }
+    match command_args.payout_specifier.cookie_rate {
+        Some(rate) if rate <= Decimal::ZERO => problems.push(anyhow::anyhow!(
+            "--cookie-rate must be more than 0, not {}",
+            rate
+        )),
+        _ => {}
+    }
+    match command_args.payout_specifier.cookie_pool {
+        Some(pool) if pool <= 0 => problems.push(anyhow::anyhow!(
+            "--cookie-pool must be more than 0, not {}",
+            pool
+        )),
+        _ => {}
+    }
+    for channel in &command_args.channel {
+        if !is_slack_channel_id(channel) {
+            problems.push(anyhow::anyhow!(
+                "--channel {:?} doesn't look like a Slack channel ID (e.g. C0123ABCD)",
+                channel
+            ));
+        }
+    }
+    if command_args
+        .category
+        .iter()
+        .chain(&command_args.exclude_category)
+        .any(|category| category.trim().is_empty())
+    {
+        problems.push(anyhow::anyhow!(
+            "--category and --exclude-category can't be empty"
+        ));
+    }
+    let query = match &command_args.query_file {
+        Some(query_file) => {
+            if !command_args.role.is_empty()
+                || command_args.include_non_helpers
+                || !command_args.channel.is_empty()
+                || !command_args.category.is_empty()
+                || !command_args.exclude_category.is_empty()
+                || command_args.exclude_reopened
+            {
+                problems.push(anyhow::anyhow!(
+                    "Leaderboard filters (--role, --category, etc.) can't be used with --query-file"
+                ));
+            }
+            check(
+                &mut problems,
+                std::fs::read_to_string(query_file)
+                    .with_context(|| format!("Failed to read query file {}", query_file.display()))
+                    .map(Some),
+            )
+        }
+        None => Some(None),
+    };
+    for db_url in &command_args.database.db {
+        check(
+            &mut problems,
+            Database::check_url(db_url).failure(Failure::Config),
+        );
+    }
+    for path in &command_args.also_write {
+        check(&mut problems, PayoutListFormat::for_path(path));
+    }
+    let (Some(flavortown), Some(announce_to), Some(period), Some(query)) =
+        (flavortown, announce_to, period, query)
+    else {
+        return Err(combine_problems(problems));
+    };
+    if !problems.is_empty() {
+        return Err(combine_problems(problems));
+    }
+    if period.end - period.start > MAX_USUAL_PAYOUT_WINDOW && !command_args.force {
+        warn!(
+            "The period is {} long, which is more than the usual {} days",
+            period.end - period.start,
+            MAX_USUAL_PAYOUT_WINDOW.whole_days()
+        );
+        if !confirm("Pay out for all of it?")? {
+            return Err(Failure::Aborted.wrap(anyhow::anyhow!(
+                "Period is longer than {} days (pass --force if that's intended)",
+                MAX_USUAL_PAYOUT_WINDOW.whole_days()
+            )));
+        }
+    }
+    let slack = SlackOptions {
+        bot_token: slack_bot_token,
+        announce_to,
+    };
+
+    let display_timezone = command_args
+        .display_timezone
+        .or(command_args.timezone)
+        .unwrap_or(timezones::db::UTC);
+    info!(
+        event = "run_started",
+        period_start = %period.start.format(&Rfc3339)?,
+        period_end = %period.end.format(&Rfc3339)?,
+        "Selecting leaderboard {}from {} to {}, in {} (Period: {})",
+        match command_args.period {
+            Some(relative_period) => format!("for {}, ", relative_period.words()),
+            None => String::new(),
+        },
+        banner_time(period.start, display_timezone)?,
+        banner_time(period.end, display_timezone)?,
+        display_timezone.name(),
+        period.end - period.start
+    );
+    match (
+        command_args.payout_specifier.cookie_rate,
+        command_args.payout_specifier.cookie_pool,
+    ) {
+        (Some(rate), _) => info!(
+            event = "payout_method",
+            cookie_rate = rate.to_f64(),
+            "Paying out {} cookies per ticket closed",
+            rate
+        ),
+        (None, Some(pool)) => info!(
+            event = "payout_method",
+            cookie_pool = pool,
+            "Sharing a pool of {} cookies between helpers, by the tickets they closed",
+            pool
+        ),
+        (None, None) => {}
+    }
+
+    let query_timeout = match command_args.query_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let journal = RunJournal::default();
+    let result = async {
+        let (mut databases, _tunnels) =
+            connect_databases(&command_args.database, query_timeout).await?;
+        if let Some(query) = query {
+            databases = databases
+                .into_iter()
+                .map(|database| match database {
+                    Database::Postgres(postgres) => Ok(Database::Postgres(
+                        postgres.with_leaderboard_query(query.clone()),
+                    )),
+                    _ => Err(anyhow::anyhow!(
+                        "--query-file is only supported for Postgres databases"
+                    )),
+                })
+                .collect::<Result<_>>()
+                .failure(Failure::Validation)?;
+        } else {
+            check_schemas(&databases, &leaderboard_filters(command_args)).await?;
+        }
+        let ticket_source = MergedTicketSource::new(databases);
+        check_overlapping_runs(ticket_source.primary(), period, command_args).await?;
+
+        // Ctrl-C drops the run, cancelling whatever it was waiting on, and then
+        // says how far it got
+        tokio::select! {
+            result = run_payout(
+                &ticket_source,
+                &flavortown,
+                command_args.record.then(|| ticket_source.primary()),
+                command_args,
+                period,
+                &slack,
+                &journal,
+            )
+            .instrument(trace_span!(
+                "payout",
+                period_start = %period.start.format(&Rfc3339)?,
+                period_end = %period.end.format(&Rfc3339)?,
+            )) => result,
+            Result::Ok(()) = tokio::signal::ctrl_c() => Err(journal.report_interrupted()),
+        }
+    }
+    .await;
+    log_api_metrics(&flavortown.metrics());
+    if let Some(ledger_file) = LedgerFile::new(&command_args.ledger, config) {
+        let entry = LedgerEntry {
+            cookie_rate: command_args.payout_specifier.cookie_rate,
+            cookie_pool: command_args.payout_specifier.cookie_pool,
+            ..LedgerEntry::new(
+                RunKind::Payout,
+                !command_args.verify_balances,
+                period,
+                &result,
+            )
+        };
+        ledger_file.append(&journal.ledger_entry(entry));
+    }
+    result
+}
+
+/// Sets up the client for the Flavortown API from the environment, the config
+/// file and `args`, asking for anything that's missing if there's a `wizard`.
+/// Returns `None` if there were problems, which are added to `problems`.
+pub(crate) fn flavortown_client(
+    args: &FlavortownArgs,
+    config: &Config,
+    wizard: Option<&SetupWizard>,
+    problems: &mut Vec<anyhow::Error>,
+) -> Option<FlavortownClient> {
+    let ask = |what: &str, key: &str, error: String| {
+        match wizard {
+            Some(wizard) => wizard.ask(what, "flavortown", key),
+            None => Err(anyhow::anyhow!(error)),
+        }
+        .failure(Failure::Config)
+    };
+    let flavortown_api = match std::env::var("FLAVORTOWN_API_BASE")
+        .ok()
+        .or_else(|| config.flavortown.api_base.clone())
+    {
+        Some(flavortown_api) => Some(flavortown_api),
+        None => check(
+            problems,
+            ask(
+                "Flavortown API base URL",
+                "api_base",
+                "FLAVORTOWN_API_BASE environment variable not set (and the config file has no [flavortown] api_base)".to_string(),
+            ),
+        ),
+    };
+    let api_key_env = config
+        .flavortown
+        .api_key_env
+        .as_deref()
+        .unwrap_or("FLAVORTOWN_API_KEY");
+    let api_key_file = check(
+        problems,
+        args.api_key_file
+            .as_deref()
+            .map(secrets::read_file)
+            .transpose()
+            .failure(Failure::Config),
+    );
+    // Only looked for elsewhere if --api-key-file wasn't given, or was read
+    let flavortown_api_key = api_key_file.and_then(|api_key_file| {
+        match api_key_file
+            .or_else(|| std::env::var(api_key_env).ok())
+            .or_else(|| config.flavortown.api_key.clone())
+            .or_else(|| {
+                secrets::get(Secret::FlavortownApiKey, config.profile.as_deref()).unwrap_or_else(
+                    |error| {
+                        warn!("{:#}", error);
+                        None
+                    },
+                )
+            }) {
+            Some(flavortown_api_key) => Some(flavortown_api_key),
+            None => check(
+                problems,
+                ask(
+                    "Flavortown API key",
+                    "api_key",
+                    format!(
+                        "{} environment variable not set (or save the key with `crimson secrets set flavortown-api-key`)",
+                        api_key_env
+                    ),
+                ),
+            ),
+        }
+    });
+    let flavortown_api = flavortown_api.and_then(|flavortown_api| {
+        check(
+            problems,
+            Url::parse(&flavortown_api)
+                .context("FLAVORTOWN_API_BASE is not a valid URL")
+                .failure(Failure::Config),
+        )
+    });
+    let token_refresh = check(
+        problems,
+        match std::env::var("FLAVORTOWN_REFRESH_TOKEN") {
+            Result::Ok(refresh_token) => std::env::var("FLAVORTOWN_TOKEN_URL")
+                .context(
+                    "FLAVORTOWN_TOKEN_URL environment variable must be set to use FLAVORTOWN_REFRESH_TOKEN",
+                )
+                .and_then(|token_url| {
+                    Url::parse(&token_url).context("FLAVORTOWN_TOKEN_URL is not a valid URL")
+                })
+                .map(|token_url| {
+                    Some(TokenRefresh {
+                        token_url,
+                        refresh_token,
+                    })
+                }),
+            Err(_) => Ok(None),
+        }
+        .failure(Failure::Config),
+    );
+    if args.concurrency == 0 {
+        problems.push(anyhow::anyhow!("--concurrency must be at least 1"));
+    }
+    let flavortown = flavortown_api.zip(flavortown_api_key).and_then(|(flavortown_api, flavortown_api_key)| {
+        let api_version = match args
+            .api_version
+            .or_else(|| ApiVersion::detect(&flavortown_api))
+        {
+            Some(api_version) => api_version,
+            None => {
+                warn!(
+                    "FLAVORTOWN_API_BASE does not end in `/api/v1` or `/api/v2`. Are you sure you have the full URL?"
+                );
+                ApiVersion::V1
+            }
+        };
+        check(
+            problems,
+            FlavortownClient::new(
+                flavortown_api,
+                flavortown_api_key,
+                api_version,
+                HttpOptions {
+                    timeout: Duration::from_secs(args.http_timeout),
+                    ca_cert: args.ca_cert.clone(),
+                },
+            )
+            .failure(Failure::Config),
+        )
+    });
+    let (Some(mut flavortown), Some(token_refresh)) = (flavortown, token_refresh) else {
+        return None;
+    };
+    if let Some(token_refresh) = token_refresh {
+        flavortown = flavortown.with_token_refresh(token_refresh);
+    }
+    Some(flavortown.with_max_concurrent_lookups(args.concurrency))
+}
+
+/// Stops helpers from being paid twice for the same tickets, by refusing to pay
+/// out for a period that overlaps one that's already been recorded (unless
+/// --allow-overlap is given)
+pub(crate) async fn check_overlapping_runs(
+    ledger: &Database,
+    period: Period,
+    command_args: &PayoutArgs,
+) -> Result<(), anyhow::Error> {
+    let overlapping = ledger
+        .overlapping_payout_runs(
+            period.start,
+            period.end,
+            command_args.payout_specifier.cookie_rate.is_some(),
+        )
+        .await
+        .failure(Failure::Database)?;
+    for run in &overlapping {
+        warn!(
+            "This period overlaps payout run #{}, from {} to {} ({})",
+            run.id,
+            run.period_start.format(&Rfc3339)?,
+            run.period_end.format(&Rfc3339)?,
+            match (run.cookie_rate, run.cookie_pool) {
+                (Some(rate), _) => format!("{} cookies per ticket", rate),
+                (None, Some(pool)) => format!("a pool of {} cookies", pool),
+                (None, None) => "unknown payout".to_string(),
+            }
+        );
+    }
+    if !overlapping.is_empty() && !command_args.allow_overlap {
+        return Err(Failure::Validation.wrap(anyhow::anyhow!(
+            "Some of this period has already been paid out for, so helpers could be paid twice (pass --allow-overlap if that's intended)"
+        )));
+    }
+    Ok(())
+}
+
+/// Payouts are weekly or monthly, so a window longer than about a quarter is
+/// probably a typo in --start or --end
+pub(crate) const MAX_USUAL_PAYOUT_WINDOW: time::Duration = time::Duration::days(92);
+
+/// Formats a time for the period banner, with its UTC offset so that it's
+/// clear when it is locally
+pub(crate) fn banner_time(datetime: OffsetDateTime, timezone: &Tz) -> Result<String> {
+    Ok(datetime.to_timezone(timezone).format(format_description!(
+        "[weekday] [day padding:none] [month repr:short] [year] (@ [hour]:[minute] UTC[offset_hour sign:mandatory]:[offset_minute])"
+    ))?)
+}
+
+/// Works out the period to pay out for from --start, --end, --duration and
+/// --period
+pub(crate) fn payout_window(command_args: &PayoutArgs) -> Result<Period, anyhow::Error> {
+    let timezone = command_args.timezone.unwrap_or(timezones::db::UTC);
+    if let Some(relative_period) = command_args.period {
+        return relative_period.resolve(timezone);
+    }
+    if let Some(dates) = command_args.week.or(command_args.month) {
+        return Period::of_dates(dates, timezone);
+    }
+    let parse = |flag: &str, s: &str| {
+        parse_datetime(s, command_args.timezone).with_context(|| format!("Invalid {}", flag))
+    };
+    let (start, end) = match (
+        &command_args.start,
+        &command_args.end,
+        command_args.duration,
+    ) {
+        (Some(_), Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "--duration can't be used with both --start and --end"
+            ));
+        }
+        (Some(start), None, Some(duration)) => {
+            let start = parse("--start", start)?;
+            (start, duration.after(start, timezone)?)
+        }
+        (start, end, duration) => {
+            let end = match end {
+                Some(end) => parse("--end", end)?,
+                None => {
+                    let now = OffsetDateTime::now_utc()
+                        .replace_nanosecond(0)
+                        .expect("0 is a valid nanosecond");
+                    info!(
+                        "No --end given, so counting up to now ({})",
+                        now.format(&Rfc3339)?
+                    );
+                    now
+                }
+            };
+            let start = match (start, duration) {
+                (Some(start), _) => parse("--start", start)?,
+                (None, Some(duration)) => duration.before(end, timezone)?,
+                (None, None) => unreachable!("clap requires --start or --duration"),
+            };
+            (start, end)
+        }
+    };
+    Ok(Period { start, end })
+}
+
+/// Hands back the result's value, or notes down its error so that checking
+/// can carry on
+pub(crate) fn check<T>(problems: &mut Vec<anyhow::Error>, result: Result<T>) -> Option<T> {
+    result.map_err(|error| problems.push(error)).ok()
+}
+
+/// Rolls the problems found by `check` up into one error. It's a validation
+/// failure, unless one of the problems is a more specific kind of failure.
+pub(crate) fn combine_problems(mut problems: Vec<anyhow::Error>) -> anyhow::Error {
+    let failure = problems
+        .iter()
+        .map(Failure::of)
+        .find(|failure| *failure != Failure::Other)
+        .unwrap_or(Failure::Validation);
+    if problems.len() == 1 {
+        return failure.wrap(problems.remove(0));
+    }
+    let list: Vec<String> = problems
+        .iter()
+        .map(|problem| format!("- {:#}", problem))
+        .collect();
+    failure.wrap(anyhow::anyhow!(
+        "{} problems:\n{}",
+        problems.len(),
+        list.join("\n")
+    ))
+}
+
+/// Whether something looks like the ID of a Slack channel, e.g. C0123ABCD
+pub(crate) fn is_slack_channel_id(id: &str) -> bool {
+    id.len() >= 9
+        && id.starts_with(['C', 'G'])
+        && id
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+/// Which tickets count towards the payouts, going by --role, --channel, etc.
+fn leaderboard_filters(command_args: &PayoutArgs) -> LeaderboardFilters {
+    let roles = if command_args.include_non_helpers {
+        Vec::new()
+    } else if command_args.role.is_empty() {
+        vec![Role::Helper]
+    } else {
+        command_args.role.clone()
+    };
+    LeaderboardFilters {
+        roles,
+        channels: command_args.channel.clone(),
+        categories: command_args.category.clone(),
+        excluded_categories: command_args.exclude_category.clone(),
+        exclude_reopened: command_args.exclude_reopened,
+    }
+}
+
+/// Calculates and prints the payouts for helpers over the given period
+pub(crate) async fn run_payout(
+    ticket_source: &impl TicketSource,
+    reward_backend: &impl RewardBackend,
+    ledger: Option<&Database>,
+    command_args: &PayoutArgs,
+    period: Period,
+    slack: &SlackOptions,
+    journal: &RunJournal,
+) -> Result<(), anyhow::Error> {
+    let filters = leaderboard_filters(command_args);
+    // The shop doesn't depend on the leaderboard, so fetch it while the query runs
+    let query_started = Instant::now();
+    let (leaderboard, shop_items) = tokio::try_join!(
+        async {
+            let leaderboard = ticket_source
+                .helper_leaderboard(period.start, period.end, &filters)
+                .instrument(trace_span!("query_leaderboard"))
+                .await
+                .failure(Failure::Database)?;
+            Ok(leaderboard)
+        },
+        async {
+            if !command_args.shop_context {
+                return Ok(Vec::new());
+            }
+            let shop_items = reward_backend.shop_items().await?;
+            Ok(shop_items)
+        },
+    )?;
+    info!(
+        event = "query_completed",
+        helpers = leaderboard.helper_tickets.len(),
+        unattributed_tickets = leaderboard.unattributed_tickets,
+        duration_ms = query_started.elapsed().as_millis() as u64,
+        "Counted the tickets of {} helper(s) in {}ms",
+        leaderboard.helper_tickets.len(),
+        query_started.elapsed().as_millis()
+    );
+    if leaderboard.unattributed_tickets > 0 {
+        warn!(
+            "{} ticket(s) were closed by someone who can't be found (deleted, unrecorded, or without a Slack ID), so they aren't counted",
+            leaderboard.unattributed_tickets
+        );
+    }
+    let helper_tickets = leaderboard.helper_tickets;
+
+    let helper_cookies = if let Some(payout_rate) = &command_args.payout_specifier.cookie_rate {
+        do_static_rate_payouts(&helper_tickets, payout_rate)?
+    } else if let Some(pool) = &command_args.payout_specifier.cookie_pool {
+        do_pool_payouts(&helper_tickets, pool)?
+    } else {
+        unreachable!("One of cookie_rate or cookie_pool should be set")
+    };
+    journal.owed(&helper_cookies, &helper_tickets);
+
+    let helper_matches = reward_backend
+        .find_users_by_slack_ids(helper_cookies.keys())
+        .instrument(trace_span!("resolve_users", helpers = helper_cookies.len()))
+        .await;
+
+    // Work out who everyone is before printing anything, so that problems
+    // don't surface halfway through the report
+    let mut helper_users = HashMap::new();
+    let mut unresolved_helpers = Vec::new();
+    let mut lookup_failures = Vec::new();
+    for (slack_id, candidates) in helper_matches {
+        let candidates = match candidates {
+            Result::Ok(candidates) => candidates,
+            Err(error) => {
+                debug!(
+                    event = "lookup_failed",
+                    slack_id,
+                    error = %error,
+                    "Couldn't look up {} on Flavortown: {}",
+                    slack_id,
+                    error
+                );
+                lookup_failures.push((slack_id, anyhow::Error::from(error)));
+                continue;
+            }
+        };
+        match pick_flavortown_user(&slack_id, candidates, command_args.interactive).await? {
+            Pick::User(user) => {
+                debug!(
+                    event = "user_resolved",
+                    slack_id,
+                    flavortown_user_id = user.id,
+                    "{} is Flavortown user {} ({})",
+                    slack_id,
+                    user.id,
+                    user.display_name
+                );
+                helper_users.insert(slack_id, user);
+            }
+            Pick::Unpicked(reason) => unresolved_helpers.push((slack_id, reason)),
+            Pick::Skipped => unresolved_helpers.push((slack_id, "skipped when asked".to_string())),
+        }
+    }
+    journal.resolved(&helper_users);
+    unresolved_helpers.sort();
+    lookup_failures.sort_by(|(id_a, _), (id_b, _)| id_a.cmp(id_b));
+    for (slack_id, reason) in &unresolved_helpers {
+        debug!(
+            event = "user_unresolved",
+            slack_id, reason, "{} can't be paid: {}", slack_id, reason
+        );
+    }
+
+    if !unresolved_helpers.is_empty() {
+        let slack_names = match &slack.bot_token {
+            Some(token) => {
+                slack::get_display_names(
+                    token,
+                    unresolved_helpers.iter().map(|(slack_id, _)| slack_id),
+                )
+                .await?
+            }
+            None => HashMap::new(),
+        };
+        println!(
+            "Could not resolve {} helper(s) to a Flavortown user:",
+            unresolved_helpers.len()
+        );
+        for (slack_id, reason) in &unresolved_helpers {
+            println!(
+                "- {} ({}): {}, owed {} cookies",
+                slack_names.get(slack_id).unwrap_or(slack_id),
+                slack_id,
+                reason,
+                helper_cookies[slack_id]
+            );
+        }
+        if !command_args.skip_unresolved
+            && !confirm_interruptible("Leave them out and carry on with everyone else?").await?
+        {
+            return Err(Failure::Aborted.wrap(anyhow::anyhow!(
+                "Aborted because {} helper(s) could not be resolved (use --interactive to pick between matches, or --skip-unresolved to leave them out)",
+                unresolved_helpers.len()
+            )));
+        }
+        println!();
+    }
+
+    print_helper_cookies(
+        &helper_cookies,
+        &helper_tickets,
+        &helper_users,
+        &shop_items,
+        &command_args
+            .format
+            .unwrap_or(PayoutListFormat::ManualPayouts),
+        command_args.mentions,
+        &lookup_failures,
+    )?;
+    journal.listed();
+    for (slack_id, _) in sort_by_cookies(&helper_cookies, &helper_tickets) {
+        let Some(user) = helper_users.get(slack_id) else {
+            continue;
+        };
+        debug!(
+            event = "payout",
+            slack_id,
+            flavortown_user_id = user.id,
+            tickets_closed = helper_tickets[slack_id],
+            cookies = helper_cookies[slack_id].to_f64(),
+            "{} is owed {} cookies",
+            user.display_name,
+            helper_cookies[slack_id]
+        );
+    }
+    // Anything more would leave those helpers out for good (the ledger would
+    // say that they'd been dealt with), so stop at the report
+    if !lookup_failures.is_empty() {
+        // When nobody could be looked up, it's the API that's failing
+        let failure = if helper_users.is_empty() {
+            Failure::Api
+        } else {
+            Failure::Partial
+        };
+        return Err(failure.wrap(anyhow::anyhow!(
+            "{} helper(s) couldn't be looked up on Flavortown, so nothing was written, recorded, posted or sent (run it again once the lookups work)",
+            lookup_failures.len()
+        )));
+    }
+    // Everything that gives out the payouts, or tells people about them
+    let dms_failed = async {
+        for path in &command_args.also_write {
+            let list = format_helper_cookies(
+                &helper_cookies,
+                &helper_tickets,
+                &helper_users,
+                &shop_items,
+                &PayoutListFormat::for_path(path)?,
+                command_args.mentions,
+            )?;
+            std::fs::write(path, list).with_context(|| {
+                format!("Failed to write the payout list to {}", path.display())
+            })?;
+            info!("Wrote the payout list to {}", path.display());
+            journal.finish(format!("Wrote the payout list to {}", path.display()));
+        }
+
+        let run_id = if let Some(ledger) = ledger {
+            let mut items: Vec<PayoutItem> = helper_cookies
+                .iter()
+                .map(|(slack_id, cookies)| {
+                    Ok(PayoutItem {
+                        slack_id: slack_id.clone(),
+                        flavortown_user_id: helper_users.get(slack_id).map(|user| user.id),
+                        tickets_closed: helper_tickets[slack_id],
+                        cookies: *cookies,
+                    })
+                })
+                .collect::<Result<_>>()?;
+            items.sort_by(|a, b| a.slack_id.cmp(&b.slack_id));
+            journal.start("recording the run in the ledger");
+            let run_id = ledger
+                .record_payout_run(&PayoutRun {
+                    period_start: period.start,
+                    period_end: period.end,
+                    cookie_rate: command_args.payout_specifier.cookie_rate,
+                    cookie_pool: command_args.payout_specifier.cookie_pool,
+                    items,
+                })
+                .await
+                .failure(Failure::Database)?;
+            info!(
+                event = "run_recorded",
+                run_id, "Recorded as payout run #{}", run_id
+            );
+            journal.recorded(run_id);
+            Some(run_id)
+        } else {
+            None
+        };
+
+        if let Some(path) = &command_args.write_plan {
+            let helpers = helper_users
+                .iter()
+                .map(|(slack_id, user)| PlannedPayout {
+                    slack_id: slack_id.clone(),
+                    flavortown_user_id: user.id,
+                    name: user.display_name.clone(),
+                    tickets_closed: helper_tickets[slack_id],
+                    cookies: helper_cookies[slack_id],
+                })
+                .collect();
+            PayoutPlan::new(
+                period.start,
+                period.end,
+                command_args.payout_specifier.cookie_rate,
+                command_args.payout_specifier.cookie_pool,
+                run_id,
+                helpers,
+            )
+            .write(path)?;
+            info!("Wrote the plan to {}", path.display());
+            journal.finish(format!("Wrote the plan to {}", path.display()));
+        }
+
+        if let Some(notifier) = slack
+            .announce_to
+            .as_ref()
+            .filter(|_| command_args.post_to_slack)
+        {
+            let announcement = payout_list_message(
+                &helper_cookies,
+                &helper_tickets,
+                &helper_users,
+                &shop_items,
+                command_args.mentions,
+            );
+            journal.start(format!(
+                "posting the payout list to {}",
+                notifier.platform()
+            ));
+            notifier.notify(&announcement).await.failure(Failure::Api)?;
+            info!("Posted the payout list to {}", notifier.platform());
+            journal.finish(format!("Posted the payout list to {}", notifier.platform()));
+        }
+
+        if let Some(notifier) = slack
+            .announce_to
+            .as_ref()
+            .filter(|_| command_args.celebrate)
+        {
+            journal.start(format!(
+                "celebrating the top helper on {}",
+                notifier.platform()
+            ));
+            celebrate_top_helper(
+                notifier,
+                slack.bot_token.as_deref(),
+                &helper_cookies,
+                &helper_tickets,
+                &helper_users,
+            )
+            .await
+            .failure(Failure::Api)?;
+            journal.finish(format!(
+                "Celebrated the top helper on {}",
+                notifier.platform()
+            ));
+        }
+
+        #[cfg(feature = "discord")]
+        if let Some(webhook_url) = &command_args.discord_webhook {
+            let embed =
+                discord_payout_embed(&helper_cookies, &helper_tickets, &helper_users, period)?;
+            journal.start("posting the payout list to Discord");
+            discord::post_embed(webhook_url, &embed)
+                .await
+                .failure(Failure::Api)?;
+            info!("Posted the payout list to Discord");
+            journal.finish("Posted the payout list to Discord");
+        }
+
+        if command_args.dm_recipients {
+            send_payout_dms(
+                slack,
+                &helper_cookies,
+                &helper_tickets,
+                &helper_users,
+                period,
+                command_args.dm_dry_run,
+                journal,
+            )
+            .await
+        } else {
+            Ok(0)
+        }
+    }
+    .instrument(trace_span!("grant"))
+    .await?;
+
+    if command_args.verify_balances {
+        verify_balances(reward_backend, &helper_users, &helper_cookies).await?;
+    }
+
+    let paid_cookies: Decimal = helper_users
+        .keys()
+        .map(|slack_id| helper_cookies[slack_id])
+        .sum();
+    info!(
+        event = "run_finished",
+        helpers_paid = helper_users.len(),
+        helpers_unresolved = unresolved_helpers.len(),
+        cookies = paid_cookies.to_f64(),
+        "Done: {} cookies for {} helper(s)",
+        paid_cookies.normalize(),
+        helper_users.len()
+    );
+    if dms_failed > 0 {
+        return Err(Failure::Partial.wrap(anyhow::anyhow!(
+            "{} DM(s) couldn't be sent, so those helpers haven't been told about their payout",
+            dms_failed
+        )));
+    }
+    Ok(())
+}
+
+/// Posts a congratulations message for the helper who closed the most tickets
+/// (out of those being paid), with their avatar from Flavortown or Slack
+pub(crate) async fn celebrate_top_helper(
+    notifier: &AnyNotifier,
+    bot_token: Option<&str>,
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+) -> Result<(), anyhow::Error> {
+    let mut rankings: Vec<(&String, i64)> = helper_users
+        .keys()
+        .map(|slack_id| (slack_id, helper_tickets[slack_id]))
+        .collect();
+    rankings.sort_by(|(id_a, tickets_a), (id_b, tickets_b)| {
+        tickets_b.cmp(tickets_a).then_with(|| id_a.cmp(id_b))
+    });
+    let Some(&(slack_id, tickets)) = rankings.first() else {
+        info!("Nobody to celebrate, as no helpers are being paid");
+        return Ok(());
+    };
+    let user = &helper_users[slack_id];
+    let total_tickets: i64 = helper_tickets.values().sum();
+
+    let mut line = vec![
+        Span::Text(":trophy: Congratulations to ".to_string()),
+        Span::Bold(user.display_name.clone()),
+        Span::Text(" (".to_string()),
+        Span::Mention {
+            slack_id: slack_id.clone(),
+            name: Some(user.display_name.clone()),
+        },
+        Span::Text("), this period's top helper! They closed ".to_string()),
+        Span::Bold(tickets.to_string()),
+        // A custom query could count nobody's tickets, which would make this NaN
+        Span::Text(if total_tickets > 0 {
+            format!(
+                " tickets ({:.0}% of them all)",
+                tickets as f64 / total_tickets as f64 * 100.0
+            )
+        } else {
+            " tickets".to_string()
+        }),
+    ];
+    match rankings.get(1) {
+        Some((_, runner_up_tickets)) if *runner_up_tickets < tickets => line.push(Span::Text(
+            format!(", {} more than anyone else,", tickets - runner_up_tickets),
+        )),
+        Some(_) => line.push(Span::Text(", tied for first place,".to_string())),
+        None => {}
+    }
+    line.extend([
+        Span::Text(" and earned ".to_string()),
+        Span::Bold(whole_cookies(helper_cookies[slack_id]).to_string()),
+        Span::Text(" cookies. Thank you! :tada:".to_string()),
+    ]);
+
+    let avatar = if !user.avatar.is_empty() {
+        Some(user.avatar.clone())
+    } else if let Some(bot_token) = bot_token {
+        slack::get_avatar(bot_token, slack_id).await?
+    } else {
+        None
+    };
+    let message = Message {
+        lines: vec![line],
+        image: avatar.map(|url| Image {
+            url,
+            alt_text: user.display_name.clone(),
+        }),
+    };
+    notifier.notify(&message).await?;
+    info!(
+        "Posted a celebration of {} to {}",
+        user.display_name,
+        notifier.platform()
+    );
+    Ok(())
+}
+
+/// DMs each helper who's being paid to tell them about their payout, or just
+/// prints the messages for a dry run. Returns how many DMs couldn't be sent.
+pub(crate) async fn send_payout_dms(
+    slack: &SlackOptions,
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+    period: Period,
+    dry_run: bool,
+    journal: &RunJournal,
+) -> Result<usize, anyhow::Error> {
+    let date_format = format_description!("[day padding:none] [month repr:short] [year]");
+    let mut messages: Vec<(String, String)> = helper_users
+        .keys()
+        .map(|slack_id| {
+            let text = format!(
+                "Hey! You closed *{}* tickets between {} and {}, so you're getting *{}* cookies on Flavortown. Thanks for helping out! :cookie:",
+                helper_tickets[slack_id],
+                period.start.format(&date_format)?,
+                period.end.format(&date_format)?,
+                whole_cookies(helper_cookies[slack_id])
+            );
+            Ok((slack_id.clone(), text))
+        })
+        .collect::<Result<_>>()?;
+    messages.sort();
+
+    if dry_run {
+        println!("Would send {} DM(s):", messages.len());
+        for (slack_id, text) in &messages {
+            println!("- {}: {}", slack_id, text);
+        }
+        return Ok(0);
+    }
+
+    let bot_token = slack
+        .bot_token
+        .as_deref()
+        .context("SLACK_BOT_TOKEN is needed to send DMs")?;
+    let sent = messages.len();
+    journal.start("sending DMs");
+    let failures =
+        slack::send_direct_messages(bot_token, messages, |slack_id| journal.dmed(slack_id)).await;
+    info!(
+        event = "dms_sent",
+        sent = sent - failures.len(),
+        failed = failures.len(),
+        "Sent {} of {} DM(s)",
+        sent - failures.len(),
+        sent
+    );
+    for (slack_id, error) in &failures {
+        warn!(
+            event = "dm_failed",
+            slack_id,
+            error = %format!("{:#}", error),
+            "Couldn't DM {}: {:#}",
+            slack_id,
+            error
+        );
+    }
+    journal.finish(format!("Sent {} of {} DM(s)", sent - failures.len(), sent));
+    Ok(failures.len())
+}
+
+/// Waits for the operator to give out the payouts, then re-fetches each
+/// helper's balance and reports any that didn't change by what they were owed
+pub(crate) async fn verify_balances(
+    reward_backend: &impl RewardBackend,
+    helper_users: &HashMap<String, FlavortownUser>,
+    helper_cookies: &HashMap<String, Decimal>,
+) -> Result<(), anyhow::Error> {
+    print!("Press Enter once all the payouts above have been given to check balances...");
+    std::io::stdout().flush()?;
+    read_line_interruptible().await?;
+
+    reward_backend.forget_cached_users();
+    let updated_matches = reward_backend
+        .find_users_by_slack_ids(helper_users.keys())
+        .await;
+    let mut discrepancies = 0;
+    let mut helper_users: Vec<(&String, &FlavortownUser)> = helper_users.iter().collect();
+    helper_users.sort_by_key(|(slack_id, _)| *slack_id);
+    for &(slack_id, user) in &helper_users {
+        let expected = whole_cookies(helper_cookies[slack_id])
+            .to_i64()
+            .context("Payout is too big to check")?;
+        let updated = updated_matches
+            .get(slack_id)
+            .and_then(|users| users.as_ref().ok())
+            .and_then(|users| users.iter().find(|u| u.id == user.id));
+        let (Some(before), Some(after)) = (user.cookies, updated.and_then(|u| u.cookies)) else {
+            println!(
+                "- {}: balance not available from the API, check manually",
+                user.display_name
+            );
+            discrepancies += 1;
+            continue;
+        };
+        if after - before != expected {
+            println!(
+                "- {}: expected +{} cookies but balance went {} -> {} ({:+})",
+                user.display_name,
+                expected,
+                before,
+                after,
+                after - before
+            );
+            discrepancies += 1;
+        }
+    }
+    if discrepancies == 0 {
+        println!("All {} balances changed as expected", helper_users.len());
+    } else {
+        println!(
+            "{} balance(s) did not change as expected (note that shop orders also change balances)",
+            discrepancies
+        );
+    }
+    println!();
+    Ok(())
+}
+
+/// Pool payouts are shared out to this many decimal places (hundredths of a
+/// cookie)
+pub const POOL_DECIMAL_PLACES: u32 = 2;
+
+/// Shares `pool` cookies out between helpers, in proportion to the number of
+/// tickets each of them closed. Shares are rounded down to
+/// `POOL_DECIMAL_PLACES`, and what that leaves over goes to the helpers who
+/// lost the most to rounding, so that the shares always add up to the pool.
+pub fn do_pool_payouts(
+    helper_tickets: &HashMap<String, i64>,
+    pool: &i64,
+) -> Result<HashMap<String, Decimal>, anyhow::Error> {
+    let pool = Decimal::from(*pool);
+    let total_tickets_closed = Decimal::from(helper_tickets.values().sum::<i64>());
+    if total_tickets_closed.is_zero() {
+        return Ok(helper_tickets
+            .keys()
+            .map(|id| (id.clone(), Decimal::ZERO))
+            .collect());
+    }
+    let mut shares: Vec<(&String, Decimal, Decimal)> = helper_tickets
+        .iter()
+        .map(|(id, tickets)| {
+            let exact = Decimal::from(*tickets) * pool / total_tickets_closed;
+            let rounded =
+                exact.round_dp_with_strategy(POOL_DECIMAL_PLACES, RoundingStrategy::ToZero);
+            (id, rounded, exact - rounded)
+        })
+        .collect();
+    // Biggest remainder first, and then by Slack ID so that ties always go the
+    // same way
+    shares.sort_by(|(id_a, _, remainder_a), (id_b, _, remainder_b)| {
+        remainder_b.cmp(remainder_a).then_with(|| id_a.cmp(id_b))
+    });
+    let step = Decimal::new(1, POOL_DECIMAL_PLACES);
+    let mut left_over = pool
+        - shares
+            .iter()
+            .map(|(_, rounded, _)| rounded)
+            .sum::<Decimal>();
+    let mut helper_cookies = HashMap::new();
+    for (id, rounded, _) in shares {
+        let share = if left_over >= step {
+            left_over -= step;
+            rounded + step
+        } else {
+            rounded
+        };
+        helper_cookies.insert(id.clone(), share.normalize());
+    }
+    Ok(helper_cookies)
+}
+
+/// Pays each helper `payout_rate` cookies for every ticket they closed
+pub fn do_static_rate_payouts(
+    helper_tickets: &HashMap<String, i64>,
+    payout_rate: &Decimal,
+) -> Result<HashMap<String, Decimal>, anyhow::Error> {
+    helper_tickets
+        .iter()
+        .map(|(id, tickets)| {
+            let cookies = Decimal::from(*tickets)
+                .checked_mul(*payout_rate)
+                .context("Too many cookies to pay out")?;
+            Ok((id.clone(), cookies.normalize()))
+        })
+        .collect()
+}
@@ -0,0 +1,86 @@
+use anyhow::{Context, Ok, Result};
+use clap::{Args, FromArgMatches};
+use tracing::{info, warn};
+
+use crate::cli::PayoutArgs;
+use crate::config::{RunConfig, Stage};
+
+/// Turns the config file's `[run]` table into the `crimson payout` that does
+/// the same, so that every stage works from the one set of payouts
+pub fn payout_args(run: &RunConfig) -> Result<Box<PayoutArgs>, anyhow::Error> {
+    if run.stages.first() != Some(&Stage::Compute) {
+        return Err(anyhow::anyhow!(
+            "The stages in the config file's [run] must start with compute"
+        ));
+    }
+    if !run.stages.is_sorted_by(|a, b| a < b) {
+        return Err(anyhow::anyhow!(
+            "The stages in the config file's [run] must be in the order compute, archive, notify, execute (each at most once)"
+        ));
+    }
+    info!(
+        "Running stages: {}",
+        run.stages
+            .iter()
+            .map(|stage| format!("{:?}", stage).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut args: Vec<String> = Vec::new();
+    for (flag, value) in [
+        ("--period", &run.period),
+        ("--start", &run.start),
+        ("--end", &run.end),
+        ("--duration", &run.duration),
+        ("--week", &run.week),
+        ("--month", &run.month),
+        ("--preset", &run.preset),
+    ] {
+        if let Some(value) = value {
+            args.extend([flag.to_string(), value.clone()]);
+        }
+    }
+    if run.stages.contains(&Stage::Archive) {
+        args.push("--record".to_string());
+    }
+    let notify = &run.notify;
+    let notifies = notify.post || notify.celebrate || notify.dm || notify.discord_webhook.is_some();
+    if run.stages.contains(&Stage::Notify) {
+        if !notifies {
+            return Err(anyhow::anyhow!(
+                "The notify stage has nothing to do (set e.g. `post = true` in the config file's [run.notify])"
+            ));
+        }
+        for (flag, enabled) in [
+            ("--post-to-slack", notify.post),
+            ("--celebrate", notify.celebrate),
+            ("--mentions", notify.mentions),
+            ("--dm-recipients", notify.dm),
+        ] {
+            if enabled {
+                args.push(flag.to_string());
+            }
+        }
+        if let Some(discord_webhook) = &notify.discord_webhook {
+            args.extend(["--discord-webhook".to_string(), discord_webhook.clone()]);
+        }
+    } else if notifies {
+        warn!("Ignoring [run.notify], as there's no notify stage");
+    }
+    if run.stages.contains(&Stage::Execute) {
+        args.push("--verify-balances".to_string());
+    }
+
+    // Parsed like the command line, so that it's checked in the same way
+    let matches = PayoutArgs::augment_args(clap::Command::new("run").no_binary_name(true))
+        .try_get_matches_from(&args)
+        .map_err(|error| {
+            // Leave out the usage, which is about flags rather than the table
+            let message = error.render().to_string();
+            let message = message.split("\n\n").next().unwrap_or_default();
+            anyhow::anyhow!("{}", message.trim_start_matches("error: "))
+        })
+        .context("Invalid [run] in the config file")?;
+    Ok(Box::new(PayoutArgs::from_arg_matches(&matches)?))
+}
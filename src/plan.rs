@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Ok, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::info;
+
+use crate::cli::ExecuteArgs;
+use crate::config::Config;
+use crate::failure::{Failure, FailureContext};
+use crate::flavortown::{FlavortownClient, RewardBackend, log_api_metrics};
+use crate::ledger::{LedgerEntry, LedgerFile, LedgerPayout, RunKind};
+use crate::natural_date::Period;
+use crate::payout::{combine_problems, flavortown_client, verify_balances};
+use crate::report::{PayoutListFormat, print_helper_cookies};
+use crate::setup::SetupWizard;
+
+/// Changes whenever plans written by an older crimson can't be read any more
+const PLAN_VERSION: u32 = 1;
+
+/// A payout that's been fully worked out, as written by `--write-plan`, so
+/// that what's given out later is exactly what was reviewed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PayoutPlan {
+    pub version: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub period_start: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub period_end: OffsetDateTime,
+    /// Set if helpers are paid a fixed number of cookies per ticket
+    pub cookie_rate: Option<Decimal>,
+    /// Set if helpers share out a fixed pool of cookies
+    pub cookie_pool: Option<i64>,
+    /// The payout run it was recorded as, with `--record`
+    pub run_id: Option<i64>,
+    /// Everyone being paid (helpers who couldn't be resolved aren't included)
+    pub helpers: Vec<PlannedPayout>,
+}
+
+/// What one helper gets as part of a `PayoutPlan`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlannedPayout {
+    pub slack_id: String,
+    pub flavortown_user_id: i64,
+    pub name: String,
+    pub tickets_closed: i64,
+    /// Written as a string, so that it's exact
+    pub cookies: Decimal,
+}
+
+impl PayoutPlan {
+    pub fn new(
+        period_start: OffsetDateTime,
+        period_end: OffsetDateTime,
+        cookie_rate: Option<Decimal>,
+        cookie_pool: Option<i64>,
+        run_id: Option<i64>,
+        mut helpers: Vec<PlannedPayout>,
+    ) -> Self {
+        helpers.sort_by(|a, b| a.slack_id.cmp(&b.slack_id));
+        Self {
+            version: PLAN_VERSION,
+            period_start,
+            period_end,
+            cookie_rate,
+            cookie_pool,
+            run_id,
+            helpers,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json + "\n")
+            .with_context(|| format!("Failed to write the plan to {}", path.display()))
+    }
+
+    /// The ledger's entry for executing the plan, with `result`. It's a dry run
+    /// unless it got as far as checking everyone's balance.
+    fn ledger_entry(
+        &self,
+        checked_balances: bool,
+        result: &Result<(), anyhow::Error>,
+    ) -> LedgerEntry {
+        let period = Period {
+            start: self.period_start,
+            end: self.period_end,
+        };
+        LedgerEntry {
+            run_id: self.run_id,
+            cookie_rate: self.cookie_rate,
+            cookie_pool: self.cookie_pool,
+            helpers: self
+                .helpers
+                .iter()
+                .map(|helper| LedgerPayout {
+                    slack_id: helper.slack_id.clone(),
+                    flavortown_user_id: Some(helper.flavortown_user_id),
+                    tickets_closed: helper.tickets_closed,
+                    cookies: helper.cookies,
+                })
+                .collect(),
+            ..LedgerEntry::new(RunKind::Verification, !checked_balances, period, result)
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan {}", path.display()))?;
+        let plan: Self = serde_json::from_str(&json)
+            .with_context(|| format!("Invalid plan {}", path.display()))?;
+        if plan.version != PLAN_VERSION {
+            return Err(anyhow::anyhow!(
+                "Plan {} is version {}, but this crimson only reads version {} (work it out again with --write-plan)",
+                path.display(),
+                plan.version,
+                PLAN_VERSION
+            ));
+        }
+        Ok(plan)
+    }
+}
+
+/// Goes through a plan written by `--write-plan`: lists its payouts for them
+/// to be given out, then checks everyone's balance. Nothing is counted again,
+/// so the payouts are exactly the ones in the plan.
+pub(crate) async fn execute(
+    execute_args: &ExecuteArgs,
+    config: &Config,
+    wizard: Option<&SetupWizard>,
+) -> Result<(), anyhow::Error> {
+    let plan = PayoutPlan::read(&execute_args.plan).failure(Failure::Validation)?;
+    let mut problems = Vec::new();
+    let flavortown = flavortown_client(&execute_args.flavortown, config, wizard, &mut problems);
+    let Some(flavortown) = flavortown.filter(|_| problems.is_empty()) else {
+        return Err(combine_problems(problems));
+    };
+    info!(
+        "Executing the plan {}for {} helper(s), from {} to {}",
+        match plan.run_id {
+            Some(run_id) => format!("for payout run #{} ", run_id),
+            None => String::new(),
+        },
+        plan.helpers.len(),
+        plan.period_start.format(&Rfc3339)?,
+        plan.period_end.format(&Rfc3339)?
+    );
+    let mut checked_balances = false;
+    let result = execute_plan(&plan, &flavortown, execute_args, &mut checked_balances).await;
+    log_api_metrics(&flavortown.metrics());
+    if let Some(ledger_file) = LedgerFile::new(&execute_args.ledger, config) {
+        ledger_file.append(&plan.ledger_entry(checked_balances, &result));
+    }
+    result
+}
+
+async fn execute_plan(
+    plan: &PayoutPlan,
+    flavortown: &FlavortownClient,
+    execute_args: &ExecuteArgs,
+    checked_balances: &mut bool,
+) -> Result<(), anyhow::Error> {
+    let helper_cookies: HashMap<String, Decimal> = plan
+        .helpers
+        .iter()
+        .map(|helper| (helper.slack_id.clone(), helper.cookies))
+        .collect();
+    let helper_tickets: HashMap<String, i64> = plan
+        .helpers
+        .iter()
+        .map(|helper| (helper.slack_id.clone(), helper.tickets_closed))
+        .collect();
+
+    // Fetched again for everyone's current balance, and to make sure that
+    // they're still the user the plan says they are
+    let mut matches = flavortown
+        .find_users_by_slack_ids(helper_cookies.keys())
+        .await;
+    let mut helper_users = HashMap::new();
+    let mut problems = Vec::new();
+    for helper in &plan.helpers {
+        match matches.remove(&helper.slack_id) {
+            Some(Result::Ok(users)) => {
+                match users
+                    .into_iter()
+                    .find(|user| user.id == helper.flavortown_user_id)
+                {
+                    Some(user) => {
+                        helper_users.insert(helper.slack_id.clone(), user);
+                    }
+                    None => problems.push(anyhow::anyhow!(
+                        "{} ({}) is no longer Flavortown user {}",
+                        helper.name,
+                        helper.slack_id,
+                        helper.flavortown_user_id
+                    )),
+                }
+            }
+            Some(Err(error)) => problems.push(anyhow::Error::from(error).context(format!(
+                "Couldn't look up {} on Flavortown",
+                helper.slack_id
+            ))),
+            None => unreachable!("Every helper in the plan is looked up"),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(combine_problems(problems));
+    }
+
+    print_helper_cookies(
+        &helper_cookies,
+        &helper_tickets,
+        &helper_users,
+        &[],
+        &execute_args
+            .format
+            .unwrap_or(PayoutListFormat::ManualPayouts),
+        false,
+        &[],
+    )?;
+    *checked_balances = true;
+    verify_balances(flavortown, &helper_users, &helper_cookies).await
+}
@@ -0,0 +1,18 @@
+use std::io::IsTerminal;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// A progress bar for one of the slow, API-heavy phases of a run, shown as
+/// e.g. "Resolving users 17/43". It's hidden when stdout isn't a terminal, so
+/// that scheduled runs and piped output stay clean.
+pub fn bar(message: &'static str, len: usize) -> ProgressBar {
+    if !std::io::stdout().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(len as u64).with_message(message);
+    bar.set_style(
+        ProgressStyle::with_template("{msg} {pos}/{len} {wide_bar}")
+            .expect("progress bar template should be valid"),
+    );
+    bar
+}
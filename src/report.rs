@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{Ok, Result};
+use clap::ValueEnum;
+use rust_decimal::{Decimal, RoundingStrategy};
+#[cfg(feature = "discord")]
+use time::macros::format_description;
+use tracing::warn;
+
+#[cfg(feature = "discord")]
+use crate::discord;
+use crate::flavortown::{FlavortownUser, ShopItem};
+#[cfg(feature = "discord")]
+use crate::natural_date::Period;
+use crate::notify::{Markup, Message, Span};
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum PayoutListFormat {
+    /// Format the payout list in a way that's optimised for letting a
+    /// Flavortown admin easily and accurately give the payouts manually
+    #[clap(name = "payout")]
+    ManualPayouts,
+    /// Format the payout list in a way that makes sense for a
+    /// Slack message
+    #[clap(name = "message")]
+    SlackMessage,
+    /// An aligned table of helpers, tickets and cookies
+    Table,
+    /// JSON, with every helper (including any who couldn't be matched to a
+    /// Flavortown user)
+    Json,
+    /// CSV, with every helper, for spreadsheets
+    Csv,
+}
+
+impl PayoutListFormat {
+    /// The format to write a file in for --also-write, going by its extension
+    pub fn for_path(path: &Path) -> Result<Self, anyhow::Error> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("csv") => Ok(Self::Csv),
+            Some("txt") => Ok(Self::ManualPayouts),
+            _ => Err(anyhow::anyhow!(
+                "Don't know what format to write {} in (it needs to end in .json, .csv or .txt)",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Prints the payout list, and then the helpers who couldn't be looked up on
+/// Flavortown (so aren't in it). For JSON and CSV, which have to stay
+/// parseable, those are logged instead.
+pub fn print_helper_cookies(
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+    shop_items: &[ShopItem],
+    format: &PayoutListFormat,
+    mentions: bool,
+    lookup_failures: &[(String, anyhow::Error)],
+) -> Result<(), anyhow::Error> {
+    print!(
+        "{}",
+        format_helper_cookies(
+            helper_cookies,
+            helper_tickets,
+            helper_users,
+            shop_items,
+            format,
+            mentions
+        )?
+    );
+    if lookup_failures.is_empty() {
+        return Ok(());
+    }
+    match format {
+        PayoutListFormat::Json | PayoutListFormat::Csv => {
+            for (slack_id, error) in lookup_failures {
+                warn!(
+                    "Could not resolve {}, owed {} cookies: {:#}",
+                    slack_id, helper_cookies[slack_id], error
+                );
+            }
+        }
+        _ => {
+            println!(
+                "Could not resolve these {} helper(s), as looking them up on Flavortown failed:",
+                lookup_failures.len()
+            );
+            for (slack_id, error) in lookup_failures {
+                println!(
+                    "- {}: owed {} cookies ({:#})",
+                    slack_id, helper_cookies[slack_id], error
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders the payout list, with the totals at the top. With `mentions`,
+/// helpers are shown as Slack mentions rather than by name.
+pub fn format_helper_cookies(
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+    shop_items: &[ShopItem],
+    format: &PayoutListFormat,
+    mentions: bool,
+) -> Result<String, anyhow::Error> {
+    match format {
+        PayoutListFormat::SlackMessage => {
+            return Ok(payout_list_message(
+                helper_cookies,
+                helper_tickets,
+                helper_users,
+                shop_items,
+                mentions,
+            )
+            .render(Markup::Slack));
+        }
+        PayoutListFormat::Table => {
+            return payout_table(helper_cookies, helper_tickets, helper_users);
+        }
+        PayoutListFormat::Json => {
+            let helpers: Vec<serde_json::Value> = sort_by_cookies(helper_cookies, helper_tickets)
+                .into_iter()
+                .map(|(slack_id, cookies)| {
+                    let user = helper_users.get(slack_id);
+                    serde_json::json!({
+                        "slack_id": slack_id,
+                        "name": user.map(|user| &user.display_name),
+                        "flavortown_user_id": user.map(|user| user.id),
+                        "tickets_closed": helper_tickets.get(slack_id),
+                        // As a string, so that it's exact
+                        "cookies": cookies.normalize(),
+                    })
+                })
+                .collect();
+            let list = serde_json::json!({
+                "total_tickets_closed": helper_tickets.values().sum::<i64>(),
+                "total_cookies": helper_cookies.values().sum::<Decimal>().normalize(),
+                "helpers": helpers,
+            });
+            return Ok(format!("{}\n", serde_json::to_string_pretty(&list)?));
+        }
+        PayoutListFormat::Csv => {
+            let mut output =
+                String::from("slack_id,name,flavortown_user_id,tickets_closed,cookies\n");
+            for (slack_id, cookies) in sort_by_cookies(helper_cookies, helper_tickets) {
+                let user = helper_users.get(slack_id);
+                writeln!(
+                    output,
+                    "{},{},{},{},{}",
+                    csv_field(slack_id),
+                    csv_field(user.map_or("", |user| &user.display_name)),
+                    user.map(|user| user.id.to_string()).unwrap_or_default(),
+                    helper_tickets
+                        .get(slack_id)
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                    cookies
+                )?;
+            }
+            return Ok(output);
+        }
+        PayoutListFormat::ManualPayouts => {}
+    }
+
+    let mut output = String::new();
+    writeln!(
+        output,
+        "Total tickets closed: {}",
+        helper_tickets.values().sum::<i64>()
+    )?;
+    writeln!(
+        output,
+        "Total cookies to pay out: {}",
+        helper_cookies.values().sum::<Decimal>().normalize()
+    )?;
+    writeln!(output)?;
+
+    for (slack_id, cookies) in sort_by_cookies(helper_cookies, helper_tickets) {
+        let Some(user) = helper_users.get(slack_id) else {
+            continue;
+        };
+        writeln!(
+            output,
+            "{}: https://flavortown.hackclub.com/admin/users/{} gets {} cookies!{} ({} tkts)\n",
+            if mentions {
+                format!("<@{}>", slack_id)
+            } else {
+                user.display_name.clone()
+            },
+            user.id,
+            cookies,
+            describe_shop_context(cookies, shop_items),
+            match helper_tickets.get(slack_id) {
+                Some(tickets) => tickets.to_string(),
+                None => "[unknown]".to_string(),
+            },
+        )?;
+    }
+    Ok(output)
+}
+
+/// The payout list as a table with a column for each detail, lined up for
+/// reading in a terminal
+pub fn payout_table(
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+) -> Result<String, anyhow::Error> {
+    let mut rows = vec![[
+        "Helper".to_string(),
+        "Flavortown user".to_string(),
+        "Tickets".to_string(),
+        "Cookies".to_string(),
+    ]];
+    // Helpers who couldn't be found on Flavortown get a row between them, so
+    // that the total adds up
+    let mut unresolved = (0, 0, Decimal::ZERO);
+    for (slack_id, cookies) in sort_by_cookies(helper_cookies, helper_tickets) {
+        let Some(user) = helper_users.get(slack_id) else {
+            unresolved.0 += 1;
+            unresolved.1 += helper_tickets.get(slack_id).copied().unwrap_or_default();
+            unresolved.2 += cookies;
+            continue;
+        };
+        rows.push([
+            user.display_name.clone(),
+            user.id.to_string(),
+            match helper_tickets.get(slack_id) {
+                Some(tickets) => tickets.to_string(),
+                None => "?".to_string(),
+            },
+            cookies.to_string(),
+        ]);
+    }
+    let (unresolved_helpers, unresolved_tickets, unresolved_cookies) = unresolved;
+    if unresolved_helpers > 0 {
+        rows.push([
+            format!("Unresolved ({} helper(s))", unresolved_helpers),
+            String::new(),
+            unresolved_tickets.to_string(),
+            unresolved_cookies.normalize().to_string(),
+        ]);
+    }
+    rows.push([
+        "Total".to_string(),
+        String::new(),
+        helper_tickets.values().sum::<i64>().to_string(),
+        helper_cookies
+            .values()
+            .sum::<Decimal>()
+            .normalize()
+            .to_string(),
+    ]);
+
+    let widths: Vec<usize> = (0..4)
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let mut output = String::new();
+    for (i, row) in rows.iter().enumerate() {
+        // The name on the left, and numbers on the right
+        writeln!(
+            output,
+            "{:<name$}  {:>id$}  {:>tickets$}  {:>cookies$}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            name = widths[0],
+            id = widths[1],
+            tickets = widths[2],
+            cookies = widths[3],
+        )?;
+        if i == 0 || i == rows.len() - 2 {
+            writeln!(output, "{}", "-".repeat(widths.iter().sum::<usize>() + 6))?;
+        }
+    }
+    Ok(output)
+}
+
+/// Quotes a value for a CSV file, if it needs it
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The payout list as an announcement, with the totals at the top. With
+/// `mentions`, helpers are mentioned rather than just named.
+pub fn payout_list_message(
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+    shop_items: &[ShopItem],
+    mentions: bool,
+) -> Message {
+    let mut message = Message::default();
+    message.push_line(vec![Span::Text(format!(
+        "Total tickets closed: {}",
+        helper_tickets.values().sum::<i64>()
+    ))]);
+    message.push_line(vec![Span::Text(format!(
+        "Total cookies to pay out: {}",
+        helper_cookies.values().sum::<Decimal>().normalize()
+    ))]);
+    message.push_line(Vec::new());
+
+    for (slack_id, cookies) in sort_by_cookies(helper_cookies, helper_tickets) {
+        let Some(user) = helper_users.get(slack_id) else {
+            continue;
+        };
+        message.push_line(vec![
+            Span::Text("- ".to_string()),
+            if mentions {
+                Span::Mention {
+                    slack_id: slack_id.clone(),
+                    name: Some(user.display_name.clone()),
+                }
+            } else {
+                Span::Bold(user.display_name.clone())
+            },
+            Span::Text(" closed ".to_string()),
+            Span::Bold(match helper_tickets.get(slack_id) {
+                Some(tickets) => tickets.to_string(),
+                None => "[unknown]".to_string(),
+            }),
+            Span::Text(" tickets, netting them ".to_string()),
+            Span::Bold(whole_cookies(cookies).to_string()),
+            Span::Text(format!(
+                " cookies{}.",
+                describe_shop_context(cookies, shop_items)
+            )),
+        ]);
+    }
+    message
+}
+
+/// Helpers and their payouts, biggest first. Ties go to whoever closed more
+/// tickets, and then by Slack ID, so that the same payouts are always listed
+/// in the same order.
+pub fn sort_by_cookies<'a>(
+    helper_cookies: &'a HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+) -> Vec<(&'a String, Decimal)> {
+    let tickets = |slack_id: &String| helper_tickets.get(slack_id).copied().unwrap_or(0);
+    let mut helper_cookies_vec: Vec<(&String, Decimal)> = helper_cookies
+        .iter()
+        .map(|(slack_id, cookies)| (slack_id, *cookies))
+        .collect();
+    helper_cookies_vec.sort_by(|(id_a, cookies_a), (id_b, cookies_b)| {
+        cookies_b
+            .cmp(cookies_a)
+            .then_with(|| tickets(id_b).cmp(&tickets(id_a)))
+            .then_with(|| id_a.cmp(id_b))
+    });
+    helper_cookies_vec
+}
+
+/// A payout as the whole number of cookies that's given out, rounding halves
+/// up (rather than to the nearest even number, as `Decimal::round` does)
+pub fn whole_cookies(cookies: Decimal) -> Decimal {
+    cookies.round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero)
+}
+
+/// What a payout could buy, to tack onto the end of a line about it (or
+/// nothing, if it can't afford anything)
+pub fn describe_shop_context(cookies: Decimal, shop_items: &[ShopItem]) -> String {
+    match describe_purchasing_power(cookies, shop_items) {
+        Some(description) => format!(" ≈ {}", description),
+        None => String::new(),
+    }
+}
+
+/// Describes what a payout could buy, in terms of the priciest shop item it
+/// affords (e.g. "2× Sticker pack"), or `None` if it can't afford anything
+pub fn describe_purchasing_power(cookies: Decimal, shop_items: &[ShopItem]) -> Option<String> {
+    // Free items would be infinitely affordable, so they're skipped
+    let item = shop_items
+        .iter()
+        .filter(|item| item.cost > Decimal::ZERO && item.cost <= cookies)
+        .max_by_key(|item| item.cost)?;
+    Some(format!("{}× {}", (cookies / item.cost).floor(), item.name))
+}
+
+/// Builds a Discord embed with the leaderboard of paid helpers as its
+/// description and the period and totals as fields
+#[cfg(feature = "discord")]
+pub(crate) fn discord_payout_embed(
+    helper_cookies: &HashMap<String, Decimal>,
+    helper_tickets: &HashMap<String, i64>,
+    helper_users: &HashMap<String, FlavortownUser>,
+    period: Period,
+) -> Result<discord::Embed, anyhow::Error> {
+    let date_format = format_description!("[day padding:none] [month repr:short] [year]");
+    let leaderboard: Vec<(&FlavortownUser, i64, Decimal)> =
+        sort_by_cookies(helper_cookies, helper_tickets)
+            .into_iter()
+            .filter_map(|(slack_id, cookies)| {
+                Some((
+                    helper_users.get(slack_id)?,
+                    helper_tickets[slack_id],
+                    cookies,
+                ))
+            })
+            .collect();
+
+    let mut description = String::new();
+    for (rank, (user, tickets, cookies)) in leaderboard.iter().enumerate() {
+        writeln!(
+            description,
+            "{}. **{}**: {} tickets, {} cookies",
+            rank + 1,
+            user.display_name,
+            tickets,
+            whole_cookies(*cookies)
+        )?;
+    }
+
+    let field = |name: &str, value: String| discord::EmbedField {
+        name: name.to_string(),
+        value,
+        inline: true,
+    };
+    Ok(discord::Embed {
+        title: "Helper leaderboard".to_string(),
+        description,
+        fields: vec![
+            field(
+                "Period",
+                format!(
+                    "{} – {}",
+                    period.start.format(&date_format)?,
+                    period.end.format(&date_format)?
+                ),
+            ),
+            field(
+                "Tickets closed",
+                leaderboard
+                    .iter()
+                    .map(|(_, tickets, _)| tickets)
+                    .sum::<i64>()
+                    .to_string(),
+            ),
+            field(
+                "Cookies paid out",
+                leaderboard
+                    .iter()
+                    .map(|(_, _, cookies)| whole_cookies(*cookies))
+                    .sum::<Decimal>()
+                    .to_string(),
+            ),
+        ],
+    })
+}
@@ -0,0 +1,156 @@
+use anyhow::{Context, Ok, Result};
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use crate::natural_date::assume_local_time;
+
+const DAY_NAMES: &[&str] = &["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+const MONTH_NAMES: &[&str] = &[
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+/// Give up looking for the next time a schedule fires after this long, as it
+/// probably never will (e.g. `0 0 30 2 *`)
+const MAX_LOOKAHEAD: Duration = Duration::days(4 * 366);
+
+/// A cron-style schedule: minute, hour, day of month, month and day of week,
+/// e.g. `0 9 * * MON` for 9am every Monday
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    /// Whether the day of month and day of week fields were both given, in
+    /// which case (as in cron) a day only has to match one of them
+    either_day: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self, anyhow::Error> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(anyhow::anyhow!(
+                "Schedule {:?} should have 5 fields (minute, hour, day of month, month, day of week)",
+                expression
+            ));
+        };
+        let mut days_of_week = parse_field(day_of_week, 0, 7, DAY_NAMES)
+            .with_context(|| format!("Invalid day of week in schedule {:?}", expression))?;
+        // Both 0 and 7 mean Sunday
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59, &[])
+                .with_context(|| format!("Invalid minute in schedule {:?}", expression))?,
+            hours: parse_field(hour, 0, 23, &[])
+                .with_context(|| format!("Invalid hour in schedule {:?}", expression))?,
+            days_of_month: parse_field(day_of_month, 1, 31, &[])
+                .with_context(|| format!("Invalid day of month in schedule {:?}", expression))?,
+            months: parse_field(month, 1, 12, MONTH_NAMES)
+                .with_context(|| format!("Invalid month in schedule {:?}", expression))?,
+            days_of_week,
+            either_day: !day_of_month.starts_with('*') && !day_of_week.starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, date: Date) -> bool {
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().number_days_from_sunday() as usize];
+        if self.either_day {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+
+    /// The first time after `after` that the schedule fires, in `timezone`
+    pub fn next_after(&self, after: OffsetDateTime, timezone: &Tz) -> Option<OffsetDateTime> {
+        let local = after.to_timezone(timezone);
+        let start = PrimitiveDateTime::new(local.date(), local.time());
+        // Cron schedules are to the minute, so start from the next whole one
+        let mut candidate = PrimitiveDateTime::new(
+            start.date(),
+            Time::from_hms(start.hour(), start.minute(), 0).ok()?,
+        ) + Duration::MINUTE;
+        while candidate - start < MAX_LOOKAHEAD {
+            if !self.months[u8::from(candidate.month()) as usize] {
+                // Skip to the start of the next month
+                let (year, month) = match candidate.month() {
+                    Month::December => (candidate.year() + 1, Month::January),
+                    month => (candidate.year(), month.next()),
+                };
+                candidate = Date::from_calendar_date(year, month, 1).ok()?.midnight();
+            } else if !self.matches_day(candidate.date()) {
+                candidate = candidate.date().next_day()?.midnight();
+            } else if !self.hours[candidate.hour() as usize] {
+                candidate = PrimitiveDateTime::new(
+                    candidate.date(),
+                    Time::from_hms(candidate.hour(), 0, 0).ok()?,
+                ) + Duration::HOUR;
+            } else if !self.minutes[candidate.minute() as usize] {
+                candidate += Duration::MINUTE;
+            } else {
+                match assume_local_time(candidate, timezone) {
+                    // Skip over times that don't happen because the clocks
+                    // went forward
+                    None => candidate += Duration::MINUTE,
+                    Some(datetime) => return Some(datetime),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parses one field of a cron expression (e.g. `*/15`, `1-5` or `MON,WED`)
+/// into a lookup table of which values from `min` to `max` it allows
+fn parse_field(field: &str, min: u8, max: u8, names: &[&str]) -> Result<Vec<bool>, anyhow::Error> {
+    let value = |s: &str| -> Result<u8> {
+        if let Some(index) = names.iter().position(|name| name.eq_ignore_ascii_case(s)) {
+            // Named values start at whatever the field's minimum is
+            return Ok(index as u8 + min);
+        }
+        let value: u8 = s
+            .parse()
+            .with_context(|| format!("{:?} isn't a number", s))?;
+        if !(min..=max).contains(&value) {
+            return Err(anyhow::anyhow!(
+                "{} isn't between {} and {}",
+                value,
+                min,
+                max
+            ));
+        }
+        Ok(value)
+    };
+
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u8>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .with_context(|| format!("Invalid step {:?}", step))?,
+            ),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // A single value with a step runs until the end, like `5/15`
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        for allowed_value in (first..=last).step_by(step as usize) {
+            allowed[allowed_value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
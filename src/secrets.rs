@@ -0,0 +1,103 @@
+use std::io::{BufRead, IsTerminal, Read};
+use std::path::Path;
+
+use anyhow::{Context, Ok, Result};
+use clap::ValueEnum;
+use keyring::Entry;
+use tracing::info;
+
+/// The service that crimson's secrets are filed under in the keyring
+const SERVICE: &str = "crimson";
+
+/// A secret that can be kept in the operating system's keyring
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum Secret {
+    FlavortownApiKey,
+}
+
+impl Secret {
+    fn name(self) -> String {
+        self.to_possible_value()
+            .expect("no secrets are skipped")
+            .get_name()
+            .to_string()
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::FlavortownApiKey => "Flavortown API key",
+        }
+    }
+
+    /// Each profile gets its own copy of the secret, so that e.g. staging
+    /// and production can have different API keys
+    fn entry(self, profile: Option<&str>) -> Result<Entry, anyhow::Error> {
+        let user = match profile {
+            Some(profile) => format!("{}@{}", self.name(), profile),
+            None => self.name(),
+        };
+        Entry::new(SERVICE, &user).context("Failed to open the keyring")
+    }
+}
+
+/// Looks a secret up in the keyring, if it's there
+pub fn get(secret: Secret, profile: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+    match secret.entry(profile)?.get_password() {
+        Result::Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(error) => Err(error).context("Failed to read from the keyring"),
+    }
+}
+
+/// Reads a secret from the terminal (without showing it) or from stdin, and
+/// saves it to the keyring
+pub fn set(secret: Secret, profile: Option<&str>) -> Result<(), anyhow::Error> {
+    let value = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password(format!("{}: ", secret.description()))?
+    } else {
+        let mut value = String::new();
+        std::io::stdin().lock().read_line(&mut value)?;
+        value
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(anyhow::anyhow!("No {} given", secret.description()));
+    }
+    secret
+        .entry(profile)?
+        .set_password(value)
+        .context("Failed to save to the keyring")?;
+    info!("Saved the {} to the keyring", secret.description());
+    Ok(())
+}
+
+pub fn delete(secret: Secret, profile: Option<&str>) -> Result<(), anyhow::Error> {
+    match secret.entry(profile)?.delete_credential() {
+        Result::Ok(()) => info!("Removed the {} from the keyring", secret.description()),
+        Err(keyring::Error::NoEntry) => {
+            info!("The {} isn't in the keyring", secret.description())
+        }
+        Err(error) => return Err(error).context("Failed to remove from the keyring"),
+    }
+    Ok(())
+}
+
+/// Reads a secret from a file (like a mounted container secret), or from
+/// stdin if the path is `-`
+pub fn read_file(path: &Path) -> Result<String, anyhow::Error> {
+    let value = if path == Path::new("-") {
+        let mut value = String::new();
+        std::io::stdin()
+            .read_to_string(&mut value)
+            .context("Failed to read secret from stdin")?;
+        value
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read secret file {}", path.display()))?
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(anyhow::anyhow!("Secret file {} is empty", path.display()));
+    }
+    Ok(value.to_string())
+}
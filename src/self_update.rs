@@ -0,0 +1,225 @@
+//! `crimson self-update`: replaces the running binary with the latest release
+//! from GitHub, for organisers who don't have a Rust toolchain.
+//!
+//! Each release has a binary for each platform, named `crimson-<target>` (with
+//! `.exe` on Windows), and a `SHA256SUMS` file listing their checksums, as
+//! `sha256sum` writes it. Release builds also have a minisign key built in
+//! (from `CRIMSON_RELEASE_PUBLIC_KEY`), in which case `SHA256SUMS` has to come
+//! with a valid `SHA256SUMS.minisig` signature.
+
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use minisign_verify::{PublicKey, Signature};
+use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::cli::{SelfUpdateArgs, confirm};
+use crate::failure::{Failure, FailureContext};
+
+/// The minisign public key (the base64 line of `minisign.pub`) that releases
+/// are signed with, if this is a release build
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("CRIMSON_RELEASE_PUBLIC_KEY");
+
+const CHECKSUMS: &str = "SHA256SUMS";
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset, anyhow::Error> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .with_context(|| format!("Release {} has no {}", self.tag_name, name))
+    }
+
+    fn version(&self) -> Result<Version, anyhow::Error> {
+        Version::parse(self.tag_name.trim_start_matches('v'))
+            .with_context(|| format!("Release {} isn't a version number", self.tag_name))
+    }
+}
+
+/// Updates crimson to the latest release (or the one asked for), after
+/// checking with the operator
+pub async fn self_update(args: &SelfUpdateArgs) -> Result<(), anyhow::Error> {
+    let current = Version::parse(env!("CARGO_PKG_VERSION")).expect("crate version is valid");
+    let client = GitHubClient::new()?;
+    let release = client.release(&args.repo, args.version.as_deref()).await?;
+    let version = release.version()?;
+    if args.version.is_none() && version <= current {
+        println!("crimson {} is the latest version", current);
+        return Ok(());
+    }
+    if args.check {
+        println!("crimson {} is available (this is {})", version, current);
+        return Ok(());
+    }
+
+    let target = target()
+        .with_context(|| {
+            format!(
+                "There are no prebuilt binaries for {} on {}",
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            )
+        })
+        .failure(Failure::Validation)?;
+    let binary_name = format!("crimson-{}{}", target, std::env::consts::EXE_SUFFIX);
+    let binary = release.asset(&binary_name)?;
+    if !args.yes && !confirm(&format!("Update crimson from {} to {}?", current, version))? {
+        return Err(anyhow::anyhow!("Not updating crimson")).failure(Failure::Aborted);
+    }
+
+    let checksums = client.download(release.asset(CHECKSUMS)?).await?;
+    let checksums = String::from_utf8(checksums).context("SHA256SUMS isn't text")?;
+    match RELEASE_PUBLIC_KEY {
+        Some(public_key) => {
+            let signature = client
+                .download(release.asset(&format!("{}.minisig", CHECKSUMS))?)
+                .await?;
+            verify_signature(public_key, &checksums, &signature)?;
+        }
+        None => warn!(
+            "This build of crimson has no release key, so only the checksum of the download is checked, not who made it"
+        ),
+    }
+    let expected = checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim_start().trim_start_matches('*') == binary_name)
+        .map(|(checksum, _)| checksum.to_lowercase())
+        .with_context(|| format!("SHA256SUMS doesn't list {}", binary_name))?;
+
+    info!("Downloading {}", binary_name);
+    let new_binary = client.download(binary).await?;
+    let checksum = hex::encode(Sha256::digest(&new_binary));
+    if checksum != expected {
+        return Err(anyhow::anyhow!(
+            "The download of {} is corrupted (its SHA-256 is {}, but SHA256SUMS says {})",
+            binary_name,
+            checksum,
+            expected
+        ));
+    }
+
+    // Only a staging copy: self_replace copies it over the running binary
+    let path = std::env::temp_dir().join(format!("{}-{}", binary_name, std::process::id()));
+    std::fs::write(&path, &new_binary)
+        .with_context(|| format!("Failed to save the new binary to {}", path.display()))?;
+    let replaced = self_replace::self_replace(&path).context("Failed to replace crimson's binary");
+    let _ = std::fs::remove_file(&path);
+    replaced?;
+    info!("Updated crimson from {} to {}", current, version);
+    Ok(())
+}
+
+/// The target that releases are built for on this platform. Linux builds are
+/// static (musl), so they run on any distribution.
+fn target() -> Option<&'static str> {
+    Some(match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => "x86_64-unknown-linux-musl",
+        ("linux", "aarch64") => "aarch64-unknown-linux-musl",
+        ("macos", "x86_64") => "x86_64-apple-darwin",
+        ("macos", "aarch64") => "aarch64-apple-darwin",
+        ("windows", "x86_64") => "x86_64-pc-windows-msvc",
+        ("windows", "aarch64") => "aarch64-pc-windows-msvc",
+        _ => return None,
+    })
+}
+
+fn verify_signature(
+    public_key: &str,
+    checksums: &str,
+    signature: &[u8],
+) -> Result<(), anyhow::Error> {
+    let public_key =
+        PublicKey::from_base64(public_key).expect("CRIMSON_RELEASE_PUBLIC_KEY is a minisign key");
+    let signature = std::str::from_utf8(signature)
+        .ok()
+        .and_then(|signature| Signature::decode(signature).ok())
+        .context("SHA256SUMS.minisig isn't a minisign signature")?;
+    public_key
+        .verify(checksums.as_bytes(), &signature, false)
+        .context(
+            "SHA256SUMS isn't signed with crimson's release key, so the release can't be trusted",
+        )
+}
+
+struct GitHubClient {
+    http: reqwest::Client,
+    api_base: String,
+    token: Option<String>,
+}
+
+impl GitHubClient {
+    /// Uses GITHUB_API_URL (for GitHub Enterprise) and GITHUB_TOKEN (to get
+    /// past the rate limit for anonymous requests), if they're set
+    fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(120))
+                .build()
+                .context("Failed to set up the HTTP client")?,
+            api_base: std::env::var("GITHUB_API_URL")
+                .unwrap_or_else(|_| "https://api.github.com".to_string()),
+            token: std::env::var("GITHUB_TOKEN").ok(),
+        })
+    }
+
+    fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .get(url)
+            .header(USER_AGENT, concat!("crimson/", env!("CARGO_PKG_VERSION")));
+        match &self.token {
+            Some(token) => request.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// The latest release of `repo`, or the one tagged `tag`
+    async fn release(&self, repo: &str, tag: Option<&str>) -> Result<Release, anyhow::Error> {
+        let url = match tag {
+            Some(tag) => format!("{}/repos/{}/releases/tags/{}", self.api_base, repo, tag),
+            None => format!("{}/repos/{}/releases/latest", self.api_base, repo),
+        };
+        self.get(&url)
+            .header(ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| match tag {
+                Some(tag) => format!("Failed to find crimson's {} release on GitHub", tag),
+                None => "Failed to find crimson's latest release on GitHub".to_string(),
+            })?
+            .json()
+            .await
+            .context("Invalid release from GitHub")
+    }
+
+    async fn download(&self, asset: &Asset) -> Result<Vec<u8>, anyhow::Error> {
+        let bytes = self
+            .get(&asset.browser_download_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to download {}", asset.name))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to download {}", asset.name))?;
+        Ok(bytes.to_vec())
+    }
+}
@@ -0,0 +1,168 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Ok, Result};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use time::OffsetDateTime;
+use time_tz::Tz;
+use tracing::{error, info};
+
+use crate::db::MergedTicketSource;
+use crate::digest;
+use crate::metrics::{METRICS, metrics_endpoint};
+use crate::natural_date::RelativePeriod;
+use crate::notify::Markup;
+
+/// Slack gives up on a slash command if it hasn't had a response after 3
+/// seconds, so queries need to finish well before then
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(2500);
+
+/// Requests signed longer ago than this are rejected, so that they can't be
+/// replayed
+const MAX_REQUEST_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// How many helpers to list in response to a slash command
+const LEADERBOARD_SIZE: usize = 10;
+
+const USAGE: &str = "Usage: `/leaderboard [today | yesterday | this week | last week | this month | last month]` (this week by default)";
+
+/// Everything the slash command handler needs
+pub struct SlashCommandServer {
+    pub ticket_source: MergedTicketSource,
+    /// The Slack app's signing secret, for checking that requests come from Slack
+    pub signing_secret: String,
+    /// Timezone that days, weeks and months start in
+    pub timezone: &'static Tz,
+}
+
+/// The parts of a slash command request that we care about
+#[derive(Deserialize, Debug)]
+struct SlashCommand {
+    #[serde(default)]
+    text: String,
+}
+
+/// Serves Slack slash commands (and metrics) on `listen` until Ctrl-C is
+/// pressed
+pub async fn serve(listen: SocketAddr, server: SlashCommandServer) -> Result<(), anyhow::Error> {
+    let app = Router::new()
+        .route("/slack/commands", post(slash_command))
+        .route("/metrics", get(metrics_endpoint))
+        .with_state(Arc::new(server));
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", listen))?;
+    info!(
+        "Listening for Slack slash commands on http://{}/slack/commands",
+        listen
+    );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+    Ok(())
+}
+
+async fn slash_command(
+    State(server): State<Arc<SlashCommandServer>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(error) = verify_signature(
+        &server.signing_secret,
+        &headers,
+        &body,
+        OffsetDateTime::now_utc(),
+    ) {
+        return (StatusCode::UNAUTHORIZED, error.to_string()).into_response();
+    }
+    let command: SlashCommand = match serde_urlencoded::from_bytes(&body) {
+        Result::Ok(command) => command,
+        Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+    };
+
+    let result = leaderboard_message(&server, &command.text).await;
+    METRICS.run_finished("slash_command", result.is_ok());
+    let text = match result {
+        Result::Ok(Some(text)) => text,
+        Result::Ok(None) => return ephemeral(USAGE),
+        Err(error) => {
+            error!("Slash command {:?} failed: {:#}", command.text, error);
+            return ephemeral(&format!("Couldn't load the leaderboard: {:#}", error));
+        }
+    };
+    axum::Json(json!({ "response_type": "in_channel", "text": text })).into_response()
+}
+
+/// A response that only the person who ran the command can see
+fn ephemeral(text: &str) -> Response {
+    axum::Json(json!({ "response_type": "ephemeral", "text": text })).into_response()
+}
+
+/// Checks a request's `X-Slack-Signature` against the signing secret, as per
+/// https://api.slack.com/authentication/verifying-requests-from-slack
+fn verify_signature(
+    signing_secret: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: OffsetDateTime,
+) -> Result<(), anyhow::Error> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .with_context(|| format!("Missing {} header", name))
+    };
+    let timestamp = header("X-Slack-Request-Timestamp")?;
+    let signed_at: i64 = timestamp.parse().context("Invalid request timestamp")?;
+    if (now.unix_timestamp() - signed_at).unsigned_abs() > MAX_REQUEST_AGE.as_secs() {
+        return Err(anyhow::anyhow!("Request timestamp is too far from now"));
+    }
+    let signature = header("X-Slack-Signature")?
+        .strip_prefix("v0=")
+        .and_then(|signature| hex::decode(signature).ok())
+        .context("Malformed signature")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes())?;
+    mac.update(b"v0:");
+    mac.update(timestamp.as_bytes());
+    mac.update(b":");
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow::anyhow!("Signature doesn't match"))
+}
+
+/// Renders the top helpers for the period named in the command's text, or
+/// returns `None` if it doesn't name one
+async fn leaderboard_message(
+    server: &SlashCommandServer,
+    text: &str,
+) -> Result<Option<String>, anyhow::Error> {
+    let relative_period = if text.trim().is_empty() {
+        RelativePeriod::ThisWeek
+    } else {
+        match RelativePeriod::from_words(text) {
+            Some(relative_period) => relative_period,
+            None => return Ok(None),
+        }
+    };
+    let message = digest::leaderboard_digest(
+        &server.ticket_source,
+        relative_period,
+        server.timezone,
+        LEADERBOARD_SIZE,
+    )
+    .await?;
+    Ok(Some(message.render(Markup::Slack)))
+}
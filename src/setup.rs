@@ -0,0 +1,83 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Ok, Result};
+use toml_edit::{DocumentMut, Item, Table, TableLike};
+
+use crate::cli::confirm;
+
+/// Asks for settings that crimson needs but hasn't been given, and offers to
+/// save them to the config file so that it doesn't have to ask again
+pub struct SetupWizard {
+    config_path: PathBuf,
+    /// The `--profile` in use, if any, which answers are saved under
+    profile: Option<String>,
+}
+
+impl SetupWizard {
+    /// A wizard, if there's someone at the terminal to answer it
+    pub fn new(config_path: PathBuf, profile: Option<String>) -> Option<Self> {
+        std::io::stdin().is_terminal().then_some(Self {
+            config_path,
+            profile,
+        })
+    }
+
+    /// Asks for a setting, and saves the answer as `key` in the config file's
+    /// `[table]` if the user wants
+    pub fn ask(&self, what: &str, table: &str, key: &str) -> Result<String, anyhow::Error> {
+        println!("No {} has been given.", what);
+        let answer = loop {
+            print!("{}: ", what);
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+                return Err(anyhow::anyhow!("No {} given", what));
+            }
+            let answer = answer.trim();
+            if !answer.is_empty() {
+                break answer.to_string();
+            }
+        };
+        if confirm(&format!("Save it to {}?", self.config_path.display()))? {
+            self.save(table, key, &answer)?;
+            println!("Saved");
+        }
+        Ok(answer)
+    }
+
+    /// Sets a value in the config file, keeping everything else (including
+    /// comments) as it was
+    fn save(&self, table: &str, key: &str, value: &str) -> Result<(), anyhow::Error> {
+        let mut document = match std::fs::read_to_string(&self.config_path) {
+            Result::Ok(text) => text
+                .parse::<DocumentMut>()
+                .with_context(|| format!("Invalid config file {}", self.config_path.display()))?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => DocumentMut::new(),
+            Err(error) => {
+                return Err(error).with_context(|| {
+                    format!("Failed to read config file {}", self.config_path.display())
+                });
+            }
+        };
+        let path: Vec<&str> = match &self.profile {
+            Some(profile) => vec!["profile", profile, table],
+            None => vec![table],
+        };
+        let mut parent: &mut dyn TableLike = document.as_table_mut();
+        for name in &path {
+            parent = parent
+                .entry(name)
+                .or_insert_with(|| {
+                    let mut table = Table::new();
+                    table.set_implicit(true);
+                    Item::Table(table)
+                })
+                .as_table_like_mut()
+                .with_context(|| format!("{} in the config file isn't a table", path.join(".")))?;
+        }
+        parent.insert(key, toml_edit::value(value));
+        std::fs::write(&self.config_path, document.to_string())
+            .with_context(|| format!("Failed to write config file {}", self.config_path.display()))
+    }
+}
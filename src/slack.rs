@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Ok, Result};
+use futures::{StreamExt, TryStreamExt, stream};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::progress;
+
+const SLACK_API_BASE: &str = "https://slack.com/api/";
+
+/// Maximum number of Slack API requests to have in flight at once
+const MAX_CONCURRENT_REQUESTS: usize = 4;
+
+#[derive(Deserialize, Debug)]
+struct SlackProfile {
+    display_name: String,
+    real_name: String,
+    image_192: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+struct SlackUser {
+    name: String,
+    profile: SlackProfile,
+}
+#[derive(Deserialize, Debug)]
+struct SlackUserInfoResponse {
+    ok: bool,
+    error: Option<String>,
+    user: Option<SlackUser>,
+}
+
+impl SlackUser {
+    /// The name Slack shows for the user, falling back to their full name or
+    /// username if they haven't set a display name
+    fn best_name(self) -> String {
+        [self.profile.display_name, self.profile.real_name]
+            .into_iter()
+            .find(|name| !name.is_empty())
+            .unwrap_or(self.name)
+    }
+}
+
+/// Looks up the Slack display name of each user, keyed by Slack ID
+pub async fn get_display_names(
+    bot_token: &str,
+    slack_ids: impl Iterator<Item = &String>,
+) -> Result<HashMap<String, String>, anyhow::Error> {
+    let client = reqwest::Client::new();
+    stream::iter(slack_ids)
+        .map(|slack_id| {
+            let client = &client;
+            async move {
+                let user = get_user_info(client, bot_token, slack_id).await?;
+                Ok((slack_id.clone(), user.best_name()))
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+        .try_collect()
+        .await
+}
+
+/// Looks up the URL of a user's Slack profile picture, if they have one
+pub async fn get_avatar(bot_token: &str, slack_id: &str) -> Result<Option<String>, anyhow::Error> {
+    let user = get_user_info(&reqwest::Client::new(), bot_token, slack_id).await?;
+    Ok(user.profile.image_192)
+}
+
+async fn get_user_info(
+    client: &reqwest::Client,
+    bot_token: &str,
+    slack_id: &str,
+) -> Result<SlackUser, anyhow::Error> {
+    let mut url = Url::parse(SLACK_API_BASE)?.join("users.info")?;
+    url.query_pairs_mut().append_pair("user", slack_id);
+    let response: SlackUserInfoResponse = client
+        .get(url)
+        .bearer_auth(bot_token)
+        .send()
+        .await
+        .context("Failed to fetch user from Slack API")?
+        .json()
+        .await
+        .context("Invalid users.info response from Slack API")?;
+    if !response.ok {
+        return Err(anyhow::anyhow!(
+            "Slack API returned error for {}: {}",
+            slack_id,
+            response.error.unwrap_or_default()
+        ));
+    }
+    response
+        .user
+        .context("Slack API returned no user in users.info response")
+}
+
+/// Somewhere in Slack that messages can be posted
+pub enum SlackDestination {
+    /// An incoming webhook, which always posts to the channel it was made for
+    Webhook(Url),
+    /// A channel that the bot has been added to, by ID, optionally replying in
+    /// a thread there
+    Channel {
+        bot_token: String,
+        channel: String,
+        thread: Option<SlackThread>,
+    },
+}
+
+/// A message to post replies under
+#[derive(Debug, Clone)]
+pub struct SlackThread {
+    /// Timestamp (`ts`) of the message that starts the thread
+    pub ts: String,
+    /// Also show replies in the channel itself
+    pub broadcast: bool,
+}
+
+impl SlackDestination {
+    /// Posts to `channel` (in `thread`, if given) as the SLACK_BOT_TOKEN bot
+    /// if one is given, otherwise to the SLACK_WEBHOOK_URL webhook
+    pub fn from_env(
+        channel: Option<&str>,
+        thread: Option<SlackThread>,
+    ) -> Result<Self, anyhow::Error> {
+        match channel {
+            Some(channel) => Ok(Self::Channel {
+                bot_token: std::env::var("SLACK_BOT_TOKEN").with_context(|| {
+                    format!(
+                        "SLACK_BOT_TOKEN environment variable must be set to post to {}",
+                        channel
+                    )
+                })?,
+                channel: channel.to_string(),
+                thread,
+            }),
+            None if thread.is_some() => Err(anyhow::anyhow!(
+                "Replying in a thread needs a channel to post in as the bot, as webhooks can't"
+            )),
+            None => {
+                let webhook_url = std::env::var("SLACK_WEBHOOK_URL").context(
+                    "Posting to Slack needs either SLACK_WEBHOOK_URL, or SLACK_BOT_TOKEN and a channel",
+                )?;
+                Ok(Self::Webhook(
+                    Url::parse(&webhook_url).context("SLACK_WEBHOOK_URL is not a valid URL")?,
+                ))
+            }
+        }
+    }
+}
+
+/// A Slack API response that we only need to check succeeded
+#[derive(Deserialize, Debug)]
+struct SlackOkResponse {
+    ok: bool,
+    error: Option<String>,
+}
+
+/// Posts a message (in Slack's mrkdwn format) to a channel
+pub async fn post_message(destination: &SlackDestination, text: &str) -> Result<(), anyhow::Error> {
+    post(destination, serde_json::json!({ "text": text })).await
+}
+
+/// Posts a message laid out with Block Kit blocks to a channel, with `text`
+/// as the fallback for notifications and clients that can't show blocks
+pub async fn post_blocks(
+    destination: &SlackDestination,
+    text: &str,
+    blocks: serde_json::Value,
+) -> Result<(), anyhow::Error> {
+    post(
+        destination,
+        serde_json::json!({ "text": text, "blocks": blocks }),
+    )
+    .await
+}
+
+async fn post(
+    destination: &SlackDestination,
+    mut message: serde_json::Value,
+) -> Result<(), anyhow::Error> {
+    let client = reqwest::Client::new();
+    match destination {
+        SlackDestination::Webhook(webhook_url) => {
+            client
+                .post(webhook_url.clone())
+                .json(&message)
+                .send()
+                .await
+                .context("Failed to post to Slack webhook")?
+                .error_for_status()
+                .context("Slack webhook rejected the message")?;
+        }
+        SlackDestination::Channel {
+            bot_token,
+            channel,
+            thread,
+        } => {
+            message["channel"] = channel.as_str().into();
+            if let Some(thread) = thread {
+                message["thread_ts"] = thread.ts.as_str().into();
+                message["reply_broadcast"] = thread.broadcast.into();
+            }
+            let url = Url::parse(SLACK_API_BASE)?.join("chat.postMessage")?;
+            let response: SlackOkResponse = client
+                .post(url)
+                .bearer_auth(bot_token)
+                .json(&message)
+                .send()
+                .await
+                .context("Failed to post message to Slack API")?
+                .json()
+                .await
+                .context("Invalid chat.postMessage response from Slack API")?;
+            if !response.ok {
+                return Err(anyhow::anyhow!(
+                    "Slack API returned error when posting to {}: {}",
+                    channel,
+                    response.error.unwrap_or_default()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the members of a user group (the kind that can be @mentioned)
+/// with the given users
+pub async fn set_usergroup_members(
+    bot_token: &str,
+    usergroup: &str,
+    slack_ids: &[&String],
+) -> Result<(), anyhow::Error> {
+    let url = Url::parse(SLACK_API_BASE)?.join("usergroups.users.update")?;
+    let users = slack_ids
+        .iter()
+        .map(|slack_id| slack_id.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let response: SlackOkResponse = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(bot_token)
+        .form(&[("usergroup", usergroup), ("users", &users)])
+        .send()
+        .await
+        .context("Failed to update user group via Slack API")?
+        .json()
+        .await
+        .context("Invalid usergroups.users.update response from Slack API")?;
+    if !response.ok {
+        return Err(anyhow::anyhow!(
+            "Slack API returned error when updating user group {}: {}",
+            usergroup,
+            response.error.unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces everything in a canvas with the given (canvas-flavoured) Markdown
+pub async fn replace_canvas_content(
+    bot_token: &str,
+    canvas_id: &str,
+    markdown: &str,
+) -> Result<(), anyhow::Error> {
+    let url = Url::parse(SLACK_API_BASE)?.join("canvases.edit")?;
+    let response: SlackOkResponse = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(bot_token)
+        .json(&serde_json::json!({
+            "canvas_id": canvas_id,
+            "changes": [{
+                "operation": "replace",
+                "document_content": { "type": "markdown", "markdown": markdown },
+            }],
+        }))
+        .send()
+        .await
+        .context("Failed to update canvas via Slack API")?
+        .json()
+        .await
+        .context("Invalid canvases.edit response from Slack API")?;
+    if !response.ok {
+        return Err(anyhow::anyhow!(
+            "Slack API returned error when updating canvas {}: {}",
+            canvas_id,
+            response.error.unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+/// Sends each user a direct message from the bot, given as (Slack ID, text).
+/// Returns the users that couldn't be messaged, and why, rather than stopping
+/// at the first failure. `on_sent` is called with each user as their message
+/// goes through.
+pub async fn send_direct_messages(
+    bot_token: &str,
+    messages: Vec<(String, String)>,
+    on_sent: impl Fn(&str),
+) -> Vec<(String, anyhow::Error)> {
+    let progress = progress::bar("Sending DMs", messages.len());
+    let on_sent = &on_sent;
+    let failures = stream::iter(messages)
+        .map(|(slack_id, text)| async move {
+            // Posting to a user ID lands in their DM with the bot
+            let destination = SlackDestination::Channel {
+                bot_token: bot_token.to_string(),
+                channel: slack_id.clone(),
+                thread: None,
+            };
+            match post_message(&destination, &text).await {
+                Result::Ok(()) => {
+                    on_sent(&slack_id);
+                    None
+                }
+                Err(error) => Some((slack_id, error)),
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+        .inspect(|_| progress.inc(1))
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await;
+    progress.finish_and_clear();
+    failures
+}
@@ -0,0 +1,48 @@
+use anyhow::{Context, Ok, Result};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::level_filters::LevelFilter;
+use tracing::warn;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::{Layer, Registry};
+
+/// Sets up exporting spans over OTLP (HTTP), if an endpoint is configured with
+/// the usual OTEL_EXPORTER_OTLP_ENDPOINT or OTEL_EXPORTER_OTLP_TRACES_ENDPOINT
+/// environment variables. The other OTEL_ variables (like
+/// OTEL_EXPORTER_OTLP_HEADERS) are respected too.
+pub fn tracer_provider() -> Result<Option<SdkTracerProvider>, anyhow::Error> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none()
+    {
+        return Ok(None);
+    }
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("Failed to set up the OTLP exporter")?;
+    Ok(Some(
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("crimson").build())
+            .build(),
+    ))
+}
+
+/// Exports crimson's spans to `tracer_provider`. They're at the trace level, so
+/// that they're left out of the logs but still exported.
+pub fn layer(tracer_provider: &SdkTracerProvider) -> Box<dyn Layer<Registry> + Send + Sync> {
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer_provider.tracer("crimson"))
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::TRACE))
+        .boxed()
+}
+
+/// Sends off any spans that haven't been exported yet
+pub async fn shutdown(tracer_provider: SdkTracerProvider) {
+    let result = tokio::task::spawn_blocking(move || tracer_provider.shutdown()).await;
+    if let Result::Ok(Err(error)) = result {
+        warn!("Failed to export traces: {}", error);
+    }
+}
@@ -0,0 +1,91 @@
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Ok, Result};
+use reqwest::Url;
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+
+/// How long to wait for ssh to start forwarding (including any prompts for a
+/// password or passphrase) before giving up
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to check whether the tunnel is up yet
+const TUNNEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An `ssh -L` port forward to a database that's only reachable through a
+/// bastion host. The tunnel is closed when this is dropped.
+pub struct SshTunnel {
+    ssh: Child,
+}
+
+impl SshTunnel {
+    /// Forwards a local port through `destination` (e.g. `user@bastion`) to the
+    /// host and port in `db_url`, returning the tunnel along with a copy of
+    /// `db_url` that points at the local end of it instead
+    pub async fn open(destination: &str, db_url: &str) -> Result<(Self, String), anyhow::Error> {
+        let mut url = Url::parse(db_url)
+            .context("The database needs to be given as a URL to use --ssh-tunnel")?;
+        let host = url
+            .host_str()
+            .context("Database URL has no host to tunnel to")?
+            .to_string();
+        let port = match url.port() {
+            Some(port) => port,
+            None => match url.scheme() {
+                "postgres" | "postgresql" => 5432,
+                "mysql" => 3306,
+                scheme => {
+                    return Err(anyhow::anyhow!(
+                        "Can't tunnel to a {}:// database without a port",
+                        scheme
+                    ));
+                }
+            },
+        };
+        // Let the OS pick a free port, then hand it over to ssh
+        let local_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+
+        let ssh = Command::new("ssh")
+            .args(["-N", "-o", "ExitOnForwardFailure=yes", "-L"])
+            .arg(format!("{}:{}:{}", local_port, host, port))
+            .arg(destination)
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to run ssh")?;
+        let mut tunnel = Self { ssh };
+        tunnel.wait_until_ready(local_port).await?;
+
+        url.set_host(Some(&Ipv4Addr::LOCALHOST.to_string()))?;
+        url.set_port(Some(local_port))
+            .map_err(|_| anyhow::anyhow!("Can't set a port on the database URL"))?;
+        Ok((tunnel, url.to_string()))
+    }
+
+    async fn wait_until_ready(&mut self, local_port: u16) -> Result<(), anyhow::Error> {
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.ssh.try_wait()? {
+                return Err(anyhow::anyhow!(
+                    "ssh exited before the tunnel was up ({})",
+                    status
+                ));
+            }
+            if TcpStream::connect((Ipv4Addr::LOCALHOST, local_port))
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+            if started.elapsed() > TUNNEL_TIMEOUT {
+                return Err(anyhow::anyhow!(
+                    "SSH tunnel wasn't up after {}s",
+                    TUNNEL_TIMEOUT.as_secs()
+                ));
+            }
+            tokio::time::sleep(TUNNEL_POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,53 @@
+-- A miniature copy of the Nephthys schema, with just the tables and columns
+-- that crimson reads, and a handful of tickets from February 2026
+
+CREATE TABLE "User" (
+    "id" SERIAL PRIMARY KEY,
+    "slackId" TEXT,
+    "helper" BOOLEAN NOT NULL DEFAULT false,
+    "admin" BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE TABLE "CategoryTag" (
+    "id" SERIAL PRIMARY KEY,
+    "name" TEXT NOT NULL
+);
+
+CREATE TABLE "Ticket" (
+    "id" SERIAL PRIMARY KEY,
+    "channelId" TEXT,
+    "closedById" INTEGER,
+    "closedAt" TIMESTAMP(3),
+    "reopenedAt" TIMESTAMP(3),
+    "categoryTagId" INTEGER REFERENCES "CategoryTag" ("id")
+);
+
+INSERT INTO "User" ("id", "slackId", "helper", "admin") VALUES
+    (1, 'U1', true, false),
+    (2, 'U2', true, false),
+    (3, 'U3', false, true),
+    -- A helper who never linked their Slack account
+    (4, NULL, true, false);
+
+INSERT INTO "CategoryTag" ("id", "name") VALUES
+    (1, 'hardware'),
+    (2, 'software');
+
+INSERT INTO "Ticket" ("channelId", "closedById", "closedAt", "reopenedAt", "categoryTagId") VALUES
+    ('C0123ABCD', 1, '2026-02-02 10:00', NULL, 1),
+    ('C0123ABCD', 1, '2026-02-03 10:00', NULL, 2),
+    ('C0456EFGH', 1, '2026-02-04 10:00', NULL, 2),
+    ('C0456EFGH', 2, '2026-02-05 10:00', NULL, 1),
+    -- Closed prematurely, then reopened
+    ('C0123ABCD', 2, '2026-02-06 10:00', '2026-02-07 10:00', 1),
+    -- Reopened, then closed properly
+    ('C0456EFGH', 2, '2026-02-12 10:00', '2026-02-11 10:00', 2),
+    -- Outside the period
+    ('C0123ABCD', 2, '2026-03-05 10:00', NULL, 1),
+    ('C0123ABCD', 1, '2026-01-31 23:59', NULL, 1),
+    -- Closed by an admin who isn't a helper
+    ('C0123ABCD', 3, '2026-02-08 10:00', NULL, NULL),
+    -- Closed by nobody we know of
+    ('C0123ABCD', NULL, '2026-02-09 10:00', NULL, NULL),
+    ('C0123ABCD', 99, '2026-02-09 11:00', NULL, NULL),
+    ('C0123ABCD', 4, '2026-02-10 10:00', NULL, NULL);
@@ -0,0 +1,131 @@
+//! Property-based tests of the payout calculations, checking that whatever the
+//! tickets, pools and rates, the payouts keep to the rules that helpers rely on.
+
+use std::collections::HashMap;
+
+use crimson::payout::{POOL_DECIMAL_PLACES, do_pool_payouts, do_static_rate_payouts};
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+
+/// Up to 30 helpers, with anything from no tickets to a very busy month
+fn helper_tickets() -> impl Strategy<Value = HashMap<String, i64>> {
+    prop::collection::hash_map("U[0-9A-Z]{1,8}", 0..5_000i64, 0..30)
+}
+
+/// Up to 100 million cookies
+fn pool() -> impl Strategy<Value = i64> {
+    1..100_000_000i64
+}
+
+/// Rates from 0.01 to 100 cookies per ticket, to the hundredth
+fn rate() -> impl Strategy<Value = Decimal> {
+    (1..10_000i64).prop_map(|hundredths| Decimal::new(hundredths, 2))
+}
+
+proptest! {
+    #[test]
+    fn pool_shares_add_up_to_the_pool(helper_tickets in helper_tickets(), pool in pool()) {
+        let cookies = do_pool_payouts(&helper_tickets, &pool).unwrap();
+        prop_assert_eq!(cookies.len(), helper_tickets.len());
+        if helper_tickets.values().sum::<i64>() > 0 {
+            prop_assert_eq!(cookies.values().sum::<Decimal>(), Decimal::from(pool));
+        } else {
+            prop_assert!(cookies.values().all(Decimal::is_zero));
+        }
+    }
+
+    #[test]
+    fn pool_shares_are_within_a_hundredth_of_exact(
+        helper_tickets in helper_tickets(),
+        pool in pool(),
+    ) {
+        let total_tickets = helper_tickets.values().sum::<i64>();
+        prop_assume!(total_tickets > 0);
+        let cookies = do_pool_payouts(&helper_tickets, &pool).unwrap();
+        let step = Decimal::new(1, POOL_DECIMAL_PLACES);
+        for (slack_id, tickets) in &helper_tickets {
+            let share = cookies[slack_id];
+            let exact = Decimal::from(*tickets) * Decimal::from(pool) / Decimal::from(total_tickets);
+            prop_assert!(share >= Decimal::ZERO);
+            prop_assert!(share.scale() <= POOL_DECIMAL_PLACES);
+            prop_assert!((share - exact).abs() < step, "{} got {}, but exactly {}", slack_id, share, exact);
+        }
+    }
+
+    #[test]
+    fn more_tickets_never_gets_fewer_pool_cookies(
+        helper_tickets in helper_tickets(),
+        pool in pool(),
+    ) {
+        let cookies = do_pool_payouts(&helper_tickets, &pool).unwrap();
+        for (id_a, tickets_a) in &helper_tickets {
+            for (id_b, tickets_b) in &helper_tickets {
+                if tickets_a > tickets_b {
+                    prop_assert!(
+                        cookies[id_a] >= cookies[id_b],
+                        "{} closed {} tickets and got {}, but {} closed {} and got {}",
+                        id_a, tickets_a, cookies[id_a], id_b, tickets_b, cookies[id_b]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal_tickets_get_pool_shares_a_hundredth_apart_at_most(
+        helper_tickets in helper_tickets(),
+        pool in pool(),
+    ) {
+        let cookies = do_pool_payouts(&helper_tickets, &pool).unwrap();
+        let step = Decimal::new(1, POOL_DECIMAL_PLACES);
+        for (id_a, tickets_a) in &helper_tickets {
+            for (id_b, tickets_b) in &helper_tickets {
+                if tickets_a == tickets_b {
+                    prop_assert!((cookies[id_a] - cookies[id_b]).abs() <= step);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pool_payouts_are_the_same_every_time(helper_tickets in helper_tickets(), pool in pool()) {
+        // Rebuilt so that the map is iterated in a different order
+        let shuffled: HashMap<String, i64> = helper_tickets.clone().into_iter().collect();
+        prop_assert_eq!(
+            do_pool_payouts(&helper_tickets, &pool).unwrap(),
+            do_pool_payouts(&shuffled, &pool).unwrap()
+        );
+    }
+
+    #[test]
+    fn rate_payouts_are_exactly_tickets_times_rate(
+        helper_tickets in helper_tickets(),
+        rate in rate(),
+    ) {
+        let cookies = do_static_rate_payouts(&helper_tickets, &rate).unwrap();
+        prop_assert_eq!(cookies.len(), helper_tickets.len());
+        for (slack_id, tickets) in &helper_tickets {
+            prop_assert_eq!(cookies[slack_id], Decimal::from(*tickets) * rate);
+        }
+    }
+
+    #[test]
+    fn more_tickets_never_gets_fewer_rate_cookies(
+        tickets in 0..5_000i64,
+        extra in 1..5_000i64,
+        rate in rate(),
+    ) {
+        let helper_tickets = HashMap::from([
+            ("U1".to_string(), tickets),
+            ("U2".to_string(), tickets + extra),
+        ]);
+        let cookies = do_static_rate_payouts(&helper_tickets, &rate).unwrap();
+        prop_assert!(cookies["U2"] > cookies["U1"]);
+    }
+}
+
+#[test]
+fn huge_rates_are_refused_rather_than_overflowing() {
+    let helper_tickets = HashMap::from([("U1".to_string(), i64::MAX)]);
+    assert!(do_static_rate_payouts(&helper_tickets, &Decimal::MAX).is_err());
+}
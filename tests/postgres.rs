@@ -0,0 +1,286 @@
+//! End-to-end tests that run crimson against a miniature Nephthys database in
+//! Postgres, with the Flavortown API mocked out. They need Docker, so are
+//! ignored by default: run them with `cargo test -- --ignored`.
+
+use std::process::{Command, Output};
+
+use rust_decimal::Decimal;
+use serde_json::json;
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::ContainerAsync;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use tokio_postgres::{Client, NoTls};
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const SCHEMA: &str = include_str!("fixtures/nephthys.sql");
+
+/// Flavortown users for the helpers in the fixtures, as (Slack ID, user ID)
+const FLAVORTOWN_USERS: &[(&str, i64)] = &[("U1", 101), ("U2", 102), ("U3", 103)];
+
+struct Nephthys {
+    // Keeps the container running until the test is done with it
+    _container: ContainerAsync<Postgres>,
+    url: String,
+    client: Client,
+}
+
+async fn start_nephthys() -> Nephthys {
+    let container = Postgres::default()
+        .start()
+        .await
+        .expect("Postgres container should start");
+    let url = format!(
+        "postgresql://postgres:postgres@{}:{}/postgres",
+        container.get_host().await.unwrap(),
+        container.get_host_port_ipv4(5432).await.unwrap()
+    );
+    let (client, connection) = tokio_postgres::connect(&url, NoTls)
+        .await
+        .expect("should connect to Postgres");
+    tokio::spawn(connection);
+    client
+        .batch_execute(SCHEMA)
+        .await
+        .expect("fixtures should load");
+    Nephthys {
+        _container: container,
+        url,
+        client,
+    }
+}
+
+async fn mock_flavortown() -> MockServer {
+    let server = MockServer::start().await;
+    for (slack_id, id) in FLAVORTOWN_USERS {
+        Mock::given(method("GET"))
+            .and(path("/api/v1/users"))
+            .and(query_param("query", *slack_id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "users": [{
+                    "id": id,
+                    "slack_id": slack_id,
+                    "display_name": format!("user{}", slack_id),
+                    "avatar": "",
+                    "project_ids": [],
+                    "cookies": 0,
+                }],
+                "pagination": { "current_page": 1, "total_pages": 1 },
+            })))
+            .mount(&server)
+            .await;
+    }
+    server
+}
+
+/// Runs a payout for February 2026, returning its output whether or not it
+/// succeeded
+async fn try_payout(nephthys: &Nephthys, args: &[&str]) -> Output {
+    let flavortown = mock_flavortown().await;
+    Command::new(env!("CARGO_BIN_EXE_crimson"))
+        .args(["payout", "--db", &nephthys.url])
+        .args([
+            "--start",
+            "2026-02-01T00:00:00Z",
+            "--end",
+            "2026-03-01T00:00:00Z",
+        ])
+        .args(["--skip-unresolved", "--format", "payout"])
+        // Keep test runs out of the developer's ledger
+        .arg("--no-ledger")
+        .args(args)
+        .env(
+            "FLAVORTOWN_API_BASE",
+            format!("{}/api/v1/", flavortown.uri()),
+        )
+        .env("FLAVORTOWN_API_KEY", "test")
+        .env_remove("DATABASE_URL")
+        .env_remove("CRIMSON_LEDGER")
+        // Keep a developer's .env out of it
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .expect("crimson should run")
+}
+
+/// Runs a payout for February 2026, returning its output
+async fn payout(nephthys: &Nephthys, args: &[&str]) -> Output {
+    let output = try_payout(nephthys, args).await;
+    assert!(
+        output.status.success(),
+        "crimson failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn counts_helper_tickets_in_period() {
+    let nephthys = start_nephthys().await;
+    let output = stdout(&payout(&nephthys, &["--cookie-rate", "2/ticket"]).await);
+
+    assert!(output.contains("Total tickets closed: 6"), "{}", output);
+    assert!(
+        output.contains("Total cookies to pay out: 12"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains(
+            "userU1: https://flavortown.hackclub.com/admin/users/101 gets 6 cookies! (3 tkts)"
+        ),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains(
+            "userU2: https://flavortown.hackclub.com/admin/users/102 gets 6 cookies! (3 tkts)"
+        ),
+        "{}",
+        output
+    );
+    // Not a helper
+    assert!(!output.contains("userU3"), "{}", output);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn splits_pool_by_tickets() {
+    let nephthys = start_nephthys().await;
+    let output = stdout(&payout(&nephthys, &["--cookie-pool", "30", "--exclude-reopened"]).await);
+
+    assert!(output.contains("Total tickets closed: 5"), "{}", output);
+    assert!(
+        output.contains("/users/101 gets 18 cookies! (3 tkts)"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("/users/102 gets 12 cookies! (2 tkts)"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn applies_filters() {
+    let nephthys = start_nephthys().await;
+
+    let output = stdout(
+        &payout(
+            &nephthys,
+            &["--cookie-rate", "1/ticket", "--category", "hardware"],
+        )
+        .await,
+    );
+    assert!(
+        output.contains("/users/101 gets 1 cookies! (1 tkts)"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("/users/102 gets 2 cookies! (2 tkts)"),
+        "{}",
+        output
+    );
+
+    let output = stdout(
+        &payout(
+            &nephthys,
+            &["--cookie-rate", "1/ticket", "--channel", "C0456EFGH"],
+        )
+        .await,
+    );
+    assert!(
+        output.contains("/users/101 gets 1 cookies! (1 tkts)"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("/users/102 gets 2 cookies! (2 tkts)"),
+        "{}",
+        output
+    );
+
+    let output =
+        stdout(&payout(&nephthys, &["--cookie-rate", "1/ticket", "--role", "admin"]).await);
+    assert!(output.contains("Total tickets closed: 1"), "{}", output);
+    assert!(
+        output.contains("/users/103 gets 1 cookies! (1 tkts)"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn reports_unattributed_tickets() {
+    let nephthys = start_nephthys().await;
+    let log = stderr(&payout(&nephthys, &["--cookie-rate", "1/ticket"]).await);
+
+    assert!(
+        log.contains("3 ticket(s) were closed by someone who can't be found"),
+        "{}",
+        log
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn records_payout_runs() {
+    let nephthys = start_nephthys().await;
+    let log = stderr(&payout(&nephthys, &["--cookie-pool", "30", "--record"]).await);
+    assert!(log.contains("Recorded as payout run #1"), "{}", log);
+
+    let items = nephthys
+        .client
+        .query(
+            "SELECT slack_id, flavortown_user_id, tickets_closed, cookies FROM crimson_payout_items WHERE run_id = 1 ORDER BY slack_id",
+            &[],
+        )
+        .await
+        .unwrap();
+    let items: Vec<(String, Option<i64>, i64, Decimal)> = items
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+        .collect();
+    assert_eq!(
+        items,
+        vec![
+            ("U1".to_string(), Some(101), 3, Decimal::from(15)),
+            ("U2".to_string(), Some(102), 3, Decimal::from(15)),
+        ]
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn refuses_overlapping_runs() {
+    let nephthys = start_nephthys().await;
+    payout(&nephthys, &["--cookie-pool", "30", "--record"]).await;
+
+    let output = try_payout(&nephthys, &["--cookie-pool", "30"]).await;
+    assert!(!output.status.success());
+    let log = stderr(&output);
+    assert!(
+        log.contains(
+            "This period overlaps payout run #1, from 2026-02-01T00:00:00Z to 2026-03-01T00:00:00Z"
+        ),
+        "{}",
+        log
+    );
+    assert!(log.contains("--allow-overlap"), "{}", log);
+
+    payout(&nephthys, &["--cookie-pool", "30", "--allow-overlap"]).await;
+    // Paying at a rate isn't the same as sharing out a pool
+    payout(&nephthys, &["--cookie-rate", "1/ticket"]).await;
+}
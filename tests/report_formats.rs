@@ -0,0 +1,172 @@
+//! Snapshot tests of every format that the payout list can be rendered in, from
+//! one fixed leaderboard. After an intended change to a format, review and
+//! accept the new snapshots with `cargo insta review`.
+
+use std::collections::HashMap;
+
+use crimson::flavortown::{FlavortownUser, ShopItem};
+use crimson::notify::{Image, Markup};
+use crimson::report::{
+    PayoutListFormat, describe_purchasing_power, format_helper_cookies, payout_list_message,
+    whole_cookies,
+};
+use rust_decimal::Decimal;
+
+/// A pool of 100 cookies, shared between three paid helpers (two of them tied)
+/// and one who has no Flavortown account
+struct Fixture {
+    helper_cookies: HashMap<String, Decimal>,
+    helper_tickets: HashMap<String, i64>,
+    helper_users: HashMap<String, FlavortownUser>,
+    shop_items: Vec<ShopItem>,
+}
+
+fn fixture() -> Fixture {
+    let helpers = [
+        ("U1", 4, "50", Some((101, "Alice"))),
+        ("U2", 2, "20", Some((102, "Bob, \"the builder\""))),
+        ("U3", 2, "20", Some((103, "Carol"))),
+        ("U4", 1, "10", None),
+    ];
+    let user = |slack_id: &str, id: i64, name: &str| FlavortownUser {
+        id,
+        slack_id: slack_id.to_string(),
+        display_name: name.to_string(),
+        avatar: String::new(),
+        cookies: Some(0),
+    };
+    Fixture {
+        helper_cookies: helpers
+            .iter()
+            .map(|(slack_id, _, cookies, _)| (slack_id.to_string(), cookies.parse().unwrap()))
+            .collect(),
+        helper_tickets: helpers
+            .iter()
+            .map(|(slack_id, tickets, _, _)| (slack_id.to_string(), *tickets))
+            .collect(),
+        helper_users: helpers
+            .iter()
+            .filter_map(|(slack_id, _, _, user_info)| {
+                let (id, name) = (*user_info)?;
+                Some((slack_id.to_string(), user(slack_id, id, name)))
+            })
+            .collect(),
+        shop_items: vec![
+            ShopItem {
+                name: "Sticker pack".to_string(),
+                cost: Decimal::from(15),
+            },
+            ShopItem {
+                name: "Hoodie".to_string(),
+                cost: Decimal::from(45),
+            },
+        ],
+    }
+}
+
+fn render(format: PayoutListFormat, mentions: bool) -> String {
+    let fixture = fixture();
+    format_helper_cookies(
+        &fixture.helper_cookies,
+        &fixture.helper_tickets,
+        &fixture.helper_users,
+        &fixture.shop_items,
+        &format,
+        mentions,
+    )
+    .unwrap()
+}
+
+fn message(mentions: bool) -> crimson::notify::Message {
+    let fixture = fixture();
+    payout_list_message(
+        &fixture.helper_cookies,
+        &fixture.helper_tickets,
+        &fixture.helper_users,
+        &fixture.shop_items,
+        mentions,
+    )
+}
+
+#[test]
+fn manual_payouts() {
+    insta::assert_snapshot!(render(PayoutListFormat::ManualPayouts, false));
+}
+
+#[test]
+fn manual_payouts_with_mentions() {
+    insta::assert_snapshot!(render(PayoutListFormat::ManualPayouts, true));
+}
+
+#[test]
+fn table() {
+    insta::assert_snapshot!(render(PayoutListFormat::Table, false));
+}
+
+#[test]
+fn json() {
+    insta::assert_snapshot!(render(PayoutListFormat::Json, false));
+}
+
+#[test]
+fn csv() {
+    insta::assert_snapshot!(render(PayoutListFormat::Csv, false));
+}
+
+#[test]
+fn slack_message() {
+    insta::assert_snapshot!(render(PayoutListFormat::SlackMessage, true));
+}
+
+#[test]
+fn markdown() {
+    insta::assert_snapshot!(message(true).render(Markup::Markdown));
+}
+
+#[test]
+fn slack_canvas() {
+    insta::assert_snapshot!(message(true).render(Markup::SlackCanvas));
+}
+
+#[test]
+fn telegram_html() {
+    insta::assert_snapshot!(message(true).render(Markup::TelegramHtml));
+}
+
+#[test]
+fn slack_blocks() {
+    let mut message = message(true);
+    message.image = Some(Image {
+        url: "https://example.com/alice.png".to_string(),
+        alt_text: "Alice".to_string(),
+    });
+    insta::assert_snapshot!(serde_json::to_string_pretty(&message.slack_blocks()).unwrap());
+}
+
+#[test]
+fn odd_shop_prices_are_skipped() {
+    let shop_items: Vec<ShopItem> = [-1, 0, 15]
+        .into_iter()
+        .map(Decimal::from)
+        .map(|cost| ShopItem {
+            name: format!("Costs {}", cost),
+            cost,
+        })
+        .collect();
+    assert_eq!(
+        describe_purchasing_power(Decimal::from(50), &shop_items).as_deref(),
+        Some("3× Costs 15")
+    );
+}
+
+#[test]
+fn half_cookies_round_up() {
+    for (cookies, whole) in [("2.5", 3), ("3.5", 4), ("2.49", 2), ("0.5", 1)] {
+        assert_eq!(
+            whole_cookies(cookies.parse().unwrap()),
+            Decimal::from(whole),
+            "{} cookies",
+            cookies
+        );
+    }
+}